@@ -1,6 +1,6 @@
 // Buffer cache
 //
-// Buffer cache is a linked list of Buf struct holding
+// Buffer cache is a hash table of Buf structs holding
 // cached copies of disk block content. Caching disk blocks
 // in memory reduces the number of disk reads and also
 // provides a synchronization point for disk blocks sused by
@@ -9,29 +9,497 @@
 // Interface:
 // To get a buffer from a particular disk block, call bread.
 // After changing buffer data, call bwrite to write ito disk.
-// When done with the buffer, call brelse.
+// When done with the buffer, call brelse (or drop it).
 // Do not use buffer after calling brelse.
 // Only one process at a time can use a buffer, so do not
 // keep them longer than necessary.
 
-
-use super::spinlock::SpinLock;
-use super::buf::Buf;
+use super::buf::{BufData, BufMeta};
+use super::disk;
 use super::params::NBUF;
+use super::sleeplock::{SleepLock, SleepLockGuard};
+use super::spinlock::{LockStats, SpinLock};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// number of hash buckets, prime to spread block numbers.
+pub const NBUCKET: usize = 13;
+
+// the buffers whose (dev, blockno) hash to one bucket.
+// entries 0..n are in use, in no particular order.
+#[derive(Clone, Copy)]
+pub struct Bucket {
+    ent: [BufMeta; NBUF],
+    n: usize,
+}
+
+// locking:
+// each bucket has its own lock, so lookups of blocks in different
+// buckets proceed in parallel. a buffer moves between buckets only
+// while both bucket locks are held, and only by a process holding
+// Bcache::evict, so at most one process ever holds two bucket locks
+// and there is no lock order between buckets to violate.
+// order: evict, then the bucket of the wanted block, then others.
+pub struct Bcache {
+    bucket: [SpinLock<Bucket>; NBUCKET],
+    evict: SpinLock<()>,
+    clock: AtomicU64, // stamps BufMeta::lastuse
+    buf: [SleepLock<BufData>; NBUF],
+}
+
+// a locked buffer, handed out by bread(). releases the buffer
+// when dropped.
+pub struct BufRef<'a> {
+    cache: &'a Bcache,
+    id: usize,
+    guard: Option<SleepLockGuard<'a, BufData>>,
+}
+
+const fn hash(dev: u32, blockno: u32) -> usize {
+    (dev as usize * 31 + blockno as usize) % NBUCKET
+}
+
+impl Bucket {
+    const fn new() -> Bucket {
+        Bucket {
+            ent: [BufMeta::new(0); NBUF],
+            n: 0,
+        }
+    }
+
+    fn lookup(&self, dev: u32, blockno: u32) -> Option<usize> {
+        (0..self.n).find(|&k| self.ent[k].dev == dev && self.ent[k].blockno == blockno)
+    }
+
+    fn find(&self, id: usize) -> usize {
+        match (0..self.n).find(|&k| self.ent[k].id == id) {
+            Some(k) => k,
+            None => panic!("bcache: buffer {} not in its bucket", id),
+        }
+    }
+
+    // the least recently used unreferenced buffer.
+    fn lru(&self) -> Option<usize> {
+        (0..self.n)
+            .filter(|&k| self.ent[k].refcnt == 0)
+            .min_by_key(|&k| self.ent[k].lastuse)
+    }
+
+    fn remove(&mut self, k: usize) -> BufMeta {
+        let m = self.ent[k];
+        self.n -= 1;
+        self.ent[k] = self.ent[self.n];
+        m
+    }
+
+    fn push(&mut self, m: BufMeta) -> usize {
+        self.ent[self.n] = m;
+        self.n += 1;
+        self.n - 1
+    }
+}
 
+impl Bcache {
+    pub const fn new() -> Bcache {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const BUF: SleepLock<BufData> = SleepLock::new(BufData::new(), "buffer");
+        #[allow(clippy::declare_interior_mutable_const)]
+        const BUCKET: SpinLock<Bucket> = SpinLock::new(Bucket::new(), "bcache.bucket");
 
-#[derive(Default)]
-pub struct Bcache<'a> {
-    lock: SpinLock<'a>,
-    buf: [Buf<'a>; NBUF],
+        // buffer i starts out as block i of device 0, which is
+        // never read, so the buffers start out spread over the
+        // buckets rather than all waiting to be stolen from one.
+        let mut free = [Bucket::new(); NBUCKET];
+        let mut i = 0;
+        while i < NBUF {
+            let h = hash(0, i as u32);
+            let k = free[h].n;
+            free[h].ent[k].id = i;
+            free[h].ent[k].blockno = i as u32;
+            free[h].n += 1;
+            i += 1;
+        }
+        let mut bucket = [BUCKET; NBUCKET];
+        let mut h = 0;
+        while h < NBUCKET {
+            bucket[h] = SpinLock::new(free[h], "bcache.bucket");
+            h += 1;
+        }
 
-    // linked list of all buffers, through prev/next.
-    //head.next is mru.
-    head: Buf<'a>,
+        Bcache {
+            bucket,
+            evict: SpinLock::new((), "bcache.evict"),
+            clock: AtomicU64::new(0),
+            buf: [BUF; NBUF],
+        }
+    }
+
+    // Look through buffer cache for block on device dev.
+    // If not found, allocate a buffer.
+    // In either case, return locked buffer.
+    fn bget(&self, dev: u32, blockno: u32) -> BufRef<'_> {
+        let h = hash(dev, blockno);
+
+        // Is the block already cached?
+        let mut bucket = self.bucket[h].lock();
+        if let Some(k) = bucket.lookup(dev, blockno) {
+            bucket.ent[k].refcnt += 1;
+            let id = bucket.ent[k].id;
+            drop(bucket);
+            return self.lockbuf(id);
+        }
+        drop(bucket);
+
+        // Not cached.
+        let evict = self.evict.lock();
+        let mut bucket = self.bucket[h].lock();
+
+        // another process may have cached it while bucket h
+        // was unlocked.
+        if let Some(k) = bucket.lookup(dev, blockno) {
+            bucket.ent[k].refcnt += 1;
+            let id = bucket.ent[k].id;
+            drop(bucket);
+            drop(evict);
+            return self.lockbuf(id);
+        }
+
+        // Recycle the least recently used (LRU) unused buffer,
+        // keeping the lock of the bucket it is in while looking
+        // further, so it can't be taken from under us.
+        let mut own = bucket.lru();
+        let mut best = own.map(|k| bucket.ent[k].lastuse);
+        let mut other = None;
+        for j in (0..NBUCKET).filter(|&j| j != h) {
+            let b = self.bucket[j].lock();
+            if let Some(k) = b.lru() {
+                if best.map_or(true, |t| b.ent[k].lastuse < t) {
+                    best = Some(b.ent[k].lastuse);
+                    other = Some((b, k));
+                    own = None;
+                }
+            }
+        }
+        let k = match (own, other) {
+            (Some(k), _) => k,
+            (None, Some((mut from, k))) => bucket.push(from.remove(k)),
+            (None, None) => panic!("bget: no buffers"),
+        };
+
+        let m = &mut bucket.ent[k];
+        m.dev = dev;
+        m.blockno = blockno;
+        m.refcnt = 1;
+        let id = m.id;
+        // nobody holds it, so the sleep lock is free.
+        let b = unsafe { self.buf[id].get_mut_unchecked() };
+        b.dev = dev;
+        b.blockno = blockno;
+        b.valid = false;
+        drop(bucket);
+        drop(evict);
+        self.lockbuf(id)
+    }
+
+    fn lockbuf(&self, id: usize) -> BufRef<'_> {
+        BufRef {
+            cache: self,
+            id,
+            guard: Some(self.buf[id].lock()),
+        }
+    }
+
+    // Return a locked buf with the contents of the indicated block.
+    pub fn bread(&self, dev: u32, blockno: u32) -> BufRef<'_> {
+        let mut b = self.bget(dev, blockno);
+        if !b.valid {
+            disk::rw(&mut b, false);
+            b.valid = true;
+        }
+        b
+    }
+
+    // Write b's contents to disk.  Must be locked.
+    pub fn bwrite(&self, b: &mut BufRef<'_>) {
+        disk::rw(b, true);
+    }
+
+    // wait until every block written to dev is on stable storage.
+    pub fn bflush(&self, dev: u32) {
+        disk::flush(dev);
+    }
+
+    // Release a locked buffer.
+    // It becomes the most recently used unreferenced buffer.
+    // dropping the BufRef does the same.
+    pub fn brelse(&self, b: BufRef<'_>) {
+        if !self.buf[b.id].holding() {
+            panic!("brelse");
+        }
+        drop(b);
+    }
+
+    // drop a reference to buffer id, which holds (dev, blockno).
+    fn unref(&self, id: usize, dev: u32, blockno: u32) {
+        let mut bucket = self.bucket[hash(dev, blockno)].lock();
+        let k = bucket.find(id);
+        let m = &mut bucket.ent[k];
+        m.refcnt -= 1;
+        if m.refcnt == 0 {
+            // no one is waiting for it.
+            m.lastuse = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        }
+    }
+
+    // contention on the cache's locks so far.
+    pub fn stats(&self) -> LockStats {
+        self.bucket
+            .iter()
+            .fold(self.evict.stats(), |s, b| s + b.stats())
+    }
 }
 
+impl Deref for BufRef<'_> {
+    type Target = BufData;
+    fn deref(&self) -> &BufData {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for BufRef<'_> {
+    fn deref_mut(&mut self) -> &mut BufData {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl Drop for BufRef<'_> {
+    fn drop(&mut self) {
+        let (dev, blockno) = (self.dev, self.blockno);
+        // give up the sleep lock before the reference.
+        self.guard = None;
+        self.cache.unref(self.id, dev, blockno);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::disk::{self, BlockDevice};
+    use super::super::fs::BSIZE;
+    use super::*;
+    use std::panic;
+    use std::sync::Mutex;
+
+    // a block device in host memory that counts its traffic.
+    pub struct MemDisk {
+        pub blocks: Mutex<Vec<[u8; BSIZE]>>,
+        pub reads: Mutex<usize>,
+        pub writes: Mutex<usize>,
+    }
+
+    impl MemDisk {
+        // a new disk registered as dev, with block i filled with i.
+        pub fn register(dev: u32, nblocks: usize) -> &'static MemDisk {
+            let d = Box::leak(Box::new(MemDisk {
+                blocks: Mutex::new((0..nblocks).map(|i| [i as u8; BSIZE]).collect()),
+                reads: Mutex::new(0),
+                writes: Mutex::new(0),
+            }));
+            disk::register(dev, d);
+            d
+        }
+    }
+
+    impl BlockDevice for MemDisk {
+        fn read_block(&self, blockno: u32, data: &mut [u8; BSIZE]) {
+            *self.reads.lock().unwrap() += 1;
+            *data = self.blocks.lock().unwrap()[blockno as usize];
+        }
+
+        fn write_block(&self, blockno: u32, data: &[u8; BSIZE]) {
+            *self.writes.lock().unwrap() += 1;
+            self.blocks.lock().unwrap()[blockno as usize] = *data;
+        }
+
+        fn flush(&self) {}
+
+        fn nblocks(&self) -> u32 {
+            self.blocks.lock().unwrap().len() as u32
+        }
+    }
+
+    // the blocks cached in the bucket of (dev, blockno).
+    fn cached(bcache: &Bcache, dev: u32, blockno: u32) -> bool {
+        let h = hash(dev, blockno);
+        bcache.bucket[h].lock().lookup(dev, blockno).is_some()
+    }
+
+    #[test]
+    fn bread_caches_blocks() {
+        let d = MemDisk::register(2, 64);
+        let bcache = Box::new(Bcache::new());
+
+        let b = bcache.bread(2, 7);
+        assert_eq!(b.data, [7; BSIZE]);
+        bcache.brelse(b);
+        let b = bcache.bread(2, 7);
+        assert_eq!((b.dev, b.blockno), (2, 7));
+        drop(b);
+        assert_eq!(*d.reads.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn bwrite_reaches_the_disk() {
+        let d = MemDisk::register(3, 64);
+        let bcache = Box::new(Bcache::new());
+
+        let mut b = bcache.bread(3, 1);
+        b.data[0] = 0xaa;
+        bcache.bwrite(&mut b);
+        drop(b);
+        assert_eq!(d.blocks.lock().unwrap()[1][0], 0xaa);
+        assert_eq!(*d.writes.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn recycles_least_recently_used() {
+        let d = MemDisk::register(4, 2 * NBUF);
+        let bcache = Box::new(Bcache::new());
+
+        for blockno in 0..NBUF as u32 {
+            drop(bcache.bread(4, blockno));
+        }
+        // touch block 0 again, so block 1 is now the oldest.
+        drop(bcache.bread(4, 0));
+
+        drop(bcache.bread(4, NBUF as u32));
+        assert!(cached(&bcache, 4, NBUF as u32));
+        assert!(cached(&bcache, 4, 0));
+        assert!(!cached(&bcache, 4, 1));
+        assert_eq!(*d.reads.lock().unwrap(), NBUF + 1);
+
+        // block 0 is still cached, block 1 has to be read again.
+        drop(bcache.bread(4, 0));
+        assert_eq!(*d.reads.lock().unwrap(), NBUF + 1);
+        drop(bcache.bread(4, 1));
+        assert_eq!(*d.reads.lock().unwrap(), NBUF + 2);
+    }
+
+    #[test]
+    fn buckets_partition_the_buffers() {
+        MemDisk::register(7, 4 * NBUF);
+        let bcache = Box::new(Bcache::new());
+
+        for blockno in 0..4 * NBUF as u32 {
+            drop(bcache.bread(7, blockno));
+        }
+        let mut ids: Vec<_> = bcache
+            .bucket
+            .iter()
+            .enumerate()
+            .flat_map(|(h, b)| {
+                let b = b.lock();
+                for m in &b.ent[..b.n] {
+                    assert_eq!(hash(m.dev, m.blockno), h);
+                }
+                b.ent[..b.n].iter().map(|m| m.id).collect::<Vec<_>>()
+            })
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (0..NBUF).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn buffers_start_spread_over_buckets() {
+        let bcache = Box::new(Bcache::new());
+        for b in &bcache.bucket {
+            let n = b.lock().n;
+            assert!(n == NBUF / NBUCKET || n == NBUF / NBUCKET + 1);
+        }
+    }
+
+    #[test]
+    fn harts_share_the_cache() {
+        let d = MemDisk::register(8, 64 * NBUF);
+        let bcache: &'static Bcache = Box::leak(Box::new(Bcache::new()));
+
+        // each hart reads its own blocks, so no one waits on a
+        // buffer's sleep lock, only on the spin locks.
+        let harts: Vec<_> = (0..4u32)
+            .map(|hart| {
+                std::thread::spawn(move || {
+                    for round in 0..8 {
+                        for i in 0..16 {
+                            let blockno = hart * 16 * NBUF as u32 + round * 4 + i;
+                            let b = bcache.bread(8, blockno);
+                            assert_eq!(b.data, [blockno as u8; BSIZE]);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for h in harts {
+            h.join().unwrap();
+        }
+        assert!(*d.reads.lock().unwrap() >= 4 * 44);
+        assert!(bcache.stats().nacquire >= 4 * 8 * 16 * 2);
+    }
+
+    // cargo test --release -- --ignored --nocapture bench_
+    #[test]
+    #[ignore]
+    fn bench_buckets_vs_single_lock() {
+        use std::time::Instant;
+
+        // few enough blocks per hart that they all stay cached, so
+        // the harts only contend for the bucket locks.
+        const NHART: u32 = 4;
+        const NBLOCK: u32 = 6;
+        const ROUNDS: u32 = 2000;
+        MemDisk::register(27, (NHART * NBLOCK) as usize * NBUCKET);
+
+        // with stride 1 the blocks fall into different buckets. with
+        // stride NBUCKET they all hash to one bucket, so every lookup
+        // takes the same lock, as with a single lock for the cache.
+        for (what, stride) in [("buckets", 1), ("single lock", NBUCKET as u32)] {
+            let bcache: &'static Bcache = Box::leak(Box::new(Bcache::new()));
+            let t = Instant::now();
+            let harts: Vec<_> = (0..NHART)
+                .map(|hart| {
+                    std::thread::spawn(move || {
+                        for _ in 0..ROUNDS {
+                            for i in 0..NBLOCK {
+                                let b = bcache.bread(27, (hart * NBLOCK + i) * stride);
+                                bcache.brelse(b);
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for h in harts {
+                h.join().unwrap();
+            }
+            let st = bcache.stats();
+            println!(
+                "{}: {:?}, {} acquires, {} spins",
+                what,
+                t.elapsed(),
+                st.nacquire,
+                st.nspin
+            );
+        }
+    }
+
+    #[test]
+    fn panics_when_all_buffers_are_busy() {
+        MemDisk::register(6, 2 * NBUF);
+        let bcache = Box::new(Bcache::new());
 
-impl<'a> Bcache<'a> {
-    fn new() {
+        let held: Vec<_> = (0..NBUF as u32).map(|i| bcache.bread(6, i)).collect();
+        let r = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            bcache.bread(6, NBUF as u32);
+        }));
+        assert!(r.is_err());
+        drop(held);
+        drop(bcache.bread(6, NBUF as u32));
     }
 }
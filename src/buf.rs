@@ -1,16 +1,52 @@
 use super::fs::BSIZE;
-use super::sleeplock::SleepLock;
 
-#[derive(Default)]
-pub struct Buf<'a> {
-    valid: bool, // has data been read from disk?
-    disk: bool,  // does disk own buffer?
-    dev: u32,
-    blockno: u32,
-    lock: SleepLock<'a>,
-    refcnt: u32,
-    prev: Option<&'a Buf<'a>>, // LRU cache list
-    next: Option<&'a Buf<'a>>,
-    qnext: Option<&'a Buf<'a>>, // disk queue
-    data: [char; BSIZE],
+// the part of a buffer protected by its sleep lock in Bcache.
+// held across disk io, so it is a sleep lock.
+pub struct BufData {
+    pub valid: bool, // has data been read from disk?
+    pub disk: bool,  // does disk own buffer?
+    pub dev: u32,
+    pub blockno: u32,
+    pub data: [u8; BSIZE],
+}
+
+impl BufData {
+    pub const fn new() -> BufData {
+        BufData {
+            valid: false,
+            disk: false,
+            dev: 0,
+            blockno: 0,
+            data: [0; BSIZE],
+        }
+    }
+}
+
+impl Default for BufData {
+    fn default() -> Self {
+        BufData::new()
+    }
+}
+
+// the part of a buffer protected by the lock of the Bcache bucket
+// it sits in, which is the bucket (dev, blockno) hashes to.
+#[derive(Clone, Copy)]
+pub struct BufMeta {
+    pub id: usize, // index of the buffer in Bcache::buf
+    pub dev: u32,
+    pub blockno: u32,
+    pub refcnt: u32,
+    pub lastuse: u64, // when refcnt last dropped to 0, for LRU
+}
+
+impl BufMeta {
+    pub const fn new(id: usize) -> BufMeta {
+        BufMeta {
+            id,
+            dev: 0,
+            blockno: 0,
+            refcnt: 0,
+            lastuse: 0,
+        }
+    }
 }
@@ -0,0 +1,57 @@
+// block devices.
+//
+// the buffer cache moves whole blocks to and from a device through
+// the BlockDevice trait. devices are registered by device number,
+// the same number Buf::dev and Inode::dev carry.
+
+use super::buf::BufData;
+use super::fs::BSIZE;
+use super::params::NDISK;
+use super::spinlock::SpinLock;
+
+pub trait BlockDevice: Sync {
+    fn read_block(&self, blockno: u32, data: &mut [u8; BSIZE]);
+    fn write_block(&self, blockno: u32, data: &[u8; BSIZE]);
+    // make every block written so far durable.
+    fn flush(&self);
+    // size of the device in blocks.
+    fn nblocks(&self) -> u32;
+}
+
+static DEVICES: SpinLock<[Option<&'static dyn BlockDevice>; NDISK]> =
+    SpinLock::new([None; NDISK], "devices");
+
+// make d the device for device number dev.
+pub fn register(dev: u32, d: &'static dyn BlockDevice) {
+    DEVICES.lock()[dev as usize] = Some(d);
+}
+
+fn device(dev: u32) -> &'static dyn BlockDevice {
+    match DEVICES.lock().get(dev as usize) {
+        Some(Some(d)) => *d,
+        _ => panic!("disk: no device {}", dev),
+    }
+}
+
+// read or write the block b names. the caller holds b's lock.
+pub fn rw(b: &mut BufData, write: bool) {
+    let d = device(b.dev);
+    if b.blockno >= d.nblocks() {
+        panic!("disk: block {} out of range on device {}", b.blockno, b.dev);
+    }
+    b.disk = true;
+    if write {
+        d.write_block(b.blockno, &b.data);
+    } else {
+        d.read_block(b.blockno, &mut b.data);
+    }
+    b.disk = false;
+}
+
+pub fn flush(dev: u32) {
+    device(dev).flush();
+}
+
+pub fn nblocks(dev: u32) -> u32 {
+    device(dev).nblocks()
+}
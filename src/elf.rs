@@ -0,0 +1,57 @@
+// Format of an ELF executable file
+
+pub const ELF_MAGIC: u32 = 0x464C457F; // "\x7FELF" in little endian
+
+pub const ELFCLASS64: u8 = 2;
+pub const ELFDATA2LSB: u8 = 1; // little endian
+pub const ET_EXEC: u16 = 2;
+pub const EM_RISCV: u16 = 243;
+
+// File header
+#[derive(Default, Clone, Copy)]
+#[repr(C)]
+pub struct ElfHdr {
+    pub magic: u32, // must equal ELF_MAGIC
+    pub class: u8,
+    pub data: u8,
+    pub elf_version: u8,
+    pub osabi: u8,
+    pub abiversion: u8,
+    pub pad: [u8; 7],
+    pub tp: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+// Program section header
+#[derive(Default, Clone, Copy)]
+#[repr(C)]
+pub struct ProgHdr {
+    pub tp: u32,
+    pub flags: u32,
+    pub off: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+// Values for ProgHdr tp
+pub const ELF_PROG_LOAD: u32 = 1;
+
+// Flag bits for ProgHdr flags
+pub const ELF_PROG_FLAG_EXEC: u32 = 1;
+pub const ELF_PROG_FLAG_WRITE: u32 = 2;
+#[allow(dead_code)] // segments are always mapped readable
+pub const ELF_PROG_FLAG_READ: u32 = 4;
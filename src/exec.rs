@@ -0,0 +1,485 @@
+// exec: replace the current process's memory with a program
+// loaded from an ELF64 RISC-V executable.
+//
+// the new image is built in a fresh page table and only swapped in
+// once everything has been loaded, so a failed exec leaves the
+// calling process untouched.
+
+use super::elf::*;
+use super::file::{InodeData, InodeId};
+use super::fs::{self, FsErr};
+use super::memlayout::TRAPFRAME;
+use super::params::{MAXARG, USERSTACK};
+use super::proc::{self, Proc};
+use super::riscv::{Pagetable, MAXVA, PG, PTE};
+use super::sleeplock::SleepLockGuard;
+use super::state::os;
+use super::vm::VmErr;
+use core::mem;
+use core::slice;
+
+// where exec() reads a program from.
+pub trait ReadAt {
+    // read into dst from offset off, return how many bytes were read;
+    // fewer than dst.len() only at the end of the file.
+    fn read_at(&mut self, off: u64, dst: &mut [u8]) -> usize;
+}
+
+// a program image already in memory.
+impl ReadAt for &[u8] {
+    fn read_at(&mut self, off: u64, dst: &mut [u8]) -> usize {
+        if off >= self.len() as u64 {
+            return 0;
+        }
+        let n = dst.len().min(self.len() - off as usize);
+        dst[..n].copy_from_slice(&self[off as usize..off as usize + n]);
+        n
+    }
+}
+
+// a program in a file, locked for as long as exec reads it.
+struct InodeProg<'a> {
+    ip: InodeId,
+    d: SleepLockGuard<'a, InodeData>,
+}
+
+impl ReadAt for InodeProg<'_> {
+    fn read_at(&mut self, off: u64, dst: &mut [u8]) -> usize {
+        if off > u32::MAX as u64 {
+            return 0;
+        }
+        let (va, n) = (dst.as_mut_ptr() as u64, dst.len() as u32);
+        match os().readi(self.ip, &mut self.d, false, va, off as u32, n) {
+            Ok(n) => n as usize,
+            Err(_) => 0,
+        }
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq)]
+pub enum ExecErr {
+    ShortReadErr,       // the file ends inside a header or segment
+    BadMagicErr,        // not an ELF file
+    BadFormatErr,       // not a 64-bit LE RISC-V executable, or offsets wrap
+    BadSegmentErr(u16), // program header i is inconsistent
+    TooManyArgsErr,     // more than MAXARG arguments
+    StackOverflowErr,   // arguments don't fit on the user stack
+    VmErr(VmErr),       // out of memory
+    FsErr(FsErr),       // path does not name a file
+}
+
+impl From<VmErr> for ExecErr {
+    fn from(e: VmErr) -> ExecErr {
+        ExecErr::VmErr(e)
+    }
+}
+
+impl From<FsErr> for ExecErr {
+    fn from(e: FsErr) -> ExecErr {
+        ExecErr::FsErr(e)
+    }
+}
+
+// map ELF segment flags to PTE permissions.
+fn flags2perm(flags: u32) -> u64 {
+    let mut perm = 0;
+    if flags & ELF_PROG_FLAG_EXEC != 0 {
+        perm = PTE::X;
+    }
+    if flags & ELF_PROG_FLAG_WRITE != 0 {
+        perm |= PTE::W;
+    }
+    perm
+}
+
+// read a header out of the file at off.
+fn readhdr<T: Default + Copy>(prog: &mut dyn ReadAt, off: u64) -> Result<T, ExecErr> {
+    let mut hdr = T::default();
+    let buf = unsafe { slice::from_raw_parts_mut(&mut hdr as *mut T as *mut u8, mem::size_of::<T>()) };
+    if prog.read_at(off, buf) != buf.len() {
+        return Err(ExecErr::ShortReadErr);
+    }
+    Ok(hdr)
+}
+
+// Load a program segment into pagetable at virtual address va.
+// va must be page-aligned
+// and the pages from va to va+sz must already be mapped.
+fn loadseg(
+    pagetable: &mut Pagetable,
+    va: u64,
+    prog: &mut dyn ReadAt,
+    offset: u64,
+    sz: u64,
+) -> Result<(), ExecErr> {
+    for i in (0..sz).step_by(PG::SIZE as usize) {
+        let pa = pagetable
+            .walkaddr(va + i)
+            .expect("loadseg: address should exist");
+        let n = (sz - i).min(PG::SIZE) as usize;
+        let dst = unsafe { slice::from_raw_parts_mut(pa as *mut u8, n) };
+        let off = offset.checked_add(i).ok_or(ExecErr::BadFormatErr)?;
+        if prog.read_at(off, dst) != n {
+            return Err(ExecErr::ShortReadErr);
+        }
+    }
+    Ok(())
+}
+
+// load the program's segments into pagetable, growing sz to cover
+// them. returns the entry point.
+fn load(pagetable: &mut Pagetable, sz: &mut u64, prog: &mut dyn ReadAt) -> Result<u64, ExecErr> {
+    // Check ELF header
+    let elf: ElfHdr = readhdr(prog, 0)?;
+    if elf.magic != ELF_MAGIC {
+        return Err(ExecErr::BadMagicErr);
+    }
+    if elf.class != ELFCLASS64
+        || elf.data != ELFDATA2LSB
+        || elf.tp != ET_EXEC
+        || elf.machine != EM_RISCV
+        || elf.phentsize as usize != mem::size_of::<ProgHdr>()
+    {
+        return Err(ExecErr::BadFormatErr);
+    }
+    // the offsets come from the file; don't let them wrap.
+    let phsize = mem::size_of::<ProgHdr>() as u64;
+    if elf.phoff.checked_add(elf.phnum as u64 * phsize).is_none() {
+        return Err(ExecErr::BadFormatErr);
+    }
+
+    // Load program into memory.
+    for i in 0..elf.phnum {
+        let ph: ProgHdr = readhdr(prog, elf.phoff + i as u64 * phsize)?;
+        if ph.tp != ELF_PROG_LOAD {
+            continue;
+        }
+        if ph.off.checked_add(ph.filesz).is_none() {
+            return Err(ExecErr::BadFormatErr);
+        }
+        let end = ph.vaddr.checked_add(ph.memsz).unwrap_or(MAXVA);
+        if ph.memsz < ph.filesz
+            || end >= TRAPFRAME
+            || ph.vaddr & (PG::SIZE - 1) != 0
+            || ph.vaddr < *sz
+        {
+            return Err(ExecErr::BadSegmentErr(i));
+        }
+        *sz = pagetable.uvmalloc(*sz, ph.vaddr + ph.memsz, flags2perm(ph.flags))?;
+        loadseg(pagetable, ph.vaddr, prog, ph.off, ph.filesz)?;
+    }
+    Ok(elf.entry)
+}
+
+// build the user stack above the image of size sz, growing sz,
+// and push argv onto it. returns the user stack pointer.
+fn pushargs(pagetable: &mut Pagetable, sz: &mut u64, argv: &[&[u8]]) -> Result<u64, ExecErr> {
+    if argv.len() > MAXARG {
+        return Err(ExecErr::TooManyArgsErr);
+    }
+
+    // Allocate some pages at the next page boundary.
+    // Make the first inaccessible as a stack guard.
+    // Use the rest as the user stack.
+    let base = PG::roundup(*sz);
+    *sz = pagetable.uvmalloc(base, base + (USERSTACK as u64 + 1) * PG::SIZE, PTE::W)?;
+    pagetable.uvmclear(base);
+    let mut sp = *sz;
+    let stackbase = sp - USERSTACK as u64 * PG::SIZE;
+
+    // Push argument strings, prepare rest of stack in ustack.
+    let mut ustack = [0u64; MAXARG + 1];
+    for (argc, arg) in argv.iter().enumerate() {
+        let len = arg.iter().position(|c| *c == 0).unwrap_or(arg.len());
+        sp = sp.checked_sub(len as u64 + 1).ok_or(ExecErr::StackOverflowErr)?;
+        sp -= sp % 16; // riscv sp must be 16-byte aligned
+        if sp < stackbase {
+            return Err(ExecErr::StackOverflowErr);
+        }
+        pagetable.copyout(sp, &arg[..len])?;
+        pagetable.copyout(sp + len as u64, &[0])?;
+        ustack[argc] = sp;
+    }
+    ustack[argv.len()] = 0;
+
+    // push the array of argv[] pointers.
+    let argc = argv.len();
+    sp -= (argc as u64 + 1) * 8;
+    sp -= sp % 16;
+    if sp < stackbase {
+        return Err(ExecErr::StackOverflowErr);
+    }
+    let ptrs = unsafe { slice::from_raw_parts(ustack.as_ptr() as *const u8, (argc + 1) * 8) };
+    pagetable.copyout(sp, ptrs)?;
+
+    Ok(sp)
+}
+
+// run the program in the file at path in the current process, with
+// arguments argv.
+// returns argc, which ends up in a0, the first argument to
+// user main(argc, argv).
+pub fn exec(path: &[u8], argv: &[&[u8]]) -> Result<u64, ExecErr> {
+    let ip = fs::namei(path)?;
+    let mut prog = InodeProg {
+        ip,
+        d: os().ilock(ip),
+    };
+    let r = exec_from(path, &mut prog, argv);
+    os().iunlock(ip, prog.d);
+    os().iput(ip);
+    r
+}
+
+// run the program read from prog in the current process, with
+// arguments argv. path names the program, for debugging.
+fn exec_from(path: &[u8], prog: &mut dyn ReadAt, argv: &[&[u8]]) -> Result<u64, ExecErr> {
+    let id = os().myproc().expect("exec");
+    let p = &os().procs[id];
+
+    let pagetable = p.proc_pagetable()?;
+    let pt = unsafe { &mut *pagetable };
+    let mut sz = 0;
+    let loaded = load(pt, &mut sz, prog).and_then(|entry| Ok((entry, pushargs(pt, &mut sz, argv)?)));
+    let (entry, sp) = match loaded {
+        Ok(v) => v,
+        Err(e) => {
+            proc::proc_freepagetable(pagetable, sz);
+            return Err(e);
+        }
+    };
+
+    // arguments to user main(argc, argv)
+    // argc is returned via the system call return
+    // value, which goes in a0.
+    let tf = unsafe { &mut *p.tf.get() };
+    tf.a1 = sp;
+
+    // Save program name for debugging.
+    setname(p, path);
+
+    // Commit to the user image.
+    let oldpagetable = p.pagetable.replace(pagetable);
+    let oldsz = p.sz.replace(sz);
+    tf.epc = entry; // initial program counter = main
+    tf.sp = sp; // initial stack pointer
+    proc::proc_freepagetable(oldpagetable, oldsz);
+
+    Ok(argv.len() as u64)
+}
+
+// the last element of path becomes the process name.
+fn setname(p: &Proc, path: &[u8]) {
+    let path = &path[..path.iter().position(|c| *c == 0).unwrap_or(path.len())];
+    let last = match path.iter().rposition(|c| *c == b'/') {
+        Some(i) => &path[i + 1..],
+        None => path,
+    };
+    p.setname(last);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::fs::tests::{putfile, rootdisk};
+    use super::super::kalloc;
+    use super::super::params::{NPROC, ROOTDEV};
+    use super::super::proc::{ProcId, Trapframe};
+    use super::*;
+    use std::convert::TryInto;
+
+    // a RISC-V executable with a text segment at 0 holding text, and a
+    // data segment at the next page holding data followed by bss
+    // bytes of zeroes.
+    pub fn image(text: &[u8], data: &[u8], bss: u64) -> Vec<u8> {
+        let ehsize = mem::size_of::<ElfHdr>();
+        let phsize = mem::size_of::<ProgHdr>();
+        let textoff = (ehsize + 2 * phsize) as u64;
+        let dataoff = textoff + text.len() as u64;
+        let elf = ElfHdr {
+            magic: ELF_MAGIC,
+            class: ELFCLASS64,
+            data: ELFDATA2LSB,
+            elf_version: 1,
+            tp: ET_EXEC,
+            machine: EM_RISCV,
+            version: 1,
+            entry: 0x10,
+            phoff: ehsize as u64,
+            ehsize: ehsize as u16,
+            phentsize: phsize as u16,
+            phnum: 2,
+            ..Default::default()
+        };
+        let text_ph = ProgHdr {
+            tp: ELF_PROG_LOAD,
+            flags: ELF_PROG_FLAG_READ | ELF_PROG_FLAG_EXEC,
+            off: textoff,
+            vaddr: 0,
+            filesz: text.len() as u64,
+            memsz: text.len() as u64,
+            align: PG::SIZE,
+            ..Default::default()
+        };
+        let data_ph = ProgHdr {
+            tp: ELF_PROG_LOAD,
+            flags: ELF_PROG_FLAG_READ | ELF_PROG_FLAG_WRITE,
+            off: dataoff,
+            vaddr: PG::SIZE,
+            filesz: data.len() as u64,
+            memsz: data.len() as u64 + bss,
+            align: PG::SIZE,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        unsafe {
+            buf.extend_from_slice(slice::from_raw_parts(&elf as *const _ as *const u8, ehsize));
+            buf.extend_from_slice(slice::from_raw_parts(&text_ph as *const _ as *const u8, phsize));
+            buf.extend_from_slice(slice::from_raw_parts(&data_ph as *const _ as *const u8, phsize));
+        }
+        buf.extend_from_slice(text);
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    // patch a field of the header at off.
+    fn patch(buf: &mut [u8], off: usize, v: &[u8]) {
+        buf[off..off + v.len()].copy_from_slice(v);
+    }
+
+    // run f as a process in slot NPROC-5 with a one page user image.
+    fn as_proc<F: FnOnce(&Proc)>(f: F) {
+        let _kmem = kalloc::tests::kinit();
+        let mem = kalloc::stats();
+        let id = ProcId(NPROC - 5);
+        let p = &os().procs[id];
+        p.tf.set(kalloc::kalloc().unwrap() as *mut Trapframe);
+        unsafe { *p.tf.get() = Trapframe::default() };
+        p.pagetable.set(p.proc_pagetable().unwrap());
+        p.sz.set(unsafe { (*p.pagetable.get()).uvmalloc(0, PG::SIZE, PTE::W).unwrap() });
+        os().mycpu().proc.set(Some(id));
+
+        f(p);
+
+        os().mycpu().proc.set(None);
+        proc::freeproc(id, p.lock.lock());
+        assert_eq!(kalloc::stats(), mem);
+    }
+
+    fn read(pt: &mut Pagetable, va: u64, n: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; n];
+        pt.copyin(&mut buf, va).unwrap();
+        buf
+    }
+
+    #[test]
+    fn exec_loads_segments_and_args() {
+        as_proc(|p| {
+            let text = [0x13u8; 40]; // nops
+            let elf = image(&text, b"data!", 100);
+            let argc = exec_from(b"/bin/echo\0", &mut &elf[..], &[b"echo\0", b"hi"]).unwrap();
+            assert_eq!(argc, 2);
+            assert_eq!(p.procname(), "echo");
+
+            let pt = unsafe { &mut *p.pagetable.get() };
+            let tf = unsafe { &*p.tf.get() };
+            assert_eq!(tf.epc, 0x10);
+
+            // text is read-execute, data read-write, bss zeroed.
+            assert_eq!(read(pt, 0, 40), &text[..]);
+            assert_eq!(pt.walk(0, false).unwrap().flags(), PTE::V | PTE::R | PTE::X | PTE::U);
+            assert_eq!(read(pt, PG::SIZE, 6), b"data!\0");
+            assert_eq!(
+                pt.walk(PG::SIZE, false).unwrap().flags(),
+                PTE::V | PTE::R | PTE::W | PTE::U
+            );
+            assert!(read(pt, PG::SIZE + 5, 100).iter().all(|b| *b == 0));
+
+            // a guard page, then the stack.
+            assert_eq!(p.sz.get(), 4 * PG::SIZE);
+            assert_eq!(pt.walkaddr(2 * PG::SIZE), None);
+            assert!(pt.walk(2 * PG::SIZE, false).unwrap().is_valid());
+            assert_eq!(tf.sp, tf.a1);
+            assert_eq!(tf.sp % 16, 0);
+            assert!(tf.sp > 3 * PG::SIZE && tf.sp < 4 * PG::SIZE);
+
+            // argv[] then the strings it points to.
+            let ptrs = read(pt, tf.sp, 24);
+            let ptr = |i: usize| u64::from_le_bytes(ptrs[i * 8..i * 8 + 8].try_into().unwrap());
+            assert_eq!(read(pt, ptr(0), 5), b"echo\0");
+            assert_eq!(read(pt, ptr(1), 3), b"hi\0");
+            assert_eq!(ptr(2), 0);
+        });
+    }
+
+    #[test]
+    fn exec_reads_the_program_from_a_file() {
+        as_proc(|p| {
+            let _root = rootdisk();
+            let root = os().iget(ROOTDEV as u32, fs::ROOTINO);
+            let text = [0x13u8; 3000]; // a few disk blocks of nops
+            putfile(root, b"echo", &image(&text, b"data!", 0));
+            os().iput(root);
+
+            let old = p.pagetable.get();
+            assert_eq!(exec(b"/nope", &[]), Err(ExecErr::FsErr(FsErr::NotFoundErr)));
+            assert_eq!(exec(b"/echo/x", &[]), Err(ExecErr::FsErr(FsErr::NotDirErr)));
+            assert_eq!(p.pagetable.get(), old);
+
+            assert_eq!(exec(b"/echo\0", &[b"echo", b"hi"]), Ok(2));
+            assert_eq!(p.procname(), "echo");
+            let pt = unsafe { &mut *p.pagetable.get() };
+            assert_eq!(read(pt, 0, text.len()), &text[..]);
+            assert_eq!(read(pt, PG::SIZE, 5), b"data!");
+            assert_eq!(unsafe { (*p.tf.get()).epc }, 0x10);
+        });
+    }
+
+    #[test]
+    fn exec_rejects_malformed_binaries() {
+        as_proc(|p| {
+            let elf = image(&[0x13; 8], b"d", 0);
+            let old = p.pagetable.get();
+
+            let mut bad = elf.clone();
+            bad[0] = 0;
+            assert_eq!(exec_from(b"x", &mut &bad[..], &[]), Err(ExecErr::BadMagicErr));
+
+            let mut bad = elf.clone();
+            patch(&mut bad, 18, &62u16.to_le_bytes()); // x86-64
+            assert_eq!(exec_from(b"x", &mut &bad[..], &[]), Err(ExecErr::BadFormatErr));
+
+            assert_eq!(exec_from(b"x", &mut &elf[..30], &[]), Err(ExecErr::ShortReadErr));
+            assert_eq!(exec_from(b"x", &mut &elf[..elf.len() - 1], &[]), Err(ExecErr::ShortReadErr));
+
+            // data segment with memsz < filesz.
+            let data_ph = mem::size_of::<ElfHdr>() + mem::size_of::<ProgHdr>();
+            let mut bad = elf.clone();
+            patch(&mut bad, data_ph + 40, &0u64.to_le_bytes());
+            assert_eq!(exec_from(b"x", &mut &bad[..], &[]), Err(ExecErr::BadSegmentErr(1)));
+
+            // data segment overlapping the text segment.
+            let mut bad = elf.clone();
+            patch(&mut bad, data_ph + 16, &0u64.to_le_bytes());
+            assert_eq!(exec_from(b"x", &mut &bad[..], &[]), Err(ExecErr::BadSegmentErr(1)));
+
+            // offsets that wrap around instead of running off the end.
+            let mut bad = elf.clone();
+            patch(&mut bad, 32, &(u64::MAX - 100).to_le_bytes()); // phoff
+            assert_eq!(exec_from(b"x", &mut &bad[..], &[]), Err(ExecErr::BadFormatErr));
+            let mut bad = elf.clone();
+            patch(&mut bad, data_ph + 8, &u64::MAX.to_le_bytes()); // off
+            assert_eq!(exec_from(b"x", &mut &bad[..], &[]), Err(ExecErr::BadFormatErr));
+
+            let args = [&b"a"[..]; MAXARG + 1];
+            assert_eq!(exec_from(b"x", &mut &elf[..], &args), Err(ExecErr::TooManyArgsErr));
+
+            let long = [b'a'; 2 * PG::SIZE as usize];
+            assert_eq!(exec_from(b"x", &mut &elf[..], &[&long[..]]), Err(ExecErr::StackOverflowErr));
+
+            // the old image is still in place.
+            assert_eq!(p.pagetable.get(), old);
+            assert_eq!(p.sz.get(), PG::SIZE);
+        });
+    }
+}
@@ -1,8 +1,13 @@
-use super::pipe;
-use super::sleeplock::SleepLock;
 use super::fs;
 use super::params;
+use super::pipe;
+use super::proc::State;
+use super::sleeplock::SleepLock;
+use super::spinlock::SpinLock;
+use core::cell::Cell;
 
+// nothing opens files yet; dup and close only pass them around.
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum FileType {
     FdNode,
     FdPipe,
@@ -10,36 +15,135 @@ pub enum FileType {
     FdDevice,
 }
 
-#[derive(Default)]
-pub struct OpenFileBufferes<'a>([File<'a>; params::NOFILE]);
+// handle of an open file: index into Ftable.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FileId(pub usize);
+
+// handle of an in-memory inode: index into the inode table.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InodeId(pub usize);
+
+// table of open files shared by all processes.
+pub struct Ftable {
+    pub file: SpinLock<[File; params::NFILE]>,
+}
+
+impl Ftable {
+    pub const fn new() -> Ftable {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const FILE: File = File::new();
+        Ftable {
+            file: SpinLock::new([FILE; params::NFILE], "ftable"),
+        }
+    }
+}
+
+impl State {
+    // Increment ref count for file f.
+    pub fn filedup(&self, f: FileId) -> FileId {
+        let mut file = self.ftable.file.lock();
+        if file[f.0].refc < 1 {
+            panic!("filedup");
+        }
+        file[f.0].refc += 1;
+        f
+    }
+
+    // Close file f.  (Decrement ref count, close when reaches 0.)
+    // the slot goes back to the table; closing the pipe or inode
+    // behind it is up to their owners.
+    pub fn fileclose(&self, f: FileId) {
+        let mut file = self.ftable.file.lock();
+        if file[f.0].refc < 1 {
+            panic!("fileclose");
+        }
+        file[f.0].refc -= 1;
+        if file[f.0].refc == 0 {
+            file[f.0] = File::new();
+        }
+    }
+}
 
+#[allow(dead_code)]
 #[derive(Default)]
-pub struct File<'a> {
+pub struct File {
     pub tp: Option<FileType>,
     pub refc: i32, // reference count.
     pub readable: bool,
     pub writable: bool,
-    pub pipe: Option<&'a pipe::Pipe<'a>>, // FdPipe
-    pub ip: Option<&'a mut Inode<'a>>,        // FdInode and FdDevice
-    pub off: u32,                 // FdInode
-    pub major: i16,               // FdDevice
+    pub pipe: Option<*mut pipe::Pipe>, // FdPipe
+    pub ip: Option<InodeId>,           // FdInode and FdDevice
+    pub off: u32,                      // FdInode
+    pub major: i16,                    // FdDevice
 }
 
-// in-memory copy of an inode
+// the pipe pointer is only followed by the holder of the file table lock.
+unsafe impl Send for File {}
+
+impl File {
+    pub const fn new() -> File {
+        File {
+            tp: None,
+            refc: 0,
+            readable: false,
+            writable: false,
+            pipe: None,
+            ip: None,
+            off: 0,
+            major: 0,
+        }
+    }
+}
+
+// the part of an inode protected by Inode::lock.
 #[derive(Default)]
-pub struct Inode<'a> {
-    pub dev: u32,  // Device number
-    pub inum: u32, // Inode numer
-    pub refc: i32, // reference count
-    pub sleep: SleepLock<'a>,   // protect everything below here
-    pub valid: i32,             // inode has been read from disk?
-
-    pub tp: u16,    // copy of disk inode
+pub struct InodeData {
+    pub valid: bool, // inode has been read from disk?
+
+    pub tp: u16, // copy of disk inode
     pub major: u16,
-    pub miner: u16,
+    pub minor: u16,
     pub nlink: u16,
     pub size: u32,
-    pub addrs: [u32; fs::NDIRECT],
+    pub flags: u32,
+    pub addrs: [u32; fs::NADDRS],
+}
+
+impl InodeData {
+    pub const fn new() -> InodeData {
+        InodeData {
+            valid: false,
+            tp: 0,
+            major: 0,
+            minor: 0,
+            nlink: 0,
+            size: 0,
+            flags: 0,
+            addrs: [0; fs::NADDRS],
+        }
+    }
+}
+
+// in-memory copy of an inode
+// dev, inum and refc are protected by Itable::lock.
+#[derive(Default)]
+pub struct Inode {
+    pub dev: Cell<u32>,             // Device number
+    pub inum: Cell<u32>,            // Inode numer
+    pub refc: Cell<i32>,            // reference count
+    pub lock: SleepLock<InodeData>, // protect everything below here
 }
 
+// the cells are only changed by the holder of Itable::lock.
+unsafe impl Sync for Inode {}
 
+impl Inode {
+    pub const fn new() -> Inode {
+        Inode {
+            dev: Cell::new(0),
+            inum: Cell::new(0),
+            refc: Cell::new(0),
+            lock: SleepLock::new(InodeData::new(), "inode"),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// file disk: a block device backed by a file on the host.
+//
+// lets the buffer cache and file system run against a disk image
+// under cargo test, without qemu.
+
+use super::disk::BlockDevice;
+use super::fs::BSIZE;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+pub struct FileDisk {
+    file: Mutex<File>,
+    nblocks: u32,
+}
+
+impl FileDisk {
+    // open the image at path, creating it or growing it with zero
+    // blocks to nblocks if needed.
+    pub fn open<P: AsRef<Path>>(path: P, nblocks: u32) -> io::Result<FileDisk> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let len = nblocks as u64 * BSIZE as u64;
+        if file.metadata()?.len() < len {
+            file.set_len(len)?;
+        }
+        Ok(FileDisk {
+            file: Mutex::new(file),
+            nblocks,
+        })
+    }
+
+    fn seek(f: &mut File, blockno: u32) {
+        f.seek(SeekFrom::Start(blockno as u64 * BSIZE as u64))
+            .expect("filedisk: seek");
+    }
+}
+
+impl BlockDevice for FileDisk {
+    fn read_block(&self, blockno: u32, data: &mut [u8; BSIZE]) {
+        let mut f = self.file.lock().unwrap();
+        FileDisk::seek(&mut f, blockno);
+        f.read_exact(data).expect("filedisk: read");
+    }
+
+    fn write_block(&self, blockno: u32, data: &[u8; BSIZE]) {
+        let mut f = self.file.lock().unwrap();
+        FileDisk::seek(&mut f, blockno);
+        f.write_all(data).expect("filedisk: write");
+    }
+
+    fn flush(&self) {
+        self.file
+            .lock()
+            .unwrap()
+            .sync_data()
+            .expect("filedisk: sync");
+    }
+
+    fn nblocks(&self) -> u32 {
+        self.nblocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::bio::Bcache;
+    use super::super::disk;
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn blocks_survive_reopening() {
+        let path = env::temp_dir().join(format!("rotonos-filedisk-{}.img", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let d = Box::leak(Box::new(FileDisk::open(&path, 16).unwrap()));
+        disk::register(9, d);
+        let bcache = Box::new(Bcache::new());
+        let mut b = bcache.bread(9, 5);
+        assert_eq!(b.data, [0; BSIZE]);
+        b.data = [0x5a; BSIZE];
+        bcache.bwrite(&mut b);
+        drop(b);
+        bcache.bflush(9);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 16 * BSIZE as u64);
+
+        let mut data = [0; BSIZE];
+        FileDisk::open(&path, 16).unwrap().read_block(5, &mut data);
+        assert_eq!(data, [0x5a; BSIZE]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,4 +1,2241 @@
-pub const ROOTINO: u32 = 1;
-pub const BSIZE: usize = 32;
+// File system implementation.  Five layers so far:
+//   + Blocks: allocator for raw disk blocks.
+//   + Files: inode allocator, reading, writing, metadata.
+//   + Directories: inode with special contents (list of other inodes!)
+//   + Names: paths like /usr/rtm/xv6/fs.c for convenient naming.
+//   + Disk layout: the superblock and mkfs.
+//
+// This file contains the low-level file system manipulation
+// routines.
+//
+// there is no log yet, so blocks are written straight through
+// with bwrite().
 
-pub const NDIRECT: usize = 12;
+use super::bio::BufRef;
+use super::disk;
+use super::file::{Inode, InodeData, InodeId};
+use super::params::{LOGSIZE, NDISK, NINODE, ROOTDEV};
+use super::proc::{either_copyin, either_copyout, State};
+use super::sleeplock::SleepLockGuard;
+use super::spinlock::SpinLock;
+use super::stat::{T_DEVICE, T_DIR, T_FILE};
+use super::state::os;
+use super::vm::VmErr;
+use core::convert::TryInto;
+use core::mem;
+use core::ptr;
+
+pub const ROOTINO: u32 = 1; // root i-number
+pub const BSIZE: usize = 1024; // block size
+
+// Disk layout:
+// [ boot block | super block | log | inode blocks |
+//                                          free bit map | data blocks]
+//
+// mkfs computes the super block and builds an initial file system. The
+// super block describes the disk layout:
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct SuperBlock {
+    pub magic: u32,      // Must be FSMAGIC
+    pub size: u32,       // Size of file system image (blocks)
+    pub nblocks: u32,    // Number of data blocks
+    pub ninodes: u32,    // Number of inodes.
+    pub nlog: u32,       // Number of log blocks
+    pub logstart: u32,   // Block number of first log block
+    pub inodestart: u32, // Block number of first inode block
+    pub bmapstart: u32,  // Block number of first free map block
+}
+
+impl SuperBlock {
+    pub const fn new() -> SuperBlock {
+        SuperBlock {
+            magic: 0,
+            size: 0,
+            nblocks: 0,
+            ninodes: 0,
+            nlog: 0,
+            logstart: 0,
+            inodestart: 0,
+            bmapstart: 0,
+        }
+    }
+}
+
+pub const FSMAGIC: u32 = 0x10203040;
+
+// an inode maps its first NDIRECT blocks directly, then one
+// singly-, one doubly- and one triply-indirect block, in that order.
+// with F_EXTENT set, addrs holds extents instead.
+//
+// this is not xv6's on-disk format, which has 12 direct blocks and
+// one singly-indirect block. the dinode stays 64 bytes, so the
+// doubly- and triply-indirect slots and the flags word are taken
+// from the direct range, and disks made with the old layout must be
+// remade by mkfs. MAXFILE is 9 + 256 + 256^2 + 256^3 = 16843017
+// blocks, about 16 GiB, but sizes are u32, so a file stops at 4 GiB.
+pub const NDIRECT: usize = 9;
+pub const NINDIRECT: usize = BSIZE / mem::size_of::<u32>();
+pub const NDINDIRECT: usize = NINDIRECT * NINDIRECT;
+pub const NTINDIRECT: usize = NDINDIRECT * NINDIRECT;
+pub const NADDRS: usize = NDIRECT + 3;
+pub const MAXFILE: usize = NDIRECT + NINDIRECT + NDINDIRECT + NTINDIRECT;
+
+// Dinode flags
+pub const F_EXTENT: u32 = 1; // blocks are mapped by extents
+
+// a run of len blocks starting at block start.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct Extent {
+    pub start: u32,
+    pub len: u32,
+}
+
+// an extent-mapped inode keeps its first NEXTENT extents in addrs,
+// and the rest in a tree whose root is block addrs[XADDR].
+//
+// every tree block starts with a (depth, count) header, followed by
+// up to NXEXTENT entries. a leaf, at depth 0, holds extents. an index
+// block holds (first, addr) pairs: a child block and the first file
+// block it maps. files only grow at the end, so the tree does too: a
+// full block gets a new sibling, and a full root a new root above it.
+// with at most MAXEDEPTH index levels a file has up to 5 + 127^2
+// extents, 16 MiB even if every one of them is a single block; a
+// write that needs more fails with FileTooBigErr.
+pub const NEXTENT: usize = (NADDRS - 1) / 2;
+pub const NXEXTENT: usize = BSIZE / mem::size_of::<Extent>() - 1;
+pub const XADDR: usize = NADDRS - 1;
+pub const MAXEDEPTH: usize = 1;
+
+// On-disk inode structure
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct Dinode {
+    pub tp: u16,              // File type
+    pub major: u16,           // Major device number (T_DEVICE only)
+    pub minor: u16,           // Minor device number (T_DEVICE only)
+    pub nlink: u16,           // Number of links to inode in file system
+    pub size: u32,            // Size of file (bytes)
+    pub flags: u32,           // F_EXTENT
+    pub addrs: [u32; NADDRS], // Data block addresses
+}
+
+// Inodes per block.
+pub const IPB: u32 = (BSIZE / mem::size_of::<Dinode>()) as u32;
+
+// inodes must not straddle blocks, and the format above assumes
+// 64 byte dinodes.
+const _: () = assert!(BSIZE % mem::size_of::<Dinode>() == 0);
+const _: () = assert!(mem::size_of::<Dinode>() == 64);
+
+// Block containing inode i
+pub fn iblock(i: u32, sb: &SuperBlock) -> u32 {
+    i / IPB + sb.inodestart
+}
+
+// Directory is a file containing a sequence of Dirent structures.
+pub const DIRSIZ: usize = 14;
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct Dirent {
+    pub inum: u16, // 0 for a free entry
+    pub name: [u8; DIRSIZ],
+}
+
+const DIRENTSZ: u32 = mem::size_of::<Dirent>() as u32;
+
+// Bitmap bits per block
+pub const BPB: u32 = (BSIZE * 8) as u32;
+
+// Block of free map containing bit for block b
+pub fn bblock(b: u32, sb: &SuperBlock) -> u32 {
+    b / BPB + sb.bmapstart
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq)]
+pub enum FsErr {
+    NoBlocksErr,
+    NoInodesErr,
+    FileTooBigErr, // past MAXFILE blocks, the extent tree or 4 GiB
+    BadOffsetErr,  // write starting beyond the end of the file
+    VmErr(VmErr),  // bad user or kernel buffer
+    NotFoundErr,   // no such file or directory
+    NotDirErr,     // a path element is not a directory
+    ExistsErr,     // the name is already in the directory
+}
+
+impl From<VmErr> for FsErr {
+    fn from(e: VmErr) -> Self {
+        FsErr::VmErr(e)
+    }
+}
+
+// there should be one superblock per disk device.
+static SB: SpinLock<[SuperBlock; NDISK]> = SpinLock::new([SuperBlock::new(); NDISK], "sb");
+
+// the superblock of the file system on dev, as read by fsinit().
+fn sb(dev: u32) -> SuperBlock {
+    SB.lock()[dev as usize]
+}
+
+// Read the super block.
+fn readsb(dev: u32) -> SuperBlock {
+    let bp = os().bcache.bread(dev, 1);
+    unsafe { ptr::read_unaligned(bp.data.as_ptr() as *const SuperBlock) }
+}
+
+// Init fs
+pub fn fsinit(dev: u32) {
+    let sb = readsb(dev);
+    if sb.magic != FSMAGIC {
+        panic!("invalid file system");
+    }
+    SB.lock()[dev as usize] = sb;
+}
+
+// make an empty file system with ninodes inodes on dev, filling the
+// whole device, and load its superblock. the root directory, with
+// just . and .., is the only inode in use.
+pub fn mkfs(dev: u32, ninodes: u32) {
+    let size = disk::nblocks(dev);
+    let nlog = LOGSIZE as u32;
+    let ninodeblocks = ninodes / IPB + 1;
+    let nbitmap = size / BPB + 1;
+    let nmeta = 2 + nlog + ninodeblocks + nbitmap;
+    if nmeta >= size {
+        panic!("mkfs: {} blocks is too small", size);
+    }
+
+    let sb = SuperBlock {
+        magic: FSMAGIC,
+        size,
+        nblocks: size - nmeta,
+        ninodes,
+        nlog,
+        logstart: 2,
+        inodestart: 2 + nlog,
+        bmapstart: 2 + nlog + ninodeblocks,
+    };
+
+    let bcache = &os().bcache;
+    for b in 0..nmeta {
+        let mut bp = bcache.bread(dev, b);
+        bp.data = [0; BSIZE];
+        if b == 1 {
+            unsafe { ptr::write_unaligned(bp.data.as_mut_ptr() as *mut SuperBlock, sb) };
+        }
+        // the metadata blocks are in use.
+        if b >= sb.bmapstart {
+            let first = (b - sb.bmapstart) * BPB;
+            for bi in 0..nmeta.saturating_sub(first).min(BPB) {
+                bp.data[bi as usize / 8] |= 1 << (bi % 8);
+            }
+        }
+        bcache.bwrite(&mut bp);
+    }
+    fsinit(dev);
+
+    let root = os().ialloc(dev, T_DIR, 0).expect("mkfs: root");
+    assert_eq!(os().itable.inode[root.0].inum.get(), ROOTINO);
+    let mut d = os().ilock(root);
+    d.nlink = 1;
+    os().iupdate(root, &d);
+    // the root is its own parent.
+    os().dirlink(root, &mut d, b".", ROOTINO).expect("mkfs: .");
+    os().dirlink(root, &mut d, b"..", ROOTINO)
+        .expect("mkfs: ..");
+    drop(d);
+    os().iput(root);
+
+    // don't let a crash leave a half-made file system behind.
+    os().bcache.bflush(dev);
+}
+
+// the dinode for inum in its inode block.
+fn getdinode(bp: &BufRef<'_>, inum: u32) -> Dinode {
+    let off = (inum % IPB) as usize * mem::size_of::<Dinode>();
+    unsafe { ptr::read_unaligned(bp.data[off..].as_ptr() as *const Dinode) }
+}
+
+fn putdinode(bp: &mut BufRef<'_>, inum: u32, dip: &Dinode) {
+    let off = (inum % IPB) as usize * mem::size_of::<Dinode>();
+    unsafe { ptr::write_unaligned(bp.data[off..].as_mut_ptr() as *mut Dinode, *dip) };
+}
+
+// the i'th block address in an indirect block.
+fn getaddr(bp: &BufRef<'_>, i: usize) -> u32 {
+    u32::from_le_bytes(bp.data[4 * i..4 * i + 4].try_into().unwrap())
+}
+
+fn putaddr(bp: &mut BufRef<'_>, i: usize, addr: u32) {
+    bp.data[4 * i..4 * i + 4].copy_from_slice(&addr.to_le_bytes());
+}
+
+// the (depth, count) header of an extent tree block.
+fn getehdr(bp: &BufRef<'_>) -> (usize, usize) {
+    (getaddr(bp, 0) as usize, getaddr(bp, 1) as usize)
+}
+
+fn putehdr(bp: &mut BufRef<'_>, depth: usize, n: usize) {
+    putaddr(bp, 0, depth as u32);
+    putaddr(bp, 1, n as u32);
+}
+
+// the i'th entry of an extent tree block: (start, len) in a leaf,
+// (first, addr) in an index block.
+fn getentry(bp: &BufRef<'_>, i: usize) -> (u32, u32) {
+    (getaddr(bp, 2 * i + 2), getaddr(bp, 2 * i + 3))
+}
+
+fn putentry(bp: &mut BufRef<'_>, i: usize, (a, b): (u32, u32)) {
+    putaddr(bp, 2 * i + 2, a);
+    putaddr(bp, 2 * i + 3, b);
+}
+
+// how many of the extents in d.addrs are in use, and the number
+// of blocks they map.
+fn einline(d: &InodeData) -> (usize, u32) {
+    let n = (0..NEXTENT).take_while(|&i| d.addrs[2 * i + 1] != 0).count();
+    (n, (0..n).map(|i| d.addrs[2 * i + 1]).sum())
+}
+
+// the right edge of an extent tree: the blocks on the way from the
+// root (node[0]) down to the last leaf (node[depth]), and the first
+// file block that leaf maps.
+struct Edge {
+    depth: usize,
+    node: [u32; MAXEDEPTH + 1],
+    base: u32,
+}
+
+// the in-memory inodes. an entry is free when its refc is 0.
+pub struct Itable {
+    lock: SpinLock<()>,
+    pub inode: [Inode; NINODE],
+}
+
+impl Itable {
+    pub const fn new() -> Itable {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INODE: Inode = Inode::new();
+        Itable {
+            lock: SpinLock::new((), "itable"),
+            inode: [INODE; NINODE],
+        }
+    }
+}
+
+// Blocks.
+impl State {
+    // Zero a block.
+    fn bzero(&self, dev: u32, bno: u32) {
+        let mut bp = self.bcache.bread(dev, bno);
+        bp.data = [0; BSIZE];
+        self.bcache.bwrite(&mut bp);
+    }
+
+    // Allocate a zeroed disk block.
+    pub fn balloc(&self, dev: u32) -> Result<u32, FsErr> {
+        let sb = sb(dev);
+        for b in (0..sb.size).step_by(BPB as usize) {
+            let mut bp = self.bcache.bread(dev, bblock(b, &sb));
+            for bi in 0..BPB.min(sb.size - b) {
+                let m = 1 << (bi % 8);
+                if bp.data[bi as usize / 8] & m == 0 {
+                    // Is block free?
+                    bp.data[bi as usize / 8] |= m; // Mark block in use.
+                    self.bcache.bwrite(&mut bp);
+                    self.bcache.brelse(bp);
+                    self.bzero(dev, b + bi);
+                    return Ok(b + bi);
+                }
+            }
+        }
+        Err(FsErr::NoBlocksErr)
+    }
+
+    // Free a disk block.
+    pub fn bfree(&self, dev: u32, b: u32) {
+        let sb = sb(dev);
+        let mut bp = self.bcache.bread(dev, bblock(b, &sb));
+        let bi = b % BPB;
+        let m = 1 << (bi % 8);
+        if bp.data[bi as usize / 8] & m == 0 {
+            panic!("freeing free block");
+        }
+        bp.data[bi as usize / 8] &= !m;
+        self.bcache.bwrite(&mut bp);
+    }
+}
+
+// Runs of blocks, for extents.
+impl State {
+    // Allocate up to n free blocks starting exactly at block
+    // start, stopping at the first block in use (or the end of
+    // its bitmap block). Zeroes them and returns how many.
+    pub fn brun(&self, dev: u32, start: u32, n: u32) -> u32 {
+        let sb = sb(dev);
+        if start >= sb.size {
+            return 0;
+        }
+        let limit = n.min(sb.size - start).min(BPB - start % BPB);
+
+        let mut bp = self.bcache.bread(dev, bblock(start, &sb));
+        let mut got = 0;
+        while got < limit {
+            let bi = (start + got) % BPB;
+            let m = 1 << (bi % 8);
+            if bp.data[bi as usize / 8] & m != 0 {
+                break;
+            }
+            bp.data[bi as usize / 8] |= m; // Mark block in use.
+            got += 1;
+        }
+        if got > 0 {
+            self.bcache.bwrite(&mut bp);
+        }
+        self.bcache.brelse(bp);
+
+        for b in start..start + got {
+            self.bzero(dev, b);
+        }
+        got
+    }
+
+    // Allocate a run of contiguous zeroed blocks: the first free
+    // run of at least n blocks, or else the longest there is.
+    // returns its start and length, which is at most n.
+    pub fn balloc_run(&self, dev: u32, n: u32) -> Result<(u32, u32), FsErr> {
+        if n == 0 {
+            // any free block would do, and brun() would take none of it.
+            return Ok((0, 0));
+        }
+        let sb = sb(dev);
+        loop {
+            // (start, len) of the best run so far.
+            let mut best = (0, 0);
+            'scan: for b in (0..sb.size).step_by(BPB as usize) {
+                let bp = self.bcache.bread(dev, bblock(b, &sb));
+                let mut run = (0, 0);
+                for bi in 0..BPB.min(sb.size - b) {
+                    if bp.data[bi as usize / 8] & (1 << (bi % 8)) != 0 {
+                        run.1 = 0;
+                        continue;
+                    }
+                    if run.1 == 0 {
+                        run.0 = b + bi;
+                    }
+                    run.1 += 1;
+                    if run.1 > best.1 {
+                        best = run;
+                    }
+                    if best.1 >= n {
+                        break 'scan;
+                    }
+                }
+            }
+            if best.1 == 0 {
+                return Err(FsErr::NoBlocksErr);
+            }
+
+            // the bitmap was not held in between, so someone may
+            // have taken part of the run; take what is left.
+            let got = self.brun(dev, best.0, best.1.min(n));
+            if got > 0 {
+                return Ok((best.0, got));
+            }
+        }
+    }
+}
+
+// Inodes.
+//
+// An inode describes a single unnamed file.
+// The inode disk structure holds metadata: the file's type,
+// its size, the number of links referring to it, and the
+// list of blocks holding the file's content.
+//
+// The inodes are laid out sequentially on disk at block
+// sb.inodestart. Each inode has a number, indicating its
+// position on the disk.
+//
+// The kernel keeps a table of in-use inodes in memory
+// to provide a place for synchronizing access
+// to inodes used by multiple processes. The in-memory
+// inodes include book-keeping information that is
+// not stored on disk: ip.refc and ip.valid.
+//
+// An inode and its in-memory representation go through a
+// sequence of states before they can be used by the
+// rest of the file system code.
+//
+// * Allocation: an inode is allocated if its type (on disk)
+//   is non-zero. ialloc() allocates, and iput() frees if
+//   the reference and link counts have fallen to zero.
+//
+// * Referencing in table: an entry in the inode table
+//   is free if ip.refc is zero. Otherwise ip.refc tracks
+//   the number of in-memory pointers to the entry (open
+//   files and current directories). iget() finds or
+//   creates a table entry and increments its ref; iput()
+//   decrements ref.
+//
+// * Valid: the information (type, size, &c) in an inode
+//   table entry is only correct when valid is true.
+//   ilock() reads the inode from
+//   the disk and sets valid, while iput() clears
+//   valid if ip.refc has fallen to zero.
+//
+// * Locked: file system code may only examine and modify
+//   the information in an inode and its content if it
+//   has first locked the inode.
+//
+// Thus a typical sequence is:
+//   ip = iget(dev, inum)
+//   d = ilock(ip)
+//   ... examine and modify d ...
+//   iunlock(ip, d)
+//   iput(ip)
+//
+// ilock() is separate from iget() so that system calls can
+// get a long-term reference to an inode (as for an open file)
+// and only lock it for short periods (e.g., in read()).
+// The separation also helps avoid deadlock and races during
+// pathname lookup. iget() increments ip.refc so that the inode
+// stays in the table and pointers to it remain valid.
+//
+// Many internal file system functions expect the caller to
+// have locked the inodes involved; this lets callers create
+// multi-step atomic operations.
+//
+// The itable.lock spin-lock protects the allocation of itable
+// entries. Since ip.refc indicates whether an entry is free,
+// and ip.dev and ip.inum indicate which i-node an entry
+// holds, one must hold itable.lock while using any of those fields.
+//
+// An ip.lock sleep-lock protects all ip fields other than refc,
+// dev, and inum.  One must hold ip.lock in order to
+// read or write that inode's ip.valid, ip.size, ip.tp, &c.
+impl State {
+    // Allocate an inode on device dev.
+    // Mark it as allocated by  giving it type tp.
+    // flags are its Dinode flags, F_EXTENT to map it by extents.
+    // Returns an unlocked but allocated and referenced inode.
+    pub fn ialloc(&self, dev: u32, tp: u16, flags: u32) -> Result<InodeId, FsErr> {
+        let sb = sb(dev);
+        for inum in 1..sb.ninodes {
+            let mut bp = self.bcache.bread(dev, iblock(inum, &sb));
+            if getdinode(&bp, inum).tp == 0 {
+                // a free inode
+                let dip = Dinode {
+                    tp,
+                    flags,
+                    ..Default::default()
+                };
+                putdinode(&mut bp, inum, &dip);
+                self.bcache.bwrite(&mut bp); // mark it allocated on the disk
+                self.bcache.brelse(bp);
+                return Ok(self.iget(dev, inum));
+            }
+        }
+        Err(FsErr::NoInodesErr)
+    }
+
+    // Copy a modified in-memory inode to disk.
+    // Must be called after every change to an ip.xxx field
+    // that lives on disk.
+    // Caller must hold ip.lock, d is what it guards.
+    pub fn iupdate(&self, ip: InodeId, d: &InodeData) {
+        let (dev, inum) = (self.itable.inode[ip.0].dev.get(), self.itable.inode[ip.0].inum.get());
+        let sb = sb(dev);
+        let mut bp = self.bcache.bread(dev, iblock(inum, &sb));
+        let dip = Dinode {
+            tp: d.tp,
+            major: d.major,
+            minor: d.minor,
+            nlink: d.nlink,
+            size: d.size,
+            flags: d.flags,
+            addrs: d.addrs,
+        };
+        putdinode(&mut bp, inum, &dip);
+        self.bcache.bwrite(&mut bp);
+    }
+
+    // Find the inode with number inum on device dev
+    // and return the in-memory copy. Does not lock
+    // the inode and does not read it from disk.
+    pub fn iget(&self, dev: u32, inum: u32) -> InodeId {
+        let _lock = self.itable.lock.lock();
+
+        // Is the inode already in the table?
+        let mut empty = None;
+        for (i, ip) in self.itable.inode.iter().enumerate() {
+            if ip.refc.get() > 0 && ip.dev.get() == dev && ip.inum.get() == inum {
+                ip.refc.set(ip.refc.get() + 1);
+                return InodeId(i);
+            }
+            if empty.is_none() && ip.refc.get() == 0 {
+                // Remember empty slot.
+                empty = Some(i);
+            }
+        }
+
+        // Recycle an inode entry.
+        let i = empty.expect("iget: no inodes");
+        let ip = &self.itable.inode[i];
+        ip.dev.set(dev);
+        ip.inum.set(inum);
+        ip.refc.set(1);
+        unsafe { ip.lock.get_mut_unchecked().valid = false };
+        InodeId(i)
+    }
+
+    // Increment reference count for ip.
+    // Returns ip to enable ip = idup(ip1) idiom.
+    pub fn idup(&self, ip: InodeId) -> InodeId {
+        let _lock = self.itable.lock.lock();
+        let inode = &self.itable.inode[ip.0];
+        inode.refc.set(inode.refc.get() + 1);
+        ip
+    }
+
+    // Lock the given inode.
+    // Reads the inode from disk if necessary.
+    // the inode stays locked until iunlock() (or until the
+    // guard is dropped).
+    pub fn ilock(&self, ip: InodeId) -> SleepLockGuard<'_, InodeData> {
+        let inode = &self.itable.inode[ip.0];
+        if inode.refc.get() < 1 {
+            panic!("ilock");
+        }
+
+        let mut d = inode.lock.lock();
+        if !d.valid {
+            let (dev, inum) = (inode.dev.get(), inode.inum.get());
+            let sb = sb(dev);
+            let bp = self.bcache.bread(dev, iblock(inum, &sb));
+            let dip = getdinode(&bp, inum);
+            self.bcache.brelse(bp);
+            d.tp = dip.tp;
+            d.major = dip.major;
+            d.minor = dip.minor;
+            d.nlink = dip.nlink;
+            d.size = dip.size;
+            d.flags = dip.flags;
+            d.addrs = dip.addrs;
+            d.valid = true;
+            if d.tp == 0 {
+                panic!("ilock: no type");
+            }
+        }
+        d
+    }
+
+    // Unlock the given inode.
+    pub fn iunlock(&self, ip: InodeId, d: SleepLockGuard<'_, InodeData>) {
+        let inode = &self.itable.inode[ip.0];
+        if !inode.lock.holding() || inode.refc.get() < 1 {
+            panic!("iunlock");
+        }
+        drop(d);
+    }
+
+    // Drop a reference to an in-memory inode.
+    // If that was the last reference, the inode table entry can
+    // be recycled.
+    // If that was the last reference and the inode has no links
+    // to it, free the inode (and its content) on disk.
+    pub fn iput(&self, ip: InodeId) {
+        let mut lock = self.itable.lock.lock();
+        let refc = self.itable.inode[ip.0].refc.get();
+        if refc < 1 {
+            panic!("iput");
+        }
+
+        if refc == 1 {
+            // refc == 1 means no other process can have ip locked,
+            // so this lock won't block (or deadlock).
+            let mut d = self.itable.inode[ip.0].lock.lock();
+            if d.valid && d.nlink == 0 {
+                // inode has no links and no other references:
+                // truncate and free.
+                drop(lock);
+                self.itrunc(ip, &mut d);
+                d.tp = 0;
+                self.iupdate(ip, &d);
+                d.valid = false;
+                drop(d);
+                lock = self.itable.lock.lock();
+            }
+        }
+
+        let inode = &self.itable.inode[ip.0];
+        inode.refc.set(inode.refc.get() - 1);
+        drop(lock);
+    }
+
+    // Inode content
+    //
+    // The content (data) associated with each inode is stored
+    // in blocks on the disk. The first NDIRECT block numbers
+    // are listed in d.addrs[].  The next NINDIRECT blocks are
+    // listed in block d.addrs[NDIRECT], the NDINDIRECT after
+    // that in the blocks listed by block d.addrs[NDIRECT + 1],
+    // and the last NTINDIRECT one level further down from
+    // d.addrs[NDIRECT + 2].
+    //
+    // sizes are u32, so a file stops at 4 GiB, well inside the
+    // triply-indirect range.
+
+    // Return the disk block address of the nth block in inode ip.
+    // If there is no such block, bmap allocates one.
+    // fails if out of disk space or if bn is past MAXFILE.
+    fn bmap(&self, ip: InodeId, d: &mut InodeData, bn: u32) -> Result<u32, FsErr> {
+        let dev = self.itable.inode[ip.0].dev.get();
+        if d.flags & F_EXTENT != 0 {
+            return self.emap(dev, d, bn);
+        }
+        let mut bn = bn as usize;
+
+        if bn < NDIRECT {
+            if d.addrs[bn] == 0 {
+                d.addrs[bn] = self.balloc(dev)?;
+            }
+            return Ok(d.addrs[bn]);
+        }
+        bn -= NDIRECT;
+
+        // span is the number of blocks under the indirect block
+        // of each level.
+        let mut span = NINDIRECT;
+        for level in 0..3 {
+            if bn < span {
+                // Load indirect block, allocating if necessary.
+                if d.addrs[NDIRECT + level] == 0 {
+                    d.addrs[NDIRECT + level] = self.balloc(dev)?;
+                }
+                return self.bmapind(dev, d.addrs[NDIRECT + level], bn, span / NINDIRECT);
+            }
+            bn -= span;
+            span *= NINDIRECT;
+        }
+
+        Err(FsErr::FileTooBigErr)
+    }
+
+    // the bn'th block under the indirect block at addr, whose
+    // entries each cover per blocks. allocates missing blocks on
+    // the way down.
+    fn bmapind(
+        &self,
+        dev: u32,
+        mut addr: u32,
+        mut bn: usize,
+        mut per: usize,
+    ) -> Result<u32, FsErr> {
+        loop {
+            let mut bp = self.bcache.bread(dev, addr);
+            let i = bn / per;
+            let mut next = getaddr(&bp, i);
+            if next == 0 {
+                next = self.balloc(dev)?;
+                putaddr(&mut bp, i, next);
+                self.bcache.bwrite(&mut bp);
+            }
+            if per == 1 {
+                return Ok(next);
+            }
+            addr = next;
+            bn %= per;
+            per /= NINDIRECT;
+        }
+    }
+
+    // Truncate inode (discard contents).
+    // Caller must hold ip.lock, d is what it guards.
+    pub fn itrunc(&self, ip: InodeId, d: &mut InodeData) {
+        let dev = self.itable.inode[ip.0].dev.get();
+        if d.flags & F_EXTENT != 0 {
+            self.etrunc(dev, d);
+            d.size = 0;
+            self.iupdate(ip, d);
+            return;
+        }
+
+        for i in 0..NDIRECT {
+            if d.addrs[i] != 0 {
+                self.bfree(dev, d.addrs[i]);
+                d.addrs[i] = 0;
+            }
+        }
+
+        for level in 0..3 {
+            if d.addrs[NDIRECT + level] != 0 {
+                self.ifree(dev, d.addrs[NDIRECT + level], level + 1);
+                d.addrs[NDIRECT + level] = 0;
+            }
+        }
+
+        d.size = 0;
+        self.iupdate(ip, d);
+    }
+
+    // free the indirect block at addr and every block under it.
+    // depth counts the levels of indirect blocks, 1 when the
+    // entries are data blocks.
+    fn ifree(&self, dev: u32, addr: u32, depth: usize) {
+        let bp = self.bcache.bread(dev, addr);
+        for j in 0..NINDIRECT {
+            let a = getaddr(&bp, j);
+            if a == 0 {
+                continue;
+            }
+            if depth > 1 {
+                self.ifree(dev, a, depth - 1);
+            } else {
+                self.bfree(dev, a);
+            }
+        }
+        self.bcache.brelse(bp);
+        self.bfree(dev, addr);
+    }
+
+    // Extents
+    //
+    // an inode with F_EXTENT maps its blocks as a list of runs:
+    // file block 0 is the first block of the first extent, and
+    // each extent continues where the one before it ends.
+    // extents 0..NEXTENT live in d.addrs as (start, len) pairs,
+    // unused ones with len 0, and the rest in the tree at
+    // d.addrs[XADDR], which is only made once those are full.
+    // the flag is given to ialloc() and stays for the life of the
+    // inode.
+
+    // the right edge of the extent tree of d, which must have one.
+    fn eedge(&self, dev: u32, d: &InodeData) -> Edge {
+        let mut edge = Edge {
+            depth: 0,
+            node: [0; MAXEDEPTH + 1],
+            base: einline(d).1,
+        };
+        edge.node[0] = d.addrs[XADDR];
+        loop {
+            let bp = self.bcache.bread(dev, edge.node[edge.depth]);
+            let (depth, n) = getehdr(&bp);
+            if depth == 0 {
+                return edge;
+            }
+            let (first, addr) = getentry(&bp, n - 1);
+            edge.base = first;
+            edge.depth += 1;
+            edge.node[edge.depth] = addr;
+        }
+    }
+
+    // the number of blocks the extents of d map.
+    fn eblocks(&self, dev: u32, d: &InodeData) -> u32 {
+        if d.addrs[XADDR] == 0 {
+            return einline(d).1;
+        }
+        let edge = self.eedge(dev, d);
+        let bp = self.bcache.bread(dev, edge.node[edge.depth]);
+        let (_, n) = getehdr(&bp);
+        edge.base + (0..n).map(|i| getentry(&bp, i).1).sum::<u32>()
+    }
+
+    // grow the last extent of d in place by up to n blocks, as far
+    // as the blocks after it are free. returns how many it took.
+    fn eextend(&self, dev: u32, d: &mut InodeData, n: u32) -> u32 {
+        if d.addrs[XADDR] == 0 {
+            let i = match einline(d).0 {
+                0 => return 0,
+                i => i - 1,
+            };
+            let got = self.brun(dev, d.addrs[2 * i] + d.addrs[2 * i + 1], n);
+            d.addrs[2 * i + 1] += got;
+            return got;
+        }
+
+        let edge = self.eedge(dev, d);
+        let mut bp = self.bcache.bread(dev, edge.node[edge.depth]);
+        let (_, cnt) = getehdr(&bp);
+        let (start, len) = getentry(&bp, cnt - 1);
+        let got = self.brun(dev, start + len, n);
+        if got > 0 {
+            putentry(&mut bp, cnt - 1, (start, len + got));
+            self.bcache.bwrite(&mut bp);
+        }
+        got
+    }
+
+    // a new extent tree block at depth, holding just entry.
+    fn enode(&self, dev: u32, depth: usize, entry: (u32, u32)) -> Result<u32, FsErr> {
+        let b = self.balloc(dev)?;
+        let mut bp = self.bcache.bread(dev, b);
+        putehdr(&mut bp, depth, 1);
+        putentry(&mut bp, 0, entry);
+        self.bcache.bwrite(&mut bp);
+        Ok(b)
+    }
+
+    // a new branch of depth levels above a leaf that holds just ex,
+    // which starts at file block first. returns its top block.
+    fn ebranch(&self, dev: u32, depth: usize, ex: Extent, first: u32) -> Result<u32, FsErr> {
+        let mut top = self.enode(dev, 0, (ex.start, ex.len))?;
+        for level in 1..=depth {
+            match self.enode(dev, level, (first, top)) {
+                Ok(b) => top = b,
+                Err(e) => {
+                    self.eunbranch(dev, top);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(top)
+    }
+
+    // free the tree blocks of a branch made by ebranch(), but not
+    // the blocks of its extent.
+    fn eunbranch(&self, dev: u32, top: u32) {
+        let mut addr = top;
+        loop {
+            let bp = self.bcache.bread(dev, addr);
+            let (depth, _) = getehdr(&bp);
+            let (_, child) = getentry(&bp, 0);
+            self.bcache.brelse(bp);
+            self.bfree(dev, addr);
+            if depth == 0 {
+                return;
+            }
+            addr = child;
+        }
+    }
+
+    // add ex after the last extent of d; first is the file block
+    // it starts at. if there is no room left in the tree, or on the
+    // disk for the tree blocks it needs, fails and leaves d as it was.
+    fn eappend(&self, dev: u32, d: &mut InodeData, ex: Extent, first: u32) -> Result<(), FsErr> {
+        if d.addrs[XADDR] == 0 {
+            let i = einline(d).0;
+            if i < NEXTENT {
+                d.addrs[2 * i] = ex.start;
+                d.addrs[2 * i + 1] = ex.len;
+            } else {
+                d.addrs[XADDR] = self.enode(dev, 0, (ex.start, ex.len))?;
+            }
+            return Ok(());
+        }
+
+        // the deepest block on the right edge with room for another
+        // entry gets one, leading down to ex through a new branch.
+        let edge = self.eedge(dev, d);
+        let room = (0..=edge.depth).rev().find(|&level| {
+            let bp = self.bcache.bread(dev, edge.node[level]);
+            getehdr(&bp).1 < NXEXTENT
+        });
+        if let Some(level) = room {
+            let entry = if level == edge.depth {
+                (ex.start, ex.len)
+            } else {
+                (first, self.ebranch(dev, edge.depth - level - 1, ex, first)?)
+            };
+            let mut bp = self.bcache.bread(dev, edge.node[level]);
+            let (depth, n) = getehdr(&bp);
+            putentry(&mut bp, n, entry);
+            putehdr(&mut bp, depth, n + 1);
+            self.bcache.bwrite(&mut bp);
+            return Ok(());
+        }
+
+        // every block on the edge is full: the old root and a new
+        // branch as deep as it become the children of a new root.
+        if edge.depth == MAXEDEPTH {
+            return Err(FsErr::FileTooBigErr);
+        }
+        let branch = self.ebranch(dev, edge.depth, ex, first)?;
+        let root = match self.enode(dev, edge.depth + 1, (einline(d).1, d.addrs[XADDR])) {
+            Ok(root) => root,
+            Err(e) => {
+                self.eunbranch(dev, branch);
+                return Err(e);
+            }
+        };
+        let mut bp = self.bcache.bread(dev, root);
+        putentry(&mut bp, 1, (first, branch));
+        putehdr(&mut bp, edge.depth + 1, 2);
+        self.bcache.bwrite(&mut bp);
+        d.addrs[XADDR] = root;
+        Ok(())
+    }
+
+    // grow the extents of d until they cover nblocks blocks,
+    // extending the last extent in place when the blocks after
+    // it are free, and otherwise adding a new one.
+    fn egrow(&self, dev: u32, d: &mut InodeData, nblocks: u32) -> Result<(), FsErr> {
+        let mut total = self.eblocks(dev, d);
+        while total < nblocks {
+            let want = nblocks - total;
+            let got = self.eextend(dev, d, want);
+            if got > 0 {
+                total += got;
+                continue;
+            }
+            let (start, len) = self.balloc_run(dev, want)?;
+            if let Err(e) = self.eappend(dev, d, Extent { start, len }, total) {
+                for b in start..start + len {
+                    self.bfree(dev, b);
+                }
+                return Err(e);
+            }
+            total += len;
+        }
+        Ok(())
+    }
+
+    // bmap() for extent-mapped inodes.
+    fn emap(&self, dev: u32, d: &mut InodeData, bn: u32) -> Result<u32, FsErr> {
+        if bn as usize >= MAXFILE {
+            return Err(FsErr::FileTooBigErr);
+        }
+        self.egrow(dev, d, bn + 1)?;
+
+        let mut base = 0;
+        for i in 0..NEXTENT {
+            let (start, len) = (d.addrs[2 * i], d.addrs[2 * i + 1]);
+            if bn < base + len {
+                return Ok(start + (bn - base));
+            }
+            base += len;
+        }
+
+        // down the tree, through the last child of each index
+        // block that starts at or before bn.
+        let mut addr = d.addrs[XADDR];
+        loop {
+            let bp = self.bcache.bread(dev, addr);
+            let (depth, n) = getehdr(&bp);
+            if depth == 0 {
+                for i in 0..n {
+                    let (start, len) = getentry(&bp, i);
+                    if bn < base + len {
+                        return Ok(start + (bn - base));
+                    }
+                    base += len;
+                }
+                panic!("emap");
+            }
+            let i = (1..n).take_while(|&i| getentry(&bp, i).0 <= bn).count();
+            let (first, child) = getentry(&bp, i);
+            base = first;
+            addr = child;
+        }
+    }
+
+    // free every block of an extent-mapped inode.
+    fn etrunc(&self, dev: u32, d: &mut InodeData) {
+        for i in 0..NEXTENT {
+            let (start, len) = (d.addrs[2 * i], d.addrs[2 * i + 1]);
+            for b in start..start + len {
+                self.bfree(dev, b);
+            }
+        }
+        if d.addrs[XADDR] != 0 {
+            self.efree(dev, d.addrs[XADDR]);
+        }
+        d.addrs = [0; NADDRS];
+    }
+
+    // free the extent tree block at addr, the blocks under it,
+    // and the blocks of their extents.
+    fn efree(&self, dev: u32, addr: u32) {
+        let bp = self.bcache.bread(dev, addr);
+        let (depth, n) = getehdr(&bp);
+        for i in 0..n {
+            let (a, b) = getentry(&bp, i);
+            if depth > 0 {
+                self.efree(dev, b);
+            } else {
+                for blk in a..a + b {
+                    self.bfree(dev, blk);
+                }
+            }
+        }
+        self.bcache.brelse(bp);
+        self.bfree(dev, addr);
+    }
+
+    // Read data from inode.
+    // Caller must hold ip.lock, d is what it guards.
+    // If user_dst, then dst is a user virtual address;
+    // otherwise, dst is a kernel address.
+    // returns the number of bytes read, short at the end of
+    // the file.
+    pub fn readi(
+        &self,
+        ip: InodeId,
+        d: &mut InodeData,
+        user_dst: bool,
+        dst: u64,
+        off: u32,
+        n: u32,
+    ) -> Result<u32, FsErr> {
+        let dev = self.itable.inode[ip.0].dev.get();
+        if off > d.size || off.checked_add(n).is_none() {
+            return Ok(0);
+        }
+        let n = n.min(d.size - off);
+
+        let mut tot = 0;
+        while tot < n {
+            let off = off + tot;
+            let addr = match self.bmap(ip, d, off / BSIZE as u32) {
+                Ok(addr) => addr,
+                Err(_) => break,
+            };
+            let bp = self.bcache.bread(dev, addr);
+            let start = off as usize % BSIZE;
+            let m = (n - tot).min((BSIZE - start) as u32);
+            let src = &bp.data[start..start + m as usize];
+            either_copyout(user_dst, dst + tot as u64, src)?;
+            tot += m;
+        }
+        Ok(tot)
+    }
+
+    // Write data to inode.
+    // Caller must hold ip.lock, d is what it guards.
+    // If user_src, then src is a user virtual address;
+    // otherwise, src is a kernel address.
+    // Returns the number of bytes successfully written.
+    // If the return value is less than the requested n,
+    // there was an error of some kind.
+    pub fn writei(
+        &self,
+        ip: InodeId,
+        d: &mut InodeData,
+        user_src: bool,
+        src: u64,
+        off: u32,
+        n: u32,
+    ) -> Result<u32, FsErr> {
+        let dev = self.itable.inode[ip.0].dev.get();
+        let end = off.checked_add(n).ok_or(FsErr::FileTooBigErr)?;
+        if off > d.size {
+            return Err(FsErr::BadOffsetErr);
+        }
+        if end as usize > MAXFILE * BSIZE {
+            return Err(FsErr::FileTooBigErr);
+        }
+        if d.flags & F_EXTENT != 0 && n > 0 {
+            // allocate the whole write at once, so that it can
+            // land in one run.
+            if let Err(e) = self.egrow(dev, d, (end - 1) / BSIZE as u32 + 1) {
+                // keep the blocks it did get, itrunc() frees them.
+                self.iupdate(ip, d);
+                return Err(e);
+            }
+        }
+
+        let mut tot = 0;
+        while tot < n {
+            let off = off + tot;
+            let addr = match self.bmap(ip, d, off / BSIZE as u32) {
+                Ok(addr) => addr,
+                Err(_) => break,
+            };
+            let mut bp = self.bcache.bread(dev, addr);
+            let start = off as usize % BSIZE;
+            let m = (n - tot).min((BSIZE - start) as u32);
+            let dst = &mut bp.data[start..start + m as usize];
+            if either_copyin(dst, user_src, src + tot as u64).is_err() {
+                break;
+            }
+            self.bcache.bwrite(&mut bp);
+            tot += m;
+        }
+
+        if off + tot > d.size {
+            d.size = off + tot;
+        }
+
+        // write the i-node back to disk even if the size didn't change
+        // because the loop above might have called bmap() and added a new
+        // block to d.addrs[].
+        self.iupdate(ip, d);
+
+        Ok(tot)
+    }
+}
+
+// Directories
+
+// the name as it is stored in a Dirent: cut to DIRSIZ bytes and
+// padded with NULs.
+fn dirname(name: &[u8]) -> [u8; DIRSIZ] {
+    let mut n = [0; DIRSIZ];
+    let len = name.len().min(DIRSIZ);
+    n[..len].copy_from_slice(&name[..len]);
+    n
+}
+
+impl State {
+    // the directory entry at off in dp.
+    fn readdirent(&self, dp: InodeId, d: &mut InodeData, off: u32) -> Dirent {
+        let mut de = Dirent::default();
+        let dst = &mut de as *mut Dirent as u64;
+        if self.readi(dp, d, false, dst, off, DIRENTSZ) != Ok(DIRENTSZ) {
+            panic!("dirlookup read");
+        }
+        de
+    }
+
+    // Look for a directory entry in a directory.
+    // If found, return its inode and the byte offset of the entry.
+    // Caller must hold dp.lock, d is what it guards.
+    pub fn dirlookup(
+        &self,
+        dp: InodeId,
+        d: &mut InodeData,
+        name: &[u8],
+    ) -> Option<(InodeId, u32)> {
+        if d.tp != T_DIR {
+            panic!("dirlookup not DIR");
+        }
+
+        let name = dirname(name);
+        for off in (0..d.size).step_by(DIRENTSZ as usize) {
+            let de = self.readdirent(dp, d, off);
+            if de.inum == 0 {
+                continue;
+            }
+            if de.name == name {
+                // entry matches path element
+                let dev = self.itable.inode[dp.0].dev.get();
+                return Some((self.iget(dev, de.inum as u32), off));
+            }
+        }
+        None
+    }
+
+    // Write a new directory entry (name, inum) into the directory dp.
+    // Caller must hold dp.lock, d is what it guards.
+    pub fn dirlink(
+        &self,
+        dp: InodeId,
+        d: &mut InodeData,
+        name: &[u8],
+        inum: u32,
+    ) -> Result<(), FsErr> {
+        // Check that name is not present.
+        if let Some((ip, _)) = self.dirlookup(dp, d, name) {
+            self.iput(ip);
+            return Err(FsErr::ExistsErr);
+        }
+
+        // Look for an empty dirent.
+        let mut off = 0;
+        while off < d.size {
+            if self.readdirent(dp, d, off).inum == 0 {
+                break;
+            }
+            off += DIRENTSZ;
+        }
+
+        let de = Dirent {
+            inum: inum as u16,
+            name: dirname(name),
+        };
+        let src = &de as *const Dirent as u64;
+        if self.writei(dp, d, false, src, off, DIRENTSZ)? != DIRENTSZ {
+            return Err(FsErr::NoBlocksErr);
+        }
+        Ok(())
+    }
+}
+
+// Paths
+
+// Copy the next path element from path into name.
+// Return the element and the rest of the path, with the slashes
+// around the element removed, or None if there is no element.
+//
+// Examples:
+//   skipelem("a/bb/c") = ("a", "bb/c")
+//   skipelem("///a//bb") = ("a", "bb")
+//   skipelem("a") = ("a", "")
+//   skipelem("") = skipelem("////") = None
+//
+// an element longer than DIRSIZ is cut short when it is compared.
+fn skipelem(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    let start = path.iter().position(|&c| c != b'/')?;
+    let path = &path[start..];
+    let len = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+    let (elem, rest) = path.split_at(len);
+    let skip = rest.iter().position(|&c| c != b'/').unwrap_or(rest.len());
+    Some((elem, &rest[skip..]))
+}
+
+// Look up and return the inode for a path name.
+// If parent is set, return the inode for the parent and copy the final
+// path element into name, which must have room for DIRSIZ bytes.
+//
+// only one inode is locked at a time: the directory is unlocked
+// before the next element is locked, so looking up .. (a parent,
+// which in another lookup is locked before its child) or .
+// (the directory itself) can't deadlock.
+fn namex(path: &[u8], parent: bool, name: &mut [u8; DIRSIZ]) -> Result<InodeId, FsErr> {
+    // paths from user space may carry their NUL.
+    let mut path = &path[..path.iter().position(|&c| c == 0).unwrap_or(path.len())];
+
+    let mut ip = if path.first() == Some(&b'/') {
+        os().iget(ROOTDEV as u32, ROOTINO)
+    } else {
+        let p = os().myproc().expect("namex");
+        let cwd = os().procs[p].cwd.get().expect("namex: no cwd");
+        os().idup(cwd)
+    };
+
+    while let Some((elem, rest)) = skipelem(path) {
+        *name = dirname(elem);
+        path = rest;
+
+        let mut d = os().ilock(ip);
+        if d.tp != T_DIR {
+            os().iunlock(ip, d);
+            os().iput(ip);
+            return Err(FsErr::NotDirErr);
+        }
+        if parent && path.is_empty() {
+            // Stop one level early.
+            os().iunlock(ip, d);
+            return Ok(ip);
+        }
+        let next = os().dirlookup(ip, &mut d, elem);
+        os().iunlock(ip, d);
+        os().iput(ip);
+        ip = match next {
+            Some((next, _)) => next,
+            None => return Err(FsErr::NotFoundErr),
+        };
+    }
+
+    if parent {
+        // the path has no final element, as with "/".
+        os().iput(ip);
+        return Err(FsErr::NotFoundErr);
+    }
+    Ok(ip)
+}
+
+pub fn namei(path: &[u8]) -> Result<InodeId, FsErr> {
+    let mut name = [0; DIRSIZ];
+    namex(path, false, &mut name)
+}
+
+pub fn nameiparent(path: &[u8], name: &mut [u8; DIRSIZ]) -> Result<InodeId, FsErr> {
+    namex(path, true, name)
+}
+
+// make an inode of type tp at path, with the given inode flags
+// (F_EXTENT for an extent-mapped file), and link it into its
+// directory. a new directory gets . and .. entries. asking for a
+// file where a file or device already is returns that instead.
+// returns the inode referenced but not locked.
+pub fn create(path: &[u8], tp: u16, flags: u32) -> Result<InodeId, FsErr> {
+    let mut name = [0; DIRSIZ];
+    let dp = nameiparent(path, &mut name)?;
+    let mut dd = os().ilock(dp);
+
+    if let Some((ip, _)) = os().dirlookup(dp, &mut dd, &name) {
+        os().iunlock(dp, dd);
+        os().iput(dp);
+        let d = os().ilock(ip);
+        let file = tp == T_FILE && (d.tp == T_FILE || d.tp == T_DEVICE);
+        os().iunlock(ip, d);
+        if file {
+            return Ok(ip);
+        }
+        os().iput(ip);
+        return Err(FsErr::ExistsErr);
+    }
+
+    let dev = os().itable.inode[dp.0].dev.get();
+    let ip = match os().ialloc(dev, tp, flags) {
+        Ok(ip) => ip,
+        Err(e) => {
+            os().iunlock(dp, dd);
+            os().iput(dp);
+            return Err(e);
+        }
+    };
+    let inum = os().itable.inode[ip.0].inum.get();
+    let mut d = os().ilock(ip);
+    d.nlink = 1;
+    os().iupdate(ip, &d);
+
+    let linked = (|| {
+        if tp == T_DIR {
+            // Create . and .. entries.
+            // No nlink++ for ".": avoid cyclic ref count.
+            os().dirlink(ip, &mut d, b".", inum)?;
+            os().dirlink(ip, &mut d, b"..", os().itable.inode[dp.0].inum.get())?;
+        }
+        os().dirlink(dp, &mut dd, &name, inum)
+    })();
+    if let Err(e) = linked {
+        // de-allocate ip.
+        d.nlink = 0;
+        os().iupdate(ip, &d);
+        os().iunlock(ip, d);
+        os().iput(ip);
+        os().iunlock(dp, dd);
+        os().iput(dp);
+        return Err(e);
+    }
+
+    if tp == T_DIR {
+        // now that success is guaranteed:
+        dd.nlink += 1; // for ".."
+        os().iupdate(dp, &dd);
+    }
+    os().iunlock(ip, d);
+    os().iunlock(dp, dd);
+    os().iput(dp);
+    Ok(ip)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::disk;
+    use super::super::params::NPROC;
+    use super::super::params::ROOTDEV;
+    use super::super::proc::ProcId;
+    use super::super::ramdisk::tests::ramdisk;
+    use super::super::stat::T_FILE;
+    use super::*;
+    use std::panic;
+    use std::sync::{Mutex, MutexGuard};
+
+    // a freshly made file system of nblocks blocks on dev.
+    pub fn mkdisk(dev: u32, nblocks: u32, ninodes: u32) {
+        disk::register(dev, ramdisk(nblocks));
+        mkfs(dev, ninodes);
+    }
+
+    // a fresh file system on ROOTDEV, where absolute paths start.
+    // tests that use it hold the returned guard, so they run one
+    // at a time.
+    pub fn rootdisk() -> MutexGuard<'static, ()> {
+        static ROOT: Mutex<()> = Mutex::new(());
+        let guard = ROOT.lock().unwrap_or_else(|e| e.into_inner());
+        mkdisk(ROOTDEV as u32, 200, 32);
+        guard
+    }
+
+    // a new file called name in directory dp, holding data.
+    pub fn putfile(dp: InodeId, name: &[u8], data: &[u8]) {
+        let dev = os().itable.inode[dp.0].dev.get();
+        let ip = os().ialloc(dev, T_FILE, 0).unwrap();
+        let inum = os().itable.inode[ip.0].inum.get();
+        let mut d = os().ilock(ip);
+        d.nlink = 1;
+        let src = data.as_ptr() as u64;
+        assert_eq!(
+            os().writei(ip, &mut d, false, src, 0, data.len() as u32),
+            Ok(data.len() as u32)
+        );
+        drop(d);
+        os().iput(ip);
+
+        let mut d = os().ilock(dp);
+        os().dirlink(dp, &mut d, name, inum).unwrap();
+    }
+
+    // the first block mkfs leaves free: the data block after the
+    // root directory's.
+    fn firstfree(dev: u32) -> u32 {
+        sb(dev).bmapstart + 2
+    }
+
+    // blocks still free on dev.
+    fn nfree(dev: u32) -> u32 {
+        let sb = sb(dev);
+        (0..sb.size).filter(|&b| !allocated(dev, b)).count() as u32
+    }
+
+    fn allocated(dev: u32, b: u32) -> bool {
+        let bp = os().bcache.bread(dev, bblock(b, &sb(dev)));
+        bp.data[(b % BPB) as usize / 8] & (1 << (b % 8)) != 0
+    }
+
+    #[test]
+    fn iget_shares_entries() {
+        let a = os().iget(ROOTDEV as u32, 900);
+        let b = os().iget(ROOTDEV as u32, 900);
+        let c = os().iget(ROOTDEV as u32 + 1, 900);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(os().itable.inode[a.0].refc.get(), 2);
+
+        assert_eq!(os().idup(c), c);
+        os().iput(c);
+        os().iput(c);
+        os().iput(a);
+        os().iput(b);
+        assert_eq!(os().itable.inode[a.0].refc.get(), 0);
+        assert_eq!(os().itable.inode[c.0].refc.get(), 0);
+    }
+
+    #[test]
+    fn mkfs_lays_out_the_disk() {
+        mkdisk(10, 200, 32);
+        let sb = readsb(10);
+        assert_eq!(sb.magic, FSMAGIC);
+        assert_eq!(sb.size, 200);
+        assert_eq!(sb.inodestart, 2 + LOGSIZE as u32);
+        assert_eq!(sb.bmapstart, sb.inodestart + 32 / IPB + 1);
+        assert_eq!(sb.nblocks, 200 - (sb.bmapstart + 1));
+        for b in 0..firstfree(10) {
+            assert!(allocated(10, b));
+        }
+        assert!(!allocated(10, firstfree(10)));
+
+        let root = os().iget(10, ROOTINO);
+        let mut d = os().ilock(root);
+        assert_eq!((d.tp, d.nlink, d.size), (T_DIR, 1, 2 * DIRENTSZ));
+        assert_eq!(d.addrs[0], sb.bmapstart + 1);
+        for (off, name) in [(0, &b"."[..]), (DIRENTSZ, &b".."[..])] {
+            let de = os().readdirent(root, &mut d, off);
+            assert_eq!((de.inum, de.name), (ROOTINO as u16, dirname(name)));
+        }
+        drop(d);
+        os().iput(root);
+    }
+
+    #[test]
+    fn balloc_hands_out_zeroed_blocks() {
+        mkdisk(11, 100, 16);
+        let first = firstfree(11);
+
+        let mut bp = os().bcache.bread(11, first);
+        bp.data = [0xff; BSIZE];
+        os().bcache.bwrite(&mut bp);
+        drop(bp);
+
+        let b = os().balloc(11).unwrap();
+        assert_eq!(b, first);
+        assert!(allocated(11, b));
+        assert_eq!(os().bcache.bread(11, b).data, [0; BSIZE]);
+
+        os().bfree(11, b);
+        assert!(!allocated(11, b));
+        let r = panic::catch_unwind(|| os().bfree(11, b));
+        assert!(r.is_err());
+
+        let n = (0..).take_while(|_| os().balloc(11).is_ok()).count();
+        assert_eq!(n as u32, sb(11).nblocks - 1);
+        assert_eq!(os().balloc(11), Err(FsErr::NoBlocksErr));
+    }
+
+    #[test]
+    fn ialloc_and_iupdate_reach_the_disk() {
+        mkdisk(12, 100, 2 * IPB);
+        let ip = os().ialloc(12, T_FILE, 0).unwrap();
+        let inum = os().itable.inode[ip.0].inum.get();
+        assert_eq!(inum, ROOTINO + 1);
+
+        let mut d = os().ilock(ip);
+        assert_eq!(d.tp, T_FILE);
+        d.nlink = 1;
+        d.size = 3;
+        d.addrs[0] = os().balloc(12).unwrap();
+        os().iupdate(ip, &d);
+        let addr = d.addrs[0];
+        drop(d);
+
+        let bp = os().bcache.bread(12, iblock(inum, &sb(12)));
+        let dip = getdinode(&bp, inum);
+        drop(bp);
+        assert_eq!(
+            (dip.tp, dip.nlink, dip.size, dip.addrs[0]),
+            (T_FILE, 1, 3, addr)
+        );
+
+        // the last reference to an unlinked inode frees it.
+        let mut d = os().ilock(ip);
+        d.nlink = 0;
+        os().iupdate(ip, &d);
+        drop(d);
+        os().iput(ip);
+        assert!(!allocated(12, addr));
+        let bp = os().bcache.bread(12, iblock(inum, &sb(12)));
+        assert_eq!(getdinode(&bp, inum).tp, 0);
+    }
+
+    #[test]
+    fn ialloc_runs_out() {
+        mkdisk(13, 100, IPB);
+        let ips: Vec<_> = (2..IPB).map(|_| os().ialloc(13, T_FILE, 0).unwrap()).collect();
+        assert_eq!(os().ialloc(13, T_FILE, 0), Err(FsErr::NoInodesErr));
+        for ip in ips {
+            os().iput(ip);
+        }
+    }
+
+    #[test]
+    fn files_grow_into_the_indirect_block() {
+        mkdisk(14, 100, 16);
+        let free = nfree(14);
+        let ip = os().ialloc(14, T_FILE, 0).unwrap();
+        let mut d = os().ilock(ip);
+
+        let n = (NDIRECT + 3) * BSIZE + 7;
+        let data: Vec<u8> = (0..n).map(|i| (i % 251) as u8).collect();
+        let r = os().writei(ip, &mut d, false, data.as_ptr() as u64, 0, n as u32);
+        assert_eq!(r, Ok(n as u32));
+        assert_eq!(d.size, n as u32);
+        assert_ne!(d.addrs[NDIRECT], 0);
+        // NDIRECT + 4 data blocks and the indirect block.
+        assert_eq!(nfree(14), free - (NDIRECT as u32 + 5));
+
+        let mut buf = vec![0u8; n + 100];
+        let r = os().readi(
+            ip,
+            &mut d,
+            false,
+            buf.as_mut_ptr() as u64,
+            0,
+            buf.len() as u32,
+        );
+        assert_eq!(r, Ok(n as u32));
+        assert_eq!(&buf[..n], &data[..]);
+
+        // a read straddling blocks at an odd offset.
+        let off = NDIRECT * BSIZE - 5;
+        let r = os().readi(ip, &mut d, false, buf.as_mut_ptr() as u64, off as u32, 10);
+        assert_eq!(r, Ok(10));
+        assert_eq!(&buf[..10], &data[off..off + 10]);
+        let r = os().readi(ip, &mut d, false, buf.as_mut_ptr() as u64, n as u32 + 1, 10);
+        assert_eq!(r, Ok(0));
+
+        // writes may not leave a hole.
+        let r = os().writei(ip, &mut d, false, data.as_ptr() as u64, n as u32 + 1, 1);
+        assert_eq!(r, Err(FsErr::BadOffsetErr));
+
+        os().itrunc(ip, &mut d);
+        assert_eq!((d.size, d.addrs), (0, [0; NADDRS]));
+        assert_eq!(nfree(14), free);
+        d.nlink = 0;
+        drop(d);
+        os().iput(ip);
+    }
+
+    #[test]
+    fn files_stop_at_maxfile() {
+        mkdisk(15, 100, 16);
+        let ip = os().ialloc(15, T_FILE, 0).unwrap();
+        let mut d = os().ilock(ip);
+
+        let last = os().bmap(ip, &mut d, MAXFILE as u32 - 1).unwrap();
+        assert_ne!(last, 0);
+        assert_eq!(
+            os().bmap(ip, &mut d, MAXFILE as u32),
+            Err(FsErr::FileTooBigErr)
+        );
+
+        // the size can't reach past 4 GiB.
+        let data = [0x77u8; 2];
+        d.size = u32::MAX - 1;
+        let r = os().writei(ip, &mut d, false, data.as_ptr() as u64, u32::MAX - 1, 2);
+        assert_eq!(r, Err(FsErr::FileTooBigErr));
+        let r = os().writei(ip, &mut d, false, data.as_ptr() as u64, u32::MAX - 1, 1);
+        assert_eq!(r, Ok(1));
+        assert_eq!(d.size, u32::MAX);
+
+        os().itrunc(ip, &mut d);
+        d.nlink = 0;
+        drop(d);
+        os().iput(ip);
+    }
+
+    // a file with blocks at both ends of every level of
+    // indirection, with holes in between.
+    #[test]
+    fn files_span_every_indirection_level() {
+        mkdisk(16, 100, 16);
+        let free = nfree(16);
+        let ip = os().ialloc(16, T_FILE, 0).unwrap();
+        let mut d = os().ilock(ip);
+
+        let single = NDIRECT;
+        let double = single + NINDIRECT;
+        let triple = double + NDINDIRECT;
+        let end = u32::MAX as usize / BSIZE - 1; // last whole block
+        let blocks = [
+            0,
+            single - 1,
+            single,
+            double - 1,
+            double,
+            double + NINDIRECT,
+            triple - 1,
+            triple,
+            triple + NDINDIRECT + NINDIRECT + 1,
+            end,
+        ];
+
+        for (k, &bn) in blocks.iter().enumerate() {
+            let off = (bn * BSIZE) as u32;
+            d.size = d.size.max(off);
+            let data = [k as u8 + 1; BSIZE];
+            let r = os().writei(ip, &mut d, false, data.as_ptr() as u64, off, BSIZE as u32);
+            assert_eq!(r, Ok(BSIZE as u32), "block {}", bn);
+        }
+        assert_eq!(d.size as usize, (end + 1) * BSIZE);
+
+        for (k, &bn) in blocks.iter().enumerate() {
+            let mut data = [0u8; BSIZE];
+            let off = (bn * BSIZE) as u32;
+            let r = os().readi(
+                ip,
+                &mut d,
+                false,
+                data.as_mut_ptr() as u64,
+                off,
+                BSIZE as u32,
+            );
+            assert_eq!(
+                (r, data),
+                (Ok(BSIZE as u32), [k as u8 + 1; BSIZE]),
+                "block {}",
+                bn
+            );
+        }
+
+        // the data blocks, one singly-indirect block, the doubly-
+        // indirect block with 3 children (the first, the second
+        // and the last), and the triply-indirect block with three
+        // paths of two blocks each below it.
+        let used = blocks.len() + 1 + (1 + 3) + (1 + 3 * 2);
+        assert_eq!(nfree(16), free - used as u32);
+
+        os().itrunc(ip, &mut d);
+        assert_eq!((d.size, d.addrs), (0, [0; NADDRS]));
+        assert_eq!(nfree(16), free);
+        drop(d);
+        os().iput(ip);
+    }
+
+    // every extent of d, in file order.
+    fn extents(dev: u32, d: &InodeData) -> Vec<Extent> {
+        fn walk(dev: u32, addr: u32, ex: &mut Vec<Extent>) {
+            let bp = os().bcache.bread(dev, addr);
+            let (depth, n) = getehdr(&bp);
+            for i in 0..n {
+                let (a, b) = getentry(&bp, i);
+                if depth > 0 {
+                    walk(dev, b, ex);
+                } else {
+                    ex.push(Extent { start: a, len: b });
+                }
+            }
+        }
+        let mut ex: Vec<Extent> = (0..NEXTENT)
+            .map(|i| Extent {
+                start: d.addrs[2 * i],
+                len: d.addrs[2 * i + 1],
+            })
+            .take_while(|e| e.len > 0)
+            .collect();
+        if d.addrs[XADDR] != 0 {
+            walk(dev, d.addrs[XADDR], &mut ex);
+        }
+        ex
+    }
+
+    // an empty file of type T_FILE on dev, mapped by extents if
+    // extent is set.
+    fn mkfile(dev: u32, extent: bool) -> InodeId {
+        let flags = if extent { F_EXTENT } else { 0 };
+        os().ialloc(dev, T_FILE, flags).unwrap()
+    }
+
+    #[test]
+    fn ialloc_picks_the_block_map() {
+        mkdisk(23, 100, 16);
+        let src = b"x".as_ptr() as u64;
+        for &extent in [false, true].iter() {
+            let ip = mkfile(23, extent);
+            let mut d = os().ilock(ip);
+            assert_eq!(os().writei(ip, &mut d, false, src, 0, 1), Ok(1));
+
+            // the flag is on disk.
+            drop(d);
+            unsafe { os().itable.inode[ip.0].lock.get_mut_unchecked().valid = false };
+            let mut d = os().ilock(ip);
+            if extent {
+                assert_eq!(d.flags, F_EXTENT);
+                assert_eq!(extents(23, &d)[0].len, 1);
+            } else {
+                assert_eq!(d.flags, 0);
+                assert_ne!(d.addrs[0], 0);
+            }
+            os().itrunc(ip, &mut d);
+            assert_eq!(d.flags, if extent { F_EXTENT } else { 0 });
+            drop(d);
+            os().iput(ip);
+        }
+    }
+
+    #[test]
+    fn balloc_run_prefers_long_runs() {
+        mkdisk(17, 100, 16);
+        let first = firstfree(17);
+        // free space: a hole of 1, a hole of 3, then the rest.
+        let free = nfree(17);
+        assert_eq!(os().balloc_run(17, 0), Ok((0, 0)));
+        assert_eq!(nfree(17), free);
+        let a = os().balloc_run(17, 6).unwrap();
+        assert_eq!(a, (first, 6));
+        os().bfree(17, first + 1);
+        for b in first + 3..first + 6 {
+            os().bfree(17, b);
+        }
+
+        assert_eq!(os().balloc_run(17, 3), Ok((first + 3, 3)));
+        assert_eq!(os().balloc_run(17, 2), Ok((first + 6, 2)));
+        assert_eq!(os().balloc_run(17, 1), Ok((first + 1, 1)));
+        // only a short run is left once the disk fills up.
+        let rest = sb(17).size - (first + 8);
+        assert_eq!(os().balloc_run(17, rest + 5), Ok((first + 8, rest)));
+        assert_eq!(os().balloc_run(17, 1), Err(FsErr::NoBlocksErr));
+        assert_eq!(os().brun(17, first, 4), 0);
+    }
+
+    #[test]
+    fn sequential_files_are_one_extent() {
+        mkdisk(18, 200, 16);
+        let free = nfree(18);
+        let ip = mkfile(18, true);
+        let mut d = os().ilock(ip);
+
+        let n = 40 * BSIZE + 10;
+        let data: Vec<u8> = (0..n).map(|i| (i % 253) as u8).collect();
+        // in pieces, so the extent has to grow in place.
+        for off in (0..n).step_by(3000) {
+            let m = 3000.min(n - off);
+            let src = data[off..].as_ptr() as u64;
+            let r = os().writei(ip, &mut d, false, src, off as u32, m as u32);
+            assert_eq!(r, Ok(m as u32));
+        }
+        let ex = extents(18, &d);
+        assert_eq!(ex.len(), 1);
+        assert_eq!(ex[0].len, 41);
+        assert_eq!(d.addrs[XADDR], 0);
+        assert_eq!(nfree(18), free - 41);
+
+        let mut buf = vec![0u8; n];
+        let r = os().readi(ip, &mut d, false, buf.as_mut_ptr() as u64, 0, n as u32);
+        assert_eq!(r, Ok(n as u32));
+        assert_eq!(buf, data);
+
+        os().itrunc(ip, &mut d);
+        assert_eq!(nfree(18), free);
+        assert_eq!(d.flags, F_EXTENT);
+        drop(d);
+        os().iput(ip);
+    }
+
+    // fill dev, then free n blocks spaced apart, so no free run is
+    // longer than one block. returns the runs it took and the holes
+    // it left, for unfragment().
+    fn fragment(dev: u32, n: usize) -> (Vec<(u32, u32)>, Vec<u32>) {
+        let mut runs = Vec::new();
+        while let Ok(run) = os().balloc_run(dev, u32::MAX) {
+            runs.push(run);
+        }
+        let first = runs[0].0;
+        let holes: Vec<u32> = (0..n as u32).map(|i| first + 2 * i).collect();
+        for &b in &holes {
+            os().bfree(dev, b);
+        }
+        (runs, holes)
+    }
+
+    // free the blocks fragment() kept.
+    fn unfragment(dev: u32, (runs, holes): (Vec<(u32, u32)>, Vec<u32>)) {
+        for (start, len) in runs {
+            for b in (start..start + len).filter(|b| !holes.contains(b)) {
+                os().bfree(dev, b);
+            }
+        }
+    }
+
+    // write an n block file on a disk fragmented so that each block
+    // is an extent of its own, with room for the tree blocks it
+    // needs; check it reads back, then remove it. returns the depth
+    // of the tree's root.
+    fn fragmented_file(dev: u32, n: usize, nodes: usize) -> usize {
+        let free = nfree(dev);
+        let holes = fragment(dev, n + nodes);
+
+        let ip = mkfile(dev, true);
+        let mut d = os().ilock(ip);
+        let data: Vec<u8> = (0..n * BSIZE).map(|i| (i / BSIZE) as u8).collect();
+        let r = os().writei(
+            ip,
+            &mut d,
+            false,
+            data.as_ptr() as u64,
+            0,
+            data.len() as u32,
+        );
+        assert_eq!(r, Ok(data.len() as u32));
+        assert_ne!(d.addrs[XADDR], 0);
+        let ex = extents(dev, &d);
+        assert_eq!(ex.len(), n);
+        assert!(ex.iter().all(|e| e.len == 1));
+        // every hole is in use, by the data or the tree.
+        assert_eq!(nfree(dev), 0);
+        let depth = getehdr(&os().bcache.bread(dev, d.addrs[XADDR])).0;
+
+        // an inode read back from disk sees the same extents.
+        drop(d);
+        unsafe { os().itable.inode[ip.0].lock.get_mut_unchecked().valid = false };
+        let mut d = os().ilock(ip);
+        assert_eq!(d.flags, F_EXTENT);
+        let mut buf = vec![0u8; data.len()];
+        let r = os().readi(
+            ip,
+            &mut d,
+            false,
+            buf.as_mut_ptr() as u64,
+            0,
+            buf.len() as u32,
+        );
+        assert_eq!(r, Ok(data.len() as u32));
+        assert_eq!(buf, data);
+
+        os().itrunc(ip, &mut d);
+        drop(d);
+        os().iput(ip);
+        unfragment(dev, holes);
+        assert_eq!(nfree(dev), free);
+        depth
+    }
+
+    #[test]
+    fn fragmented_files_spill_into_the_tree() {
+        mkdisk(19, 300, 16);
+        // one leaf holds the extents past the inode's.
+        assert_eq!(fragmented_file(19, NEXTENT + 20, 1), 0);
+    }
+
+    #[test]
+    fn extent_tree_grows_a_new_root() {
+        mkdisk(24, 700, 16);
+        // a full leaf, its new sibling, and the root above both.
+        assert_eq!(fragmented_file(24, NEXTENT + NXEXTENT + 20, 3), 1);
+    }
+
+    // the most extents a file can have.
+    const MAXEXTENT: usize = NEXTENT + NXEXTENT.pow(MAXEDEPTH as u32 + 1);
+
+    #[test]
+    fn writes_past_maxextent_fail() {
+        mkdisk(25, 300, 16);
+        let free = nfree(25);
+        let ip = mkfile(25, true);
+        let mut d = os().ilock(ip);
+
+        // writing MAXEXTENT single-block extents one by one takes
+        // too long here, so build the full tree directly. every
+        // extent maps block b; b + 1 is in use, so the last one
+        // can't grow in place.
+        let (b, _) = os().balloc_run(25, 2).unwrap();
+        for i in 0..NEXTENT {
+            d.addrs[2 * i] = b;
+            d.addrs[2 * i + 1] = 1;
+        }
+        let root = os().balloc(25).unwrap();
+        let mut leaves = Vec::new();
+        for j in 0..NXEXTENT {
+            let leaf = os().balloc(25).unwrap();
+            let mut bp = os().bcache.bread(25, leaf);
+            putehdr(&mut bp, 0, NXEXTENT);
+            for i in 0..NXEXTENT {
+                putentry(&mut bp, i, (b, 1));
+            }
+            os().bcache.bwrite(&mut bp);
+            drop(bp);
+            let mut bp = os().bcache.bread(25, root);
+            putentry(&mut bp, j, ((NEXTENT + j * NXEXTENT) as u32, leaf));
+            os().bcache.bwrite(&mut bp);
+            leaves.push(leaf);
+        }
+        let mut bp = os().bcache.bread(25, root);
+        putehdr(&mut bp, 1, NXEXTENT);
+        os().bcache.bwrite(&mut bp);
+        drop(bp);
+        d.addrs[XADDR] = root;
+        let size = (MAXEXTENT * BSIZE) as u32;
+        d.size = size;
+        assert_eq!(extents(25, &d).len(), MAXEXTENT);
+
+        // the last block is there, but there is no room for another.
+        let left = nfree(25);
+        let mut buf = [0u8; BSIZE];
+        let dst = buf.as_mut_ptr() as u64;
+        let r = os().readi(ip, &mut d, false, dst, size - BSIZE as u32, BSIZE as u32);
+        assert_eq!(r, Ok(BSIZE as u32));
+        let r = os().writei(ip, &mut d, false, dst, size, 1);
+        assert_eq!(r, Err(FsErr::FileTooBigErr));
+        assert_eq!(d.size, size);
+        assert_eq!(extents(25, &d).len(), MAXEXTENT);
+        assert_eq!(nfree(25), left);
+
+        // the blocks are shared, so itrunc() can't free them.
+        for blk in leaves.into_iter().chain([root, b, b + 1]) {
+            os().bfree(25, blk);
+        }
+        d.addrs = [0; NADDRS];
+        d.size = 0;
+        drop(d);
+        os().iput(ip);
+        assert_eq!(nfree(25), free);
+    }
+
+    #[test]
+    fn extent_writes_report_a_full_disk() {
+        mkdisk(26, 100, 16);
+        let ip = mkfile(26, true);
+        let mut d = os().ilock(ip);
+        let mut runs = Vec::new();
+        while let Ok(run) = os().balloc_run(26, u32::MAX) {
+            runs.push(run);
+        }
+
+        let src = b"x".as_ptr() as u64;
+        assert_eq!(os().writei(ip, &mut d, false, src, 0, 1), Err(FsErr::NoBlocksErr));
+        assert_eq!(d.size, 0);
+
+        for (start, len) in runs {
+            for b in start..start + len {
+                os().bfree(26, b);
+            }
+        }
+        assert_eq!(os().writei(ip, &mut d, false, src, 0, 1), Ok(1));
+        os().itrunc(ip, &mut d);
+        drop(d);
+        os().iput(ip);
+    }
+
+    // cargo test --release -- --ignored --nocapture bench_
+    #[test]
+    #[ignore]
+    fn bench_extent_vs_indirect_reads() {
+        use std::time::Instant;
+
+        const SIZE: usize = 4 << 20;
+        const CHUNK: usize = 64 << 10;
+        const ROUNDS: usize = 20;
+        let data = vec![0x5au8; SIZE];
+        let mut buf = vec![0u8; CHUNK];
+
+        for (dev, extent) in [(20, false), (21, true)] {
+            mkdisk(dev, (SIZE / BSIZE) as u32 + 200, 16);
+            let free = nfree(dev);
+            let ip = mkfile(dev, extent);
+            let mut d = os().ilock(ip);
+
+            let t = Instant::now();
+            let r = os().writei(ip, &mut d, false, data.as_ptr() as u64, 0, SIZE as u32);
+            assert_eq!(r, Ok(SIZE as u32));
+            let write = t.elapsed();
+            let meta = free - nfree(dev) - (SIZE / BSIZE) as u32;
+
+            let t = Instant::now();
+            for _ in 0..ROUNDS {
+                for off in (0..SIZE).step_by(CHUNK) {
+                    let dst = buf.as_mut_ptr() as u64;
+                    let r = os().readi(ip, &mut d, false, dst, off as u32, CHUNK as u32);
+                    assert_eq!(r, Ok(CHUNK as u32));
+                }
+            }
+            let read = t.elapsed();
+
+            println!(
+                "{:>8}: write {:?}, read {:.1} MiB/s, {} metadata blocks",
+                if extent { "extent" } else { "indirect" },
+                write,
+                (ROUNDS * SIZE) as f64 / (1 << 20) as f64 / read.as_secs_f64(),
+                meta
+            );
+
+            os().itrunc(ip, &mut d);
+            drop(d);
+            os().iput(ip);
+        }
+    }
+
+    #[test]
+    fn skipelem_splits_paths() {
+        assert_eq!(skipelem(b"a/bb/c"), Some((&b"a"[..], &b"bb/c"[..])));
+        assert_eq!(skipelem(b"///a//bb"), Some((&b"a"[..], &b"bb"[..])));
+        assert_eq!(skipelem(b"a"), Some((&b"a"[..], &b""[..])));
+        assert_eq!(skipelem(b"a//"), Some((&b"a"[..], &b""[..])));
+        assert_eq!(skipelem(b""), None);
+        assert_eq!(skipelem(b"////"), None);
+    }
+
+    // make directory name in dp, like mkdir.
+    fn mkdir(dp: InodeId, name: &[u8]) -> InodeId {
+        let dev = os().itable.inode[dp.0].dev.get();
+        let ip = os().ialloc(dev, T_DIR, 0).unwrap();
+        let inum = os().itable.inode[ip.0].inum.get();
+        let parent = os().itable.inode[dp.0].inum.get();
+
+        let mut d = os().ilock(ip);
+        d.nlink = 1;
+        os().iupdate(ip, &d);
+        os().dirlink(ip, &mut d, b".", inum).unwrap();
+        os().dirlink(ip, &mut d, b"..", parent).unwrap();
+        drop(d);
+
+        let mut d = os().ilock(dp);
+        os().dirlink(dp, &mut d, name, inum).unwrap();
+        d.nlink += 1; // for ".."
+        os().iupdate(dp, &d);
+        ip
+    }
+
+    #[test]
+    fn dirlink_and_dirlookup() {
+        mkdisk(22, 100, 16);
+        let root = os().iget(22, ROOTINO);
+        let a = mkdir(root, b"a");
+        let ainum = os().itable.inode[a.0].inum.get();
+
+        let mut d = os().ilock(root);
+        let (ip, off) = os().dirlookup(root, &mut d, b"a").unwrap();
+        assert_eq!((ip, off), (a, 2 * DIRENTSZ));
+        os().iput(ip);
+        assert_eq!(os().dirlookup(root, &mut d, b"b"), None);
+        assert_eq!(
+            os().dirlink(root, &mut d, b"a", ainum),
+            Err(FsErr::ExistsErr)
+        );
+
+        // long names are cut to DIRSIZ, and freed entries reused.
+        let long = b"abcdefghijklmnopqrstuvwxyz";
+        os().dirlink(root, &mut d, long, ainum).unwrap();
+        let (ip, off) = os().dirlookup(root, &mut d, &long[..DIRSIZ]).unwrap();
+        os().iput(ip);
+        let free = Dirent::default();
+        let src = &free as *const Dirent as u64;
+        os().writei(root, &mut d, false, src, off, DIRENTSZ)
+            .unwrap();
+        assert_eq!(os().dirlookup(root, &mut d, long), None);
+        let size = d.size;
+        os().dirlink(root, &mut d, b"c", ainum).unwrap();
+        assert_eq!(d.size, size);
+        assert_eq!(
+            os().dirlookup(root, &mut d, b"c").map(|(ip, off)| {
+                os().iput(ip);
+                off
+            }),
+            Some(off)
+        );
+        drop(d);
+
+        os().iput(a);
+        os().iput(root);
+    }
+
+    // the inode number behind ip, dropping the reference.
+    fn inum(ip: Result<InodeId, FsErr>) -> Result<u32, FsErr> {
+        ip.map(|ip| {
+            let inum = os().itable.inode[ip.0].inum.get();
+            os().iput(ip);
+            inum
+        })
+    }
+
+    #[test]
+    fn namei_walks_paths() {
+        let _root = rootdisk();
+        let root = os().iget(ROOTDEV as u32, ROOTINO);
+        let a = mkdir(root, b"a");
+        let b = mkdir(a, b"b");
+        let f = os().ialloc(ROOTDEV as u32, T_FILE, 0).unwrap();
+        let finum = os().itable.inode[f.0].inum.get();
+        let mut d = os().ilock(b);
+        os().dirlink(b, &mut d, b"f", finum).unwrap();
+        drop(d);
+        let (ainum, binum) = (os().itable.inode[a.0].inum.get(), os().itable.inode[b.0].inum.get());
+
+        assert_eq!(inum(namei(b"/")), Ok(ROOTINO));
+        assert_eq!(inum(namei(b"/a/b/f")), Ok(finum));
+        assert_eq!(inum(namei(b"//a//b/../b/./f\0junk")), Ok(finum));
+        assert_eq!(inum(namei(b"/..")), Ok(ROOTINO));
+        assert_eq!(inum(namei(b"/a/b/..")), Ok(ainum));
+        assert_eq!(inum(namei(b"/a/x")), Err(FsErr::NotFoundErr));
+        assert_eq!(inum(namei(b"/a/b/f/g")), Err(FsErr::NotDirErr));
+
+        let mut name = [0; DIRSIZ];
+        assert_eq!(inum(nameiparent(b"/a/b/f", &mut name)), Ok(binum));
+        assert_eq!(name, dirname(b"f"));
+        assert_eq!(inum(nameiparent(b"/a/new/", &mut name)), Ok(ainum));
+        assert_eq!(name, dirname(b"new"));
+        assert_eq!(inum(nameiparent(b"/", &mut name)), Err(FsErr::NotFoundErr));
+
+        // relative paths start at the current directory.
+        let id = ProcId(NPROC - 6);
+        os().procs[id].cwd.set(Some(os().idup(a)));
+        os().mycpu().proc.set(Some(id));
+        assert_eq!(inum(namei(b"b/f")), Ok(finum));
+        assert_eq!(inum(namei(b".")), Ok(ainum));
+        assert_eq!(inum(namei(b"../a/b")), Ok(binum));
+        assert_eq!(inum(nameiparent(b"f", &mut name)), Ok(ainum));
+        os().mycpu().proc.set(None);
+        os().iput(os().procs[id].cwd.take().unwrap());
+
+        for ip in [f, b, a, root] {
+            os().iput(ip);
+        }
+    }
+
+    #[test]
+    fn create_links_new_inodes() {
+        let _root = rootdisk();
+        let dir = create(b"/a", T_DIR, 0).unwrap();
+        let f = create(b"/a/f", T_FILE, F_EXTENT).unwrap();
+        let finum = os().itable.inode[f.0].inum.get();
+
+        assert_eq!(inum(namei(b"/a/f")), Ok(finum));
+        assert_eq!(inum(namei(b"/a/..")), Ok(ROOTINO));
+        let d = os().ilock(f);
+        assert_eq!((d.tp, d.nlink, d.flags), (T_FILE, 1, F_EXTENT));
+        os().iunlock(f, d);
+        let root = os().iget(ROOTDEV as u32, ROOTINO);
+        let d = os().ilock(root);
+        assert_eq!(d.nlink, 2);
+        os().iunlock(root, d);
+        os().iput(root);
+
+        // asking for a file where one is returns it, anything
+        // else already there is an error.
+        assert_eq!(inum(create(b"/a/f", T_FILE, 0)), Ok(finum));
+        assert_eq!(inum(create(b"/a/f", T_DIR, 0)), Err(FsErr::ExistsErr));
+        assert_eq!(inum(create(b"/a", T_FILE, 0)), Err(FsErr::ExistsErr));
+        assert_eq!(inum(create(b"/x/f", T_FILE, 0)), Err(FsErr::NotFoundErr));
+        assert_eq!(inum(create(b"/a/f/g", T_FILE, 0)), Err(FsErr::NotDirErr));
+
+        os().iput(f);
+        os().iput(dir);
+    }
+}
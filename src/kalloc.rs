@@ -0,0 +1,304 @@
+// Physical memory allocator, for user processes,
+// kernel stacks, page-table pages,
+// and pipe buffers. Allocates whole 4096-byte pages.
+//
+// the free pages are threaded into a list through their first word.
+// a bitmap remembers which pages are free, so freeing a page twice
+// (or freeing one that was never handed out) is caught right away
+// instead of corrupting the list.
+
+use super::memlayout::{KERNBASE, PHYSTOP};
+use super::riscv::PG;
+use super::spinlock::SpinLock;
+use core::ptr;
+
+// the most pages one allocator can manage: all of RAM.
+const NPAGE: usize = ((PHYSTOP - KERNBASE) / PG::SIZE) as usize;
+
+// junk written into pages in debug builds, to catch dangling refs.
+const JUNK_FREE: u8 = 1;
+const JUNK_ALLOC: u8 = 5;
+
+struct Run {
+    next: *mut Run,
+}
+
+pub struct Kmem {
+    freelist: *mut Run,
+    base: u64,               // first page managed
+    npage: usize,            // number of pages managed
+    nfree: usize,            // pages on the free list
+    free: [u64; NPAGE / 64], // bit set if the page is free
+}
+
+// the free list lives in the pages themselves, which only the
+// holder of the lock touches.
+unsafe impl Send for Kmem {}
+
+pub static KMEM: SpinLock<Kmem> = SpinLock::new(Kmem::new(), "kmem");
+
+// a snapshot of the allocator's page counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KmemStats {
+    pub nfree: usize, // pages ready to be handed out
+    pub nused: usize, // pages handed out and not freed yet
+}
+
+#[cfg(target_os = "none")]
+extern "C" {
+    // first address after kernel.
+    // defined by kernel.ld.
+    static end: u8;
+}
+
+// hand all the RAM after the kernel to the allocator.
+#[cfg(target_os = "none")]
+pub fn kinit() {
+    let start = unsafe { &end as *const u8 as u64 };
+    unsafe { KMEM.lock().freerange(start, PHYSTOP) };
+}
+
+// Allocate one 4096-byte page of physical memory.
+// Returns a pointer that the kernel can use.
+// Returns None if the memory cannot be allocated.
+pub fn kalloc() -> Option<*mut u8> {
+    KMEM.lock().kalloc()
+}
+
+// Free the page of physical memory pointed at by pa,
+// which normally should have been returned by a
+// call to kalloc().  (The exception is when
+// initializing the allocator; see kinit above.)
+pub fn kfree(pa: *mut u8) {
+    KMEM.lock().kfree(pa)
+}
+
+// how many pages are free and in use right now.
+pub fn stats() -> KmemStats {
+    let kmem = KMEM.lock();
+    KmemStats {
+        nfree: kmem.nfree(),
+        nused: kmem.nused(),
+    }
+}
+
+impl Kmem {
+    pub const fn new() -> Kmem {
+        Kmem {
+            freelist: ptr::null_mut(),
+            base: 0,
+            npage: 0,
+            nfree: 0,
+            free: [0; NPAGE / 64],
+        }
+    }
+
+    // manage the whole pages in [pa_start, pa_end).
+    // the memory must not be used by anything else from now on.
+    pub unsafe fn freerange(&mut self, pa_start: u64, pa_end: u64) {
+        let start = PG::roundup(pa_start);
+        let npage = (PG::rounddown(pa_end).saturating_sub(start) / PG::SIZE) as usize;
+        if npage > NPAGE {
+            panic!("freerange: {} pages is more than RAM", npage);
+        }
+        self.base = start;
+        self.npage = npage;
+        for i in 0..npage {
+            self.kfree((start + i as u64 * PG::SIZE) as *mut u8);
+        }
+    }
+
+    pub fn kalloc(&mut self) -> Option<*mut u8> {
+        let r = self.freelist;
+        if r.is_null() {
+            return None;
+        }
+        unsafe {
+            self.freelist = (*r).next;
+        }
+        let pa = r as *mut u8;
+        self.mark(pa, false);
+        self.nfree -= 1;
+
+        if cfg!(debug_assertions) {
+            unsafe { ptr::write_bytes(pa, JUNK_ALLOC, PG::SIZE as usize) };
+        }
+        Some(pa)
+    }
+
+    pub fn kfree(&mut self, pa: *mut u8) {
+        let addr = pa as u64;
+        if addr & (PG::SIZE - 1) != 0
+            || addr < self.base
+            || addr >= self.base + self.npage as u64 * PG::SIZE
+        {
+            panic!("kfree {:#x}", addr);
+        }
+        if self.is_free(pa) {
+            panic!("kfree: double free {:#x}", addr);
+        }
+
+        if cfg!(debug_assertions) {
+            // Fill with junk to catch dangling refs.
+            unsafe { ptr::write_bytes(pa, JUNK_FREE, PG::SIZE as usize) };
+        }
+
+        let r = pa as *mut Run;
+        unsafe {
+            (*r).next = self.freelist;
+        }
+        self.freelist = r;
+        self.mark(pa, true);
+        self.nfree += 1;
+    }
+
+    // pages ready to be handed out.
+    pub fn nfree(&self) -> usize {
+        self.nfree
+    }
+
+    // pages handed out and not freed yet.
+    pub fn nused(&self) -> usize {
+        self.npage - self.nfree
+    }
+
+    fn is_free(&self, pa: *mut u8) -> bool {
+        let i = self.index(pa);
+        self.free[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    fn mark(&mut self, pa: *mut u8, free: bool) {
+        let i = self.index(pa);
+        if free {
+            self.free[i / 64] |= 1 << (i % 64);
+        } else {
+            self.free[i / 64] &= !(1 << (i % 64));
+        }
+    }
+
+    fn index(&self, pa: *mut u8) -> usize {
+        ((pa as u64 - self.base) / PG::SIZE) as usize
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::alloc::{alloc, dealloc, Layout};
+    use std::panic;
+    use std::sync::{Mutex, MutexGuard, Once};
+
+    // page aligned host memory standing in for physical RAM.
+    pub struct Ram {
+        pub start: u64,
+        pub end: u64,
+        layout: Layout,
+    }
+
+    impl Ram {
+        pub fn new(npage: usize) -> Ram {
+            let layout = Layout::from_size_align(npage * PG::SIZE as usize, PG::SIZE as usize)
+                .unwrap();
+            let start = unsafe { alloc(layout) } as u64;
+            assert_ne!(start, 0);
+            Ram {
+                start,
+                end: start + layout.size() as u64,
+                layout,
+            }
+        }
+
+        // a fresh allocator over this memory.
+        pub fn kmem(&self) -> Box<Kmem> {
+            let mut kmem = Box::new(Kmem::new());
+            unsafe { kmem.freerange(self.start, self.end) };
+            kmem
+        }
+    }
+
+    // back the global allocator with host memory, once per test run.
+    // tests that use the global allocator hold the returned guard, so
+    // they run one at a time and can count pages.
+    pub fn kinit() -> MutexGuard<'static, ()> {
+        static INIT: Once = Once::new();
+        static SERIAL: Mutex<()> = Mutex::new(());
+        INIT.call_once(|| {
+            let ram = Box::leak(Box::new(Ram::new(1024)));
+            unsafe { KMEM.lock().freerange(ram.start, ram.end) };
+        });
+        SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    impl Drop for Ram {
+        fn drop(&mut self) {
+            unsafe { dealloc(self.start as *mut u8, self.layout) };
+        }
+    }
+
+    #[test]
+    fn hands_out_every_page_once() {
+        let ram = Ram::new(8);
+        let mut kmem = ram.kmem();
+        assert_eq!((kmem.nfree(), kmem.nused()), (8, 0));
+
+        let mut pages: Vec<u64> = (0..8).map(|_| kmem.kalloc().unwrap() as u64).collect();
+        assert!(kmem.kalloc().is_none());
+        assert_eq!((kmem.nfree(), kmem.nused()), (0, 8));
+
+        pages.sort_unstable();
+        pages.dedup();
+        assert_eq!(pages.len(), 8);
+        for pa in pages.iter() {
+            assert_eq!(pa % PG::SIZE, 0);
+            assert!(*pa >= ram.start && *pa < ram.end);
+        }
+
+        for pa in pages {
+            kmem.kfree(pa as *mut u8);
+        }
+        assert_eq!((kmem.nfree(), kmem.nused()), (8, 0));
+    }
+
+    #[test]
+    fn range_is_rounded_to_whole_pages() {
+        let ram = Ram::new(4);
+        let mut kmem = Box::new(Kmem::new());
+        unsafe { kmem.freerange(ram.start + 1, ram.end - 1) };
+        assert_eq!(kmem.nfree(), 2);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn junk_fills_pages() {
+        let ram = Ram::new(1);
+        let mut kmem = ram.kmem();
+        let pa = kmem.kalloc().unwrap();
+        let page = unsafe { std::slice::from_raw_parts(pa, PG::SIZE as usize) };
+        assert!(page.iter().all(|b| *b == JUNK_ALLOC));
+        kmem.kfree(pa);
+        // the first word holds the free list link.
+        assert!(page[8..].iter().all(|b| *b == JUNK_FREE));
+    }
+
+    #[test]
+    fn catches_double_free() {
+        let ram = Ram::new(2);
+        let mut kmem = ram.kmem();
+        let pa = kmem.kalloc().unwrap();
+        kmem.kfree(pa);
+        let r = panic::catch_unwind(panic::AssertUnwindSafe(|| kmem.kfree(pa)));
+        assert!(r.is_err());
+        assert_eq!(kmem.nfree(), 2);
+    }
+
+    #[test]
+    fn rejects_foreign_pages() {
+        let ram = Ram::new(2);
+        let mut kmem = ram.kmem();
+        let pa = kmem.kalloc().unwrap();
+        let r = panic::catch_unwind(panic::AssertUnwindSafe(|| unsafe { kmem.kfree(pa.add(8)) }));
+        assert!(r.is_err());
+        let r = panic::catch_unwind(panic::AssertUnwindSafe(|| kmem.kfree(ram.end as *mut u8)));
+        assert!(r.is_err());
+    }
+}
@@ -1,20 +1,94 @@
-#![feature(asm)]
+// the kernel is freestanding; the hosted build (see README) keeps std
+// so the kernel core can be driven from cargo test.
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
+// the hosted binary is an empty main(); the kernel core in it is
+// only reached from cargo test. in the tests, what only the boot path
+// uses is marked cfg_attr(not(target_os = "none"), allow(dead_code)).
+#![cfg_attr(all(not(target_os = "none"), not(test)), allow(dead_code))]
+
+#[macro_use]
+mod printf;
 
 mod riscv;
 mod proc;
 mod params;
 mod spinlock;
+mod elf;
+mod exec;
 mod file;
 mod fs;
 mod pipe;
+mod kalloc;
 mod memlayout;
 mod string;
 mod vm;
 mod buf;
 mod sleeplock;
+mod stat;
 mod bio;
+mod disk;
+#[cfg(not(target_os = "none"))]
+mod filedisk;
+mod ramdisk;
 mod switch;
 mod state;
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+mod uart;
+mod plic;
+mod trap;
+mod syscall;
+mod sysfile;
+mod sysproc;
+#[cfg(target_os = "none")]
+mod start;
+
+#[cfg(target_os = "none")]
+core::arch::global_asm!(include_str!("asm/entry.S"), options(raw));
+#[cfg(target_os = "none")]
+core::arch::global_asm!(include_str!("asm/kernelvec.S"), options(raw));
+#[cfg(target_os = "none")]
+core::arch::global_asm!(include_str!("asm/trampoline.S"), options(raw));
+
+#[cfg(target_os = "none")]
+use core::sync::atomic::{AtomicBool, Ordering};
 
-fn main() {
+#[cfg(target_os = "none")]
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+// start() jumps here in supervisor mode on all cpus.
+#[cfg(target_os = "none")]
+fn main() -> ! {
+    let id = state::os().cpuid();
+    if id.0 == 0 {
+        uart::init();
+        println!();
+        println!("RotonOS kernel is booting");
+        println!();
+        kalloc::kinit(); // physical page allocator
+        vm::kvminit(); // create kernel page table
+        vm::kvminithart(); // turn on paging
+        proc::procinit(); // process table
+        trap::trapinithart(); // install kernel trap vector
+        plic::init(); // set up interrupt controller
+        plic::inithart(); // ask PLIC for device interrupts
+        ramdisk::init(); // root disk
+        proc::userinit(); // first user process
+        let mem = kalloc::stats();
+        println!("{} pages free, {} in use", mem.nfree, mem.nused);
+        STARTED.store(true, Ordering::SeqCst);
+    } else {
+        while !STARTED.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+        println!("hart {} starting", id.0);
+        vm::kvminithart(); // turn on paging
+        trap::trapinithart(); // install kernel trap vector
+        plic::inithart(); // ask PLIC for device interrupts
+    }
+
+    proc::scheduler()
 }
+
+#[cfg(not(target_os = "none"))]
+fn main() {}
@@ -1,3 +1,5 @@
+#![allow(non_snake_case)]
+
 // physical memory layout.
 // based on qemu's hw/riscv/virt.c:
 //
@@ -34,13 +36,15 @@ pub mod UART {
 
 // virtio mmio interface
 pub mod UVIRTIO {
+    #[cfg_attr(not(target_os = "none"), allow(dead_code))]
     pub const UVIRTIO0: u64 = 0x10001000;
     pub const UVIRTIO0_IRQ: u32 = 1;
 }
 
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
 pub mod CLINT {
     // local interrupt controller, which contains the timer.
-    pub const CLINT: u64 = 0x20000000;
+    pub const CLINT: u64 = 0x2000000;
 
     #[inline]
     pub fn clint_mtimecmp(hardid: u64) -> u64 {
@@ -50,8 +54,12 @@ pub mod CLINT {
 }
 
 // qemu puts programmable interrupt controller here.
+// the machine-mode registers are listed too, as in xv6's
+// memlayout.h, though the kernel only programs the S-mode ones.
+#[allow(dead_code)]
 pub mod PLIC {
     pub const PLIC: u64 = 0x0c000000;
+    #[allow(clippy::identity_op)]
     pub const PRIORITY: u64 = PLIC + 0x0;
     pub const PENDING: u64 = PLIC + 0x1000;
 
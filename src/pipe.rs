@@ -1,20 +1,28 @@
-use super::spinlock;
+use super::spinlock::SpinLock;
 
 const PIPESIZE: usize = 512;
+#[allow(dead_code)]
 pub struct PipeData([u8; PIPESIZE]);
 
 impl Default for PipeData {
     fn default() -> Self {
-        PipeData([0 as u8; 512])
+        PipeData([0; PIPESIZE])
     }
 }
 
+// the part of a pipe protected by Pipe::lock.
+#[allow(dead_code)]
 #[derive(Default)]
-pub struct Pipe<'a> {
-    spinlock: spinlock::SpinLock<'a>,
+pub struct PipeInner {
     data: PipeData,
     nread: u32,     // num of bytes read
     nwrite: u32,    // num of bytes written
     readopen: i32,  // read fd is still open
     writeopen: i32, // write fd is still open
 }
+
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct Pipe {
+    lock: SpinLock<PipeInner>,
+}
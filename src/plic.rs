@@ -0,0 +1,48 @@
+// the riscv Platform Level Interrupt Controller (PLIC).
+
+use super::memlayout::{PLIC, UART, UVIRTIO};
+use super::state::os;
+use core::ptr;
+
+#[inline]
+fn write(addr: u64, v: u32) {
+    unsafe { ptr::write_volatile(addr as *mut u32, v) }
+}
+
+#[inline]
+fn read(addr: u64) -> u32 {
+    unsafe { ptr::read_volatile(addr as *const u32) }
+}
+
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn init() {
+    // set desired IRQ priorities non-zero (otherwise disabled).
+    write(PLIC::PRIORITY + UART::UART0_1RQ as u64 * 4, 1);
+    write(PLIC::PRIORITY + UVIRTIO::UVIRTIO0_IRQ as u64 * 4, 1);
+}
+
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn inithart() {
+    let hart = os().cpuid().0 as u64;
+
+    // set uart's enable bit for this hart's S-mode.
+    write(
+        PLIC::senable(hart),
+        (1 << UART::UART0_1RQ) | (1 << UVIRTIO::UVIRTIO0_IRQ),
+    );
+
+    // set this hart's S-mode priority threshold to 0.
+    write(PLIC::spriority(hart), 0);
+}
+
+// ask the PLIC what interrupt we should serve.
+pub fn claim() -> u32 {
+    let hart = os().cpuid().0 as u64;
+    read(PLIC::sclaim(hart))
+}
+
+// tell the PLIC we've served this IRQ.
+pub fn complete(irq: u32) {
+    let hart = os().cpuid().0 as u64;
+    write(PLIC::sclaim(hart), irq);
+}
@@ -0,0 +1,63 @@
+// formatted console output -- print!, println! and the panic handler.
+//
+// in the kernel the text goes to the uart. the hosted build has no
+// uart, so it goes to the host's stderr instead.
+
+use super::spinlock::SpinLock;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// set once a hart panics, other harts freeze their output.
+pub static PANICKED: AtomicBool = AtomicBool::new(false);
+
+pub struct Printer;
+
+// serialize output so lines from different harts don't interleave.
+static PR: SpinLock<Printer> = SpinLock::new(Printer, "pr");
+
+impl Write for Printer {
+    #[cfg(target_os = "none")]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            super::uart::putc_sync(c);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "none"))]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        std::eprint!("{}", s);
+        Ok(())
+    }
+}
+
+pub fn _print(args: fmt::Arguments) {
+    if PANICKED.load(Ordering::Relaxed) {
+        // the panicking hart owns the console now.
+        unsafe {
+            let _ = PR.get_mut_unchecked().write_fmt(args);
+        }
+        return;
+    }
+    let _ = PR.lock().write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::printf::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[cfg(target_os = "none")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    // don't wait for a lock the panicking code might hold.
+    PANICKED.store(true, Ordering::Relaxed);
+    println!("panic: {}", info);
+    loop {}
+}
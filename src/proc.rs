@@ -1,24 +1,83 @@
 // process and scheduling
 // process -- unit of isolation.
+//
+// processes and cpus never point at each other directly. they refer
+// to each other by index into the tables held by State, so nothing
+// in here needs to borrow anything else for a lifetime.
 
-use super::file::{Inode, OpenFileBufferes};
+use super::bio::Bcache;
+use super::file::{FileId, Ftable, InodeId};
+use super::fs::{self, Itable};
+use super::kalloc::{kalloc, kfree};
+use super::memlayout::{kstack, TRAMPOLINE, TRAPFRAME};
 use super::params;
-use super::riscv;
-use super::spinlock;
+use super::riscv::{self, PG, PTE};
+use super::spinlock::{SpinLock, SpinLockGuard};
+use super::state::os;
+use super::switch::swtch;
+use super::trap;
+use super::vm::{self, VmErr};
+use core::cell::{Cell, UnsafeCell};
+use core::fmt;
+use core::ops::Index;
+use core::ptr;
+use core::sync::atomic::AtomicPtr;
 
-#[derive(Default)]
-pub struct Cpus<'a, 'proc: 'a>([Cpu<'a, 'proc>; params::NCPU]);
+// handle of a process: index into State::procs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ProcId(pub usize);
+
+// handle of a cpu: index into State::cpus, same as the hartid.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CpuId(pub usize);
+
+pub struct Cpus([Cpu; params::NCPU]);
+
+impl Cpus {
+    pub const fn new() -> Cpus {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const CPU: Cpu = Cpu::new();
+        Cpus([CPU; params::NCPU])
+    }
+}
+
+impl Index<CpuId> for Cpus {
+    type Output = Cpu;
+    fn index(&self, id: CpuId) -> &Cpu {
+        &self.0[id.0]
+    }
+}
+
+pub struct Procs([Proc; params::NPROC]);
+
+impl Procs {
+    pub const fn new() -> Procs {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const PROC: Proc = Proc::new();
+        Procs([PROC; params::NPROC])
+    }
 
-pub struct Procs<'a>([Proc<'a>; params::NPROC]);
-impl<'a> Default for Procs<'a> {
-    fn default() -> Self {
-        Procs([Default::default(); params::NPROC])
+    // handles of every slot in the process table.
+    pub fn ids(&self) -> impl Iterator<Item = ProcId> {
+        (0..params::NPROC).map(ProcId)
     }
 }
-pub struct InitProc<'a>(Proc<'a>);
+
+impl Index<ProcId> for Procs {
+    type Output = Proc;
+    fn index(&self, id: ProcId) -> &Proc {
+        &self.0[id.0]
+    }
+}
+
+// the first user process.
+#[derive(Clone, Copy)]
+pub struct InitProc(pub ProcId);
 
 // registers for context swithing.
+// laid out as switch.S expects.
 #[derive(Default)]
+#[repr(C)]
 pub struct Context {
     pub ra: u64,
     pub sp: u64,
@@ -38,13 +97,48 @@ pub struct Context {
     pub s11: u64,
 }
 
+impl Context {
+    pub const fn new() -> Context {
+        Context {
+            ra: 0,
+            sp: 0,
+            s0: 0,
+            s1: 0,
+            s2: 0,
+            s3: 0,
+            s4: 0,
+            s5: 0,
+            s6: 0,
+            s7: 0,
+            s8: 0,
+            s9: 0,
+            s10: 0,
+            s11: 0,
+        }
+    }
+}
+
 // state of each CPU
 #[derive(Default)]
-pub struct Cpu<'a, 'proc: 'a> {
-    pub proc: Option<&'a mut Proc<'a>>, // the process run on cpu.
-    pub scheduler: Context,                   // switch to enter scheduler.
-    pub noff: i32,                            // depth of push_off() nesting.
-    pub intena: bool,                         // interrups flag
+pub struct Cpu {
+    pub proc: Cell<Option<ProcId>>,     // the process run on cpu.
+    pub scheduler: UnsafeCell<Context>, // switch to enter scheduler.
+    pub noff: Cell<i32>,                // depth of push_off() nesting.
+    pub intena: Cell<bool>,             // interrups flag
+}
+
+// a cpu's struct is only used by that cpu, with interrupts off.
+unsafe impl Sync for Cpu {}
+
+impl Cpu {
+    pub const fn new() -> Cpu {
+        Cpu {
+            proc: Cell::new(None),
+            scheduler: UnsafeCell::new(Context::new()),
+            noff: Cell::new(0),
+            intena: Cell::new(false),
+        }
+    }
 }
 
 // per-process data for trap for handling code in trampoline.S
@@ -60,7 +154,9 @@ pub struct Cpu<'a, 'proc: 'a> {
 // the trapframe incldues callee-saved user registers like s0-s11 because the
 // return-to-user path via usertrapret() doesn't return through the
 // entire knernel call stack.
-#[derive(Default)]
+// the field offsets are hard coded in trampoline.S.
+#[derive(Default, Clone, Copy)]
+#[repr(C)]
 pub struct Trapframe {
     pub kernel_satp: u64,   // kernal page table
     pub kernel_sp: u64,     // top of process's kernal stack
@@ -106,18 +202,36 @@ pub struct Trapframe {
     pub t6: u64,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Default)]
 pub enum ProcState {
+    #[default]
     Unused,
+    Used,
     Sleeping,
     Runnable,
     Running,
     Zombie,
 }
 
-impl Default for ProcState {
-    fn default() -> Self {
-        ProcState::Unused
+// the part of a process protected by Proc::lock.
+// the lock must be held to read or change any of it.
+pub struct ProcInner {
+    pub state: ProcState, // process state
+    pub chan: usize,      // if non zero, sleep on chan
+    pub killed: bool,     // kill flag
+    pub xstate: i32,      // exit status to be returned to parent's wait
+    pub pid: i32,         // process id
+}
+
+impl ProcInner {
+    pub const fn new() -> ProcInner {
+        ProcInner {
+            state: ProcState::Unused,
+            chan: 0,
+            killed: false,
+            xstate: 0,
+            pid: 0,
+        }
     }
 }
 
@@ -129,205 +243,1049 @@ impl Default for ProcState {
 //                    when syscall finished, kernel will switch back to user space by
 //                    calling `sret` (lower hw privilege).
 // run state.       for scheduling
-#[derive(Default)]
-pub struct Proc<'a, 'b> {
-    os: Option<*mut State<'static>>,
-    pub lock: spinlock::SpinLock<'a>,
+pub struct Proc {
+    pub lock: SpinLock<ProcInner>,
 
-    pub state: ProcState, // process state
-    pub parent: Option<&'a Proc<'a, 'b>>,
-    pub chan: Option<*const ()>, // if non zero, sleep on chan
-    pub killed: bool,            // kill flag
-    pub xstate: bool,            // exit status
-    pub pid: i32,                // process id
+    // State::wait_lock must be held when using this.
+    pub parent: Cell<Option<ProcId>>, // parent process
+
+    // private to the process, lock need not be held.
+    pub kstack: Cell<u64>,                             // bottom of kernal stack for the process
+    pub sz: Cell<u64>,                                 // size of proces mem
+    pub pagetable: Cell<*mut riscv::Pagetable>,        // user page table
+    pub tf: Cell<*mut Trapframe>,                      // data page for trampoline.S
+    pub context: UnsafeCell<Context>,                  // switch() here to run process
+    pub ofile: [Cell<Option<FileId>>; params::NOFILE], // open files
+    pub cwd: Cell<Option<InodeId>>,                    // current directory.
+    pub name: Cell<[u8; 16]>,                          // proc name (debugging)
+}
+
+// the cells above are only used by the process itself, or by
+// whoever holds p.lock while it is not running (allocproc, fork,
+// freeproc), or under wait_lock for parent.
+unsafe impl Sync for Proc {}
+
+impl Proc {
+    // an unused slot of the process table.
+    pub const fn new() -> Proc {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const NOFILE: Cell<Option<FileId>> = Cell::new(None);
+        Proc {
+            lock: SpinLock::new(ProcInner::new(), "proc"),
+            parent: Cell::new(None),
+            kstack: Cell::new(0),
+            sz: Cell::new(0),
+            pagetable: Cell::new(ptr::null_mut()),
+            tf: Cell::new(ptr::null_mut()),
+            context: UnsafeCell::new(Context::new()),
+            ofile: [NOFILE; params::NOFILE],
+            cwd: Cell::new(None),
+            name: Cell::new([0; 16]),
+        }
+    }
+
+    pub fn setkilled(&self) {
+        self.lock.lock().killed = true;
+    }
+
+    pub fn killed(&self) -> bool {
+        self.lock.lock().killed
+    }
+
+    // the name, for diagnostics.
+    pub fn procname(&self) -> ProcName {
+        ProcName(self.name.get())
+    }
+
+    // set the name, cut short to leave room for the NUL.
+    pub fn setname(&self, name: &[u8]) {
+        let mut buf = [0; 16];
+        let n = name.len().min(buf.len() - 1);
+        buf[..n].copy_from_slice(&name[..n]);
+        self.name.set(buf);
+    }
+
+    // Create a user page table for a given process, with no user memory,
+    // but with trampoline and trapframe pages.
+    pub fn proc_pagetable(&self) -> Result<*mut riscv::Pagetable, VmErr> {
+        // An empty page table.
+        let pagetable = vm::uvmcreate()?;
+        let pt = unsafe { &mut *pagetable };
+
+        // map the trampoline code (for system call return)
+        // at the highest user virtual address.
+        // only the supervisor uses it, on the way
+        // to/from user space, so not PTE::U.
+        let trampoline = trap::trampoline as *const () as u64;
+        if let Err(e) = pt.mappages(TRAMPOLINE, PG::SIZE, trampoline, PTE::R | PTE::X) {
+            vm::uvmfree(pagetable, 0);
+            return Err(e);
+        }
+
+        // map the trapframe page just below the trampoline page, for
+        // trampoline.S.
+        if let Err(e) = pt.mappages(TRAPFRAME, PG::SIZE, self.tf.get() as u64, PTE::R | PTE::W) {
+            pt.uvmunmap(TRAMPOLINE, 1, false);
+            vm::uvmfree(pagetable, 0);
+            return Err(e);
+        }
+
+        Ok(pagetable)
+    }
+}
+
+// a copy of a process's name.
+#[derive(PartialEq)]
+pub struct ProcName([u8; 16]);
+
+impl ProcName {
+    // the name up to its NUL.
+    pub fn as_str(&self) -> &str {
+        let n = self.0.iter().position(|c| *c == 0).unwrap_or(self.0.len());
+        core::str::from_utf8(&self.0[..n]).unwrap_or("???")
+    }
+}
+
+impl fmt::Display for ProcName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for ProcName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl PartialEq<&str> for ProcName {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+// Allocate a page for each process's kernel stack.
+// Map it high in memory, followed by an invalid
+// guard page.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn proc_mapstacks(kpgtbl: &mut riscv::Pagetable) {
+    for id in os().procs.ids() {
+        let pa = kalloc().expect("proc_mapstacks");
+        let va = kstack(id.0 as u64);
+        vm::kvmmap(kpgtbl, va, pa as u64, PG::SIZE, PTE::R | PTE::W);
+    }
+}
+
+// initialize the proc table.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn procinit() {
+    for id in os().procs.ids() {
+        os().procs[id].kstack.set(kstack(id.0 as u64));
+    }
+}
+
+// Look in the process table for an Unused proc.
+// If found, initialize state required to run in the kernel,
+// and return with p.lock held.
+// If there are no free procs, or a memory allocation fails, return Err.
+pub fn allocproc() -> Result<(ProcId, SpinLockGuard<'static, ProcInner>), StateErr> {
+    let mut found = None;
+    for id in os().procs.ids() {
+        let mut inner = os().procs[id].lock.lock();
+        if inner.state == ProcState::Unused {
+            inner.pid = os().allocpid();
+            inner.state = ProcState::Used;
+            found = Some((id, inner));
+            break;
+        }
+    }
+    let (id, inner) = found.ok_or(StateErr::NoFreeProcErr)?;
+    let p = &os().procs[id];
+
+    // Allocate a trapframe page.
+    match kalloc() {
+        Some(pa) => p.tf.set(pa as *mut Trapframe),
+        None => {
+            freeproc(id, inner);
+            return Err(StateErr::VmErr(VmErr::OutOfMemErr));
+        }
+    }
+
+    // An empty user page table.
+    match p.proc_pagetable() {
+        Ok(pagetable) => p.pagetable.set(pagetable),
+        Err(e) => {
+            freeproc(id, inner);
+            return Err(e.into());
+        }
+    }
+
+    // Set up new context to start executing at forkret,
+    // which returns to user space.
+    let context = unsafe { &mut *p.context.get() };
+    *context = Context::new();
+    context.ra = forkret as *const () as u64;
+    context.sp = p.kstack.get() + PG::SIZE;
+
+    Ok((id, inner))
+}
+
+// free a proc structure and the data hanging from it,
+// including user pages.
+// p.lock must be held, and is released.
+pub fn freeproc(id: ProcId, mut inner: SpinLockGuard<'_, ProcInner>) {
+    let p = &os().procs[id];
+    if !p.tf.get().is_null() {
+        kfree(p.tf.get() as *mut u8);
+    }
+    p.tf.set(ptr::null_mut());
+    if !p.pagetable.get().is_null() {
+        proc_freepagetable(p.pagetable.get(), p.sz.get());
+    }
+    p.pagetable.set(ptr::null_mut());
+    p.sz.set(0);
+    p.parent.set(None);
+    p.name.set([0; 16]);
+    *inner = ProcInner::new();
+}
+
+// Free a process's page table, and free the
+// physical memory it refers to.
+pub fn proc_freepagetable(pagetable: *mut riscv::Pagetable, sz: u64) {
+    let pt = unsafe { &mut *pagetable };
+    pt.uvmunmap(TRAMPOLINE, 1, false);
+    pt.uvmunmap(TRAPFRAME, 1, false);
+    vm::uvmfree(pagetable, sz);
+}
+
+// Grow or shrink user memory by n bytes.
+pub fn growproc(n: i32) -> Result<(), VmErr> {
+    let id = os().myproc().expect("growproc");
+    let p = &os().procs[id];
+    let pt = unsafe { &mut *p.pagetable.get() };
+
+    let sz = p.sz.get();
+    let delta = n.unsigned_abs() as u64;
+    p.sz.set(if n > 0 {
+        pt.uvmalloc(sz, sz + delta, PTE::W)?
+    } else if n < 0 {
+        let newsz = sz.checked_sub(delta).ok_or(VmErr::BadAddressErr(sz))?;
+        pt.uvmdealloc(sz, newsz)
+    } else {
+        sz
+    });
+    Ok(())
+}
+
+// a user program that calls exec("/init", argv).
+// assembled from asm/initcode.S.
+// od -t xC initcode
+#[rustfmt::skip]
+pub static INITCODE: [u8; 52] = [
+    0x17, 0x05, 0x00, 0x00, 0x13, 0x05, 0x45, 0x02,
+    0x97, 0x05, 0x00, 0x00, 0x93, 0x85, 0x35, 0x02,
+    0x93, 0x08, 0x70, 0x00, 0x73, 0x00, 0x00, 0x00,
+    0x93, 0x08, 0x20, 0x00, 0x73, 0x00, 0x00, 0x00,
+    0xef, 0xf0, 0x9f, 0xff, 0x2f, 0x69, 0x6e, 0x69,
+    0x74, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+];
+
+// Set up first user process.
+pub fn userinit() {
+    let (id, mut inner) = allocproc().expect("userinit");
+    *os().initproc.lock() = Some(InitProc(id));
+    let p = &os().procs[id];
+
+    // allocate one user page and copy initcode's instructions
+    // and data into it.
+    unsafe { (*p.pagetable.get()).uvmfirst(&INITCODE) };
+    p.sz.set(PG::SIZE);
+
+    // prepare for the very first "return" from kernel to user.
+    let tf = unsafe { &mut *p.tf.get() };
+    tf.epc = 0; // user program counter
+    tf.sp = PG::SIZE; // user stack pointer
+
+    p.setname(b"initcode");
+    p.cwd.set(Some(os().iget(params::ROOTDEV as u32, fs::ROOTINO)));
+
+    inner.state = ProcState::Runnable;
+}
+
+// Create a new process, copying the parent.
+// Sets up child kernel stack to return as if from fork() system call.
+// Returns the child's pid.
+pub fn fork() -> Result<i32, StateErr> {
+    let id = os().myproc().expect("fork");
+
+    // Allocate process.
+    let (nid, ninner) = allocproc()?;
+    let p = &os().procs[id];
+    let np = &os().procs[nid];
+
+    // Copy user memory from parent to child.
+    if let Err(e) = unsafe { (*p.pagetable.get()).uvmcopy(&mut *np.pagetable.get(), p.sz.get()) } {
+        freeproc(nid, ninner);
+        return Err(e.into());
+    }
+    np.sz.set(p.sz.get());
+
+    // copy saved user registers.
+    unsafe { *np.tf.get() = *p.tf.get() };
+
+    // Cause fork to return 0 in the child.
+    unsafe { (*np.tf.get()).a0 = 0 };
+
+    // increment reference counts on open file descriptors.
+    for (nf, f) in np.ofile.iter().zip(p.ofile.iter()) {
+        nf.set(f.get().map(|f| os().filedup(f)));
+    }
+    np.cwd.set(p.cwd.get().map(|ip| os().idup(ip)));
+
+    np.name.set(p.name.get());
+
+    let pid = ninner.pid;
+    drop(ninner);
+
+    let wait_lock = os().wait_lock.lock();
+    np.parent.set(Some(id));
+    drop(wait_lock);
+
+    np.lock.lock().state = ProcState::Runnable;
+
+    Ok(pid)
+}
+
+// Pass p's abandoned children to init.
+// Caller must hold wait_lock.
+pub fn reparent(id: ProcId) {
+    let init = os().initproc.lock().expect("reparent: no init").0;
+    for pp in os().procs.ids() {
+        if os().procs[pp].parent.get() == Some(id) {
+            os().procs[pp].parent.set(Some(init));
+            os().wakeup(&os().procs[init] as *const Proc);
+        }
+    }
+}
 
-    pub kstack: u64,                   // bottom of kernal stack for the process
-    pub sz: usize,                     // size of proces mem
-    pub pagetable: riscv::Pagetable,   // page table
-    pub tf: Option<&'a mut Trapframe>, // data page for trampoline.S
-    pub context: Context,              // switch() here to run process
-    pub ofile: Option<&'a mut OpenFileBufferes<'b>>, // open files
-    pub cwd: Option<&'a mut Inode<'b>>, // current directory.
-    pub name: &'a str,                 // proc name (debugging)
+// Exit the current process.  Does not return.
+// An exited process remains in the zombie state
+// until its parent calls wait().
+pub fn exit(status: i32) -> ! {
+    let id = os().myproc().expect("exit");
+    if let Some(InitProc(init)) = *os().initproc.lock() {
+        if init == id {
+            panic!("init exiting");
+        }
+    }
+
+    // Close all open files.
+    let p = &os().procs[id];
+    for f in p.ofile.iter() {
+        if let Some(f) = f.take() {
+            os().fileclose(f);
+        }
+    }
+    if let Some(cwd) = p.cwd.take() {
+        os().iput(cwd);
+    }
+
+    let wait_lock = os().wait_lock.lock();
+
+    // Give any children to init.
+    reparent(id);
+
+    // Parent might be sleeping in wait().
+    if let Some(parent) = p.parent.get() {
+        os().wakeup(&os().procs[parent] as *const Proc);
+    }
+
+    let mut inner = p.lock.lock();
+    inner.xstate = status;
+    inner.state = ProcState::Zombie;
+
+    drop(wait_lock);
+
+    // Jump into the scheduler, never to return.
+    sched(&inner);
+    panic!("zombie exit");
 }
 
-// process can access the OS state.
-impl<'a, 'b> OSFetch for Proc<'a, 'b> {
-    fn get_os(&mut self) -> Option<*mut State<'a>> {
-        self.os
+// Wait for a child process to exit and return its pid.
+// the exit status is copied out to addr, unless addr is 0.
+// Return Err if this process has no children.
+pub fn wait(addr: u64) -> Result<i32, StateErr> {
+    let id = os().myproc().expect("wait");
+    let p = &os().procs[id];
+
+    let mut wait_lock = os().wait_lock.lock();
+    loop {
+        // Scan through table looking for exited children.
+        let mut havekids = false;
+        for pp in os().procs.ids() {
+            if os().procs[pp].parent.get() != Some(id) {
+                continue;
+            }
+            // make sure the child isn't still in exit() or swtch().
+            let child = os().procs[pp].lock.lock();
+            havekids = true;
+            if child.state == ProcState::Zombie {
+                // Found one.
+                let pid = child.pid;
+                if addr != 0 {
+                    let xstate = child.xstate.to_le_bytes();
+                    unsafe { (*p.pagetable.get()).copyout(addr, &xstate)? };
+                }
+                freeproc(pp, child);
+                return Ok(pid);
+            }
+        }
+
+        // No point waiting if we don't have any children.
+        if !havekids {
+            return Err(StateErr::NoChildrenErr);
+        }
+        if p.killed() {
+            return Err(StateErr::KilledErr);
+        }
+
+        // Wait for a child to exit.
+        wait_lock = os().sleep(p as *const Proc, wait_lock);
     }
 }
 
-impl<'a, 'b> Proc<'a, 'b> {
-    pub fn new() -> Proc<'a, 'b> {
-        unimplemented!();
+// Kill the process with the given pid.
+// The victim won't exit until it tries to return
+// to user space (see usertrap() in trap.rs).
+pub fn kill(pid: i32) -> Result<(), StateErr> {
+    for id in os().procs.ids() {
+        let mut p = os().procs[id].lock.lock();
+        if p.pid == pid && p.state != ProcState::Unused {
+            p.killed = true;
+            if p.state == ProcState::Sleeping {
+                // Wake process from sleep().
+                p.state = ProcState::Runnable;
+            }
+            return Ok(());
+        }
     }
+    Err(StateErr::ProcessDoesntExistErr)
+}
+
+// Copy to either a user address, or kernel address,
+// depending on user_dst.
+pub fn either_copyout(user_dst: bool, dst: u64, src: &[u8]) -> Result<(), VmErr> {
+    if user_dst {
+        let id = os().myproc().expect("either_copyout");
+        unsafe { (*os().procs[id].pagetable.get()).copyout(dst, src) }
+    } else {
+        unsafe { ptr::copy(src.as_ptr(), dst as *mut u8, src.len()) };
+        Ok(())
+    }
+}
+
+// Copy from either a user address, or kernel address,
+// depending on user_src.
+pub fn either_copyin(dst: &mut [u8], user_src: bool, src: u64) -> Result<(), VmErr> {
+    if user_src {
+        let id = os().myproc().expect("either_copyin");
+        unsafe { (*os().procs[id].pagetable.get()).copyin(dst, src) }
+    } else {
+        unsafe { ptr::copy(src as *const u8, dst.as_mut_ptr(), dst.len()) };
+        Ok(())
+    }
+}
 
-    // Wake up process if it is sleeping in wait(); used by exit();
-    fn wakeup1(&mut self) {
-        if !self.lock.holding() {
-            panic!("wakeup1");
+// A fork child's very first scheduling by scheduler()
+// will swtch to forkret.
+extern "C" fn forkret() {
+    // Still holding p.lock from scheduler.
+    let id = os().myproc().expect("forkret");
+    unsafe { os().procs[id].lock.force_unlock() };
+
+    trap::usertrapret();
+}
+
+// Per-CPU process scheduler.
+// Each CPU calls scheduler() after setting itself up.
+// Scheduler never returns.  It loops, doing:
+//  - choose a process to run.
+//  - swtch to start running that process.
+//  - eventually that process transfers control
+//    via swtch back to the scheduler.
+// a Runnable process is only ever picked up under its p.lock, so two
+// harts scanning the table at once can't both run it.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn scheduler() -> ! {
+    let c = os().mycpu();
+    c.proc.set(None);
+    loop {
+        // Avoid deadlock by ensuring that devices can interrupt.
+        riscv::DEV_INTR::on();
+
+        let mut found = false;
+        for id in os().procs.ids() {
+            let mut p = os().procs[id].lock.lock();
+            if p.state == ProcState::Runnable {
+                // Switch to chosen process.  It is the process's job
+                // to release its lock and then reacquire it
+                // before jumping back to us.
+                p.state = ProcState::Running;
+                c.proc.set(Some(id));
+                unsafe {
+                    swtch(c.scheduler.get(), os().procs[id].context.get());
+                }
+
+                // Process is done running for now.
+                // It should have changed its p.state before coming back.
+                c.proc.set(None);
+                found = true;
+            }
         }
-        if self.chan.unwrap() as *const () == self as *const _ as *const () {
-            self.state = ProcState::Runnable;
+
+        if !found {
+            // nothing to run; stop running on this core until an interrupt.
+            riscv::DEV_INTR::on();
+            riscv::wfi();
         }
     }
+}
+
+// Switch to scheduler.  Must hold only p.lock
+// and have changed proc.state. Saves and restores
+// intena because intena is a property of this
+// kernel thread, not this CPU. It should
+// be proc.intena and proc.noff, but that would
+// break in the few places where a lock is held but
+// there's no process.
+pub fn sched(p: &SpinLockGuard<'_, ProcInner>) {
+    let id = os().myproc().expect("sched no proc");
+    let proc = &os().procs[id];
+
+    if p.spinlock() != &proc.lock || !proc.lock.holding() {
+        panic!("sched p.lock");
+    }
+    if os().mycpu().noff.get() != 1 {
+        panic!("sched locks");
+    }
+    if p.state == ProcState::Running {
+        panic!("sched running");
+    }
+    if riscv::DEV_INTR::get() {
+        panic!("sched interruptible");
+    }
 
-    // Kill the process with the given pid
-    // The victim won't exit until it tires to return
-    // to user space (usertrap() in trap.rs)
+    let intena = os().mycpu().intena.get();
+    unsafe {
+        swtch(proc.context.get(), os().mycpu().scheduler.get());
+    }
+    os().mycpu().intena.set(intena);
 }
 
-pub fn sched() {}
+// Give up the CPU for one scheduling round.
+pub fn yield_() {
+    let id = os().myproc().expect("yield");
+    let mut p = os().procs[id].lock.lock();
+    p.state = ProcState::Runnable;
+    sched(&p);
+}
 
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq)]
 pub enum StateErr {
-    CpuIndexErr,
-    ProcessDoesntExistErr,
+    ProcessDoesntExistErr, // no process has that pid
+    NoFreeProcErr,         // the process table is full
+    NoChildrenErr,         // nothing to wait for
+    KilledErr,             // killed while waiting
+    VmErr(VmErr),          // could not build or copy an address space
+}
+
+impl From<VmErr> for StateErr {
+    fn from(e: VmErr) -> StateErr {
+        StateErr::VmErr(e)
+    }
 }
 
 // global state, exists for the entire lifetime of the program.
-pub struct State<'a> {
-    cpus: Cpus<'a>,
-    procs: Procs<'a>,
-    initproc: InitProc<'a>,
-    nextpid: i32,
-    pid_lock: spinlock::SpinLock<'a>,
-}
-
-impl State<'_> {
-    pub fn new<'a>() -> State<'a> {
-        let mut state = State {
-            cpus: Default::default(),
-            procs: Default::default(),
-            initproc: InitProc(Proc::new()),
-            nextpid: 1,
-            pid_lock: Default::default(),
-        };
-        let stateptr = Some(&mut state as *mut State<'_>);
-        state.pid_lock = spinlock::SpinLock::new("nexPid", stateptr);
-        state
+pub struct State {
+    pub cpus: Cpus,
+    pub procs: Procs,
+    pub initproc: SpinLock<Option<InitProc>>,
+    pub kpagetable: AtomicPtr<riscv::Pagetable>, // set by vm::kvminit()
+    pub ftable: Ftable,
+    pub itable: Itable,
+    pub bcache: Bcache,
+
+    // helps ensure that wakeups of wait()ing
+    // parents are not lost. helps obey the
+    // memory model when using p.parent.
+    // must be acquired before any p.lock.
+    pub wait_lock: SpinLock<()>,
+
+    nextpid: SpinLock<i32>,
+}
+
+impl State {
+    pub const fn new() -> State {
+        State {
+            cpus: Cpus::new(),
+            procs: Procs::new(),
+            initproc: SpinLock::new(None, "initproc"),
+            kpagetable: AtomicPtr::new(ptr::null_mut()),
+            ftable: Ftable::new(),
+            itable: Itable::new(),
+            bcache: Bcache::new(),
+            wait_lock: SpinLock::new((), "wait_lock"),
+            nextpid: SpinLock::new(1, "nextpid"),
+        }
     }
 
     // must be called with interrupts disabled.
     // prevent process be moved to a different cpu.
-    pub fn cpuid(&self) -> u64 {
-        riscv::REGS::TP::read()
+    pub fn cpuid(&self) -> CpuId {
+        CpuId(riscv::REGS::TP::read() as usize)
     }
 
-    pub fn mycpu(&mut self) -> Result<&'_ mut Cpu<'_>, StateErr> {
-        let id = self.cpuid() as usize;
-        match self.cpus {
-            // borrow Option content in Cpus
-            Cpus(ref mut cpus) if id < cpus.len() => Ok(&mut cpus[id]),
-            _ => Err(StateErr::CpuIndexErr),
+    // return this cpu's cpu struct.
+    // interrupts must be disabled.
+    pub fn mycpu(&self) -> &Cpu {
+        let id = self.cpuid();
+        if id.0 >= params::NCPU {
+            panic!("mycpu");
         }
+        &self.cpus[id]
     }
 
-    pub fn myproc(&mut self) -> Result<&'_ mut Proc<'_>, StateErr> {
-        let mut result: Result<&'_ mut Proc<'_>, StateErr>;
+    // return the current process, or None if the cpu is
+    // running the scheduler.
+    pub fn myproc(&self) -> Option<ProcId> {
+        let mut result = None;
         self.push_off()
             .sdo(|state| {
-                let proc = state.mycpu().ok().unwrap().proc.unwrap();
-                result = Ok(proc);
+                result = state.mycpu().proc.get();
             })
             .pop_off();
         result
     }
 
-    pub fn allocpid(&mut self) -> i32 {
-        self.pid_lock.acquire();
-        self.nextpid = self.nextpid + 1;
-        let pid = self.nextpid;
-        self.pid_lock.release();
-        pid
+    pub fn allocpid(&self) -> i32 {
+        let mut nextpid = self.nextpid.lock();
+        *nextpid += 1;
+        *nextpid
     }
 
     // atomically release lock and sleep on chan.
     // reacquires lock when awakened.
-    pub fn sleep<T>(&mut self, chan: *const T, lk: &'a mut spinlock::SpinLock<'a>) {
+    pub fn sleep<'a, T, U>(
+        &self,
+        chan: *const T,
+        lk: SpinLockGuard<'a, U>,
+    ) -> SpinLockGuard<'a, U> {
         // must acquire p.lock in order to
         // change p.state and then call sched.
         // Once we hold p.lock we can be guaranteed that we won't
         // miss any wakeup
         // so it's okay to release lk.
-        let p = self.proc_ref_mut();
-        if lk != &p.lock {
-            p.lock.acquire();
-            lk.release();
-        }
+        let id = self.myproc().expect("sleep");
+        let mut p = self.procs[id].lock.lock();
+        let lk = lk.unlock();
 
-        p.chan = Some(chan as *const ());
+        p.chan = chan as usize;
         p.state = ProcState::Sleeping;
 
-        sched();
+        sched(&p);
 
         // no more chan
-        p.chan = None;
+        p.chan = 0;
 
         // reacquire original lock.
-        if lk != &p.lock {
-            p.lock.release();
-            lk.acquire();
-        }
+        drop(p);
+        lk.lock()
     }
 
     // wake up all processes sleeping on chan.
     // Must be called without any p.lock.
-    pub fn wakeup<T>(&mut self, chan: *const T) {
-        self.procs.0.iter_mut().map(|proc| {
-            proc.lock.acquire();
-            if proc.state == ProcState::Sleeping
-                && match proc.chan {
-                    Some(chanp) => chanp == chan as *const (),
-                    None => false,
-                }
-            {
-                proc.state = ProcState::Runnable;
+    pub fn wakeup<T>(&self, chan: *const T) {
+        for id in self.procs.ids() {
+            let mut p = self.procs[id].lock.lock();
+            if p.state == ProcState::Sleeping && p.chan == chan as usize {
+                p.state = ProcState::Runnable;
             }
-            proc.lock.release();
-        });
+        }
     }
 }
 
-impl OSFetch for State<'_> {
-    fn get_os(&mut self) -> Option<*mut State> {
-        return Some(self as *mut _);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+    use std::thread;
+
+    #[test]
+    fn allocpid_is_unique_across_harts() {
+        let workers: Vec<_> = (0..4)
+            .map(|_| thread::spawn(|| (0..100).map(|_| os().allocpid()).collect::<Vec<_>>()))
+            .collect();
+        let mut pids: Vec<i32> = workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect();
+        pids.sort_unstable();
+        pids.dedup();
+        assert_eq!(pids.len(), 400);
     }
-}
 
-// fetch state element easier.
-// all structs that can fetch os will have a definite size.
-pub trait OSFetch: Sized {
-    fn get_os(&mut self) -> Option<*mut State>;
+    #[test]
+    fn proc_pagetable_maps_trampoline_and_trapframe() {
+        use super::super::kalloc;
+        let _kmem = kalloc::tests::kinit();
+        let nfree = kalloc::stats().nfree;
+
+        let p = Proc::new();
+        p.tf.set(kalloc::kalloc().unwrap() as *mut Trapframe);
+        let pagetable = p.proc_pagetable().unwrap();
+        let pt = unsafe { &mut *pagetable };
+
+        let tf = *pt.walk(TRAPFRAME, false).unwrap();
+        assert_eq!(tf.pa(), p.tf.get() as u64);
+        assert_eq!(tf.flags(), PTE::V | PTE::R | PTE::W);
+        let tramp = *pt.walk(TRAMPOLINE, false).unwrap();
+        assert_eq!(tramp.flags(), PTE::V | PTE::R | PTE::X);
+        // neither page is reachable from user mode.
+        assert_eq!(pt.walkaddr(TRAPFRAME), None);
+        assert_eq!(pt.walkaddr(TRAMPOLINE), None);
 
-    #[inline]
-    fn os_ref(&mut self) -> &'_ State {
-        unsafe { self.get_os().unwrap().as_ref().unwrap() }
+        // grow a user image below them, then tear it all down.
+        p.sz.set(pt.uvmalloc(0, 2 * PG::SIZE, PTE::W).unwrap());
+        assert!(pt.walkaddr(PG::SIZE).is_some());
+        proc_freepagetable(pagetable, p.sz.get());
+        kalloc::kfree(p.tf.get() as *mut u8);
+        assert_eq!(kalloc::stats().nfree, nfree);
     }
 
-    #[inline]
-    fn os_ref_mut(&mut self) -> &'_ mut State {
-        unsafe { self.get_os().unwrap().as_mut().unwrap() }
+    // a process with an npage user image, as if it had been running.
+    fn user(npage: u64) -> ProcId {
+        let (id, inner) = allocproc().unwrap();
+        drop(inner);
+        let p = &os().procs[id];
+        p.sz.set(unsafe { (*p.pagetable.get()).uvmalloc(0, npage * PG::SIZE, PTE::W).unwrap() });
+        p.setname(b"user");
+        id
     }
 
-    #[inline]
-    fn cpu_ref(&mut self) -> &'_ Cpu {
-        unsafe { (*self.get_os().unwrap()).mycpu().ok().unwrap() as &'_ Cpu<'_> }
+    fn pid(id: ProcId) -> i32 {
+        os().procs[id].lock.lock().pid
     }
 
-    #[inline]
-    fn cpu_ref_mut(&mut self) -> &'_ mut Cpu {
-        unsafe { (*self.get_os().unwrap()).mycpu().ok().unwrap() }
+    fn reap(id: ProcId) {
+        freeproc(id, os().procs[id].lock.lock());
     }
 
-    #[inline]
-    fn proc_ref(&mut self) -> &'_ Proc {
-        unsafe { (*self.get_os().unwrap()).myproc().ok().unwrap() as &'_ Proc }
+    #[test]
+    fn allocproc_and_freeproc() {
+        use super::super::kalloc;
+        let _kmem = kalloc::tests::kinit();
+        let nfree = kalloc::stats().nfree;
+
+        let (id, inner) = allocproc().unwrap();
+        assert!(inner.state == ProcState::Used);
+        assert!(inner.pid > 0);
+        let p = &os().procs[id];
+        assert!(!p.tf.get().is_null() && !p.pagetable.get().is_null());
+        let context = unsafe { &*p.context.get() };
+        assert_eq!(context.ra, forkret as *const () as u64);
+        assert_eq!(context.sp, p.kstack.get() + PG::SIZE);
+
+        freeproc(id, inner);
+        assert!(os().procs[id].lock.lock().state == ProcState::Unused);
+        assert!(os().procs[id].pagetable.get().is_null());
+        assert_eq!(kalloc::stats().nfree, nfree);
     }
 
-    #[inline]
-    fn proc_ref_mut(&mut self) -> &'_ mut Proc {
-        unsafe { (*self.get_os().unwrap()).myproc().ok().unwrap() }
+    #[test]
+    fn fork_copies_the_parent() {
+        let _kmem = super::super::kalloc::tests::kinit();
+        let parent = user(2);
+        let p = &os().procs[parent];
+        unsafe {
+            (*p.pagetable.get()).copyout(PG::SIZE + 7, b"forked").unwrap();
+            (*p.tf.get()).a0 = 77;
+            (*p.tf.get()).epc = 0x1234;
+        }
+        {
+            let mut file = os().ftable.file.lock();
+            file[5].refc = 1;
+        }
+        p.ofile[2].set(Some(FileId(5)));
+        os().mycpu().proc.set(Some(parent));
+
+        let cpid = fork().unwrap();
+        os().mycpu().proc.set(None);
+
+        let child = os().procs.ids().find(|id| pid(*id) == cpid).unwrap();
+        let c = &os().procs[child];
+        assert_eq!(c.parent.get(), Some(parent));
+        assert!(c.lock.lock().state == ProcState::Runnable);
+        assert_eq!(c.sz.get(), 2 * PG::SIZE);
+        assert_eq!(c.procname(), "user");
+        assert_eq!(c.ofile[2].get(), Some(FileId(5)));
+        assert_eq!(os().ftable.file.lock()[5].refc, 2);
+        unsafe {
+            assert_eq!(((*c.tf.get()).a0, (*c.tf.get()).epc), (0, 0x1234));
+            let mut buf = [0u8; 6];
+            (*c.pagetable.get()).copyin(&mut buf, PG::SIZE + 7).unwrap();
+            assert_eq!(&buf, b"forked");
+            assert_ne!((*c.pagetable.get()).walkaddr(0), (*p.pagetable.get()).walkaddr(0));
+        }
+
+        os().fileclose(FileId(5));
+        os().fileclose(FileId(5));
+        assert_eq!(os().ftable.file.lock()[5].refc, 0);
+        c.ofile[2].set(None);
+        p.ofile[2].set(None);
+        reap(child);
+        reap(parent);
     }
-}
 
+    #[test]
+    fn wait_reaps_zombie_children() {
+        let _kmem = super::super::kalloc::tests::kinit();
+        let parent = user(1);
+        let child = user(1);
+        let cpid = pid(child);
+        os().procs[child].parent.set(Some(parent));
+        {
+            let mut c = os().procs[child].lock.lock();
+            c.state = ProcState::Zombie;
+            c.xstate = -7;
+        }
+        os().mycpu().proc.set(Some(parent));
+
+        assert_eq!(wait(16), Ok(cpid));
+        assert!(os().procs[child].lock.lock().state == ProcState::Unused);
+        let mut xstate = [0u8; 4];
+        unsafe { (*os().procs[parent].pagetable.get()).copyin(&mut xstate, 16).unwrap() };
+        assert_eq!(i32::from_le_bytes(xstate), -7);
+
+        // nothing left to wait for.
+        assert_eq!(wait(0), Err(StateErr::NoChildrenErr));
+
+        os().mycpu().proc.set(None);
+        reap(parent);
+    }
+
+    #[test]
+    fn kill_marks_and_wakes() {
+        let _kmem = super::super::kalloc::tests::kinit();
+        let id = user(0);
+        os().procs[id].lock.lock().state = ProcState::Sleeping;
+
+        assert_eq!(kill(pid(id)), Ok(()));
+        assert!(os().procs[id].killed());
+        assert!(os().procs[id].lock.lock().state == ProcState::Runnable);
+        assert_eq!(kill(-5), Err(StateErr::ProcessDoesntExistErr));
+
+        reap(id);
+    }
+
+    #[test]
+    fn reparent_hands_children_to_init() {
+        let _kmem = super::super::kalloc::tests::kinit();
+        let (init, dying, orphan) = (user(0), user(0), user(0));
+        *os().initproc.lock() = Some(InitProc(init));
+        os().procs[orphan].parent.set(Some(dying));
+        os().procs[init].lock.lock().state = ProcState::Sleeping;
+        os().procs[init].lock.lock().chan = &os().procs[init] as *const Proc as usize;
+
+        {
+            let _wait_lock = os().wait_lock.lock();
+            reparent(dying);
+        }
+        assert_eq!(os().procs[orphan].parent.get(), Some(init));
+        // init was woken up to reap it.
+        assert!(os().procs[init].lock.lock().state == ProcState::Runnable);
+
+        *os().initproc.lock() = None;
+        for id in [init, dying, orphan].iter() {
+            reap(*id);
+        }
+    }
+
+    #[test]
+    fn userinit_builds_init() {
+        let _kmem = super::super::kalloc::tests::kinit();
+        userinit();
+        let id = os().initproc.lock().unwrap().0;
+        let p = &os().procs[id];
+        assert!(p.lock.lock().state == ProcState::Runnable);
+        assert_eq!(p.procname(), "initcode");
+        assert_eq!(p.sz.get(), PG::SIZE);
+        unsafe {
+            assert_eq!(((*p.tf.get()).epc, (*p.tf.get()).sp), (0, PG::SIZE));
+            let pt = &mut *p.pagetable.get();
+            let pte = *pt.walk(0, false).unwrap();
+            assert_eq!(pte.flags(), PTE::V | PTE::R | PTE::W | PTE::X | PTE::U);
+            let mut code = [0u8; 52];
+            pt.copyin(&mut code, 0).unwrap();
+            assert_eq!(code, INITCODE);
+        }
+        let cwd = p.cwd.take().unwrap();
+        assert_eq!(os().itable.inode[cwd.0].inum.get(), fs::ROOTINO);
+
+        os().iput(cwd);
+        *os().initproc.lock() = None;
+        reap(id);
+    }
+
+    // run p's user code from its epc up to the first ecall, leaving
+    // the system call number and arguments in the trapframe. knows
+    // only the instructions initcode uses: auipc, addi (and li) and
+    // ecall.
+    fn run_to_ecall(p: &Proc) {
+        let (tf, pt) = unsafe { (&mut *p.tf.get(), &mut *p.pagetable.get()) };
+        let mut x = [0u64; 32];
+        loop {
+            let mut ins = [0u8; 4];
+            pt.copyin(&mut ins, tf.epc).unwrap();
+            let ins = u32::from_le_bytes(ins);
+            let (rd, rs1) = (((ins >> 7) & 31) as usize, ((ins >> 15) & 31) as usize);
+            match ins & 0x7f {
+                0x17 => x[rd] = tf.epc.wrapping_add((ins & !0xfff) as i32 as u64),
+                0x13 if (ins >> 12) & 7 == 0 => {
+                    x[rd] = x[rs1].wrapping_add((ins as i32 >> 20) as u64)
+                }
+                0x73 if ins == 0x73 => break,
+                _ => panic!("run_to_ecall: {:#010x} at {:#x}", ins, tf.epc),
+            }
+            x[0] = 0;
+            tf.epc += 4;
+        }
+        tf.a0 = x[10];
+        tf.a1 = x[11];
+        tf.a7 = x[17];
+    }
+
+    #[test]
+    fn initcode_execs_init() {
+        use super::super::exec::tests::image;
+        use super::super::fs::tests::{putfile, rootdisk};
+        use super::super::syscall::{syscall, SYS_EXEC};
+        let _kmem = super::super::kalloc::tests::kinit();
+        let _root = rootdisk();
+        let root = os().iget(params::ROOTDEV as u32, fs::ROOTINO);
+        putfile(root, b"init", &image(&[0x13; 8], b"d", 0));
+        os().iput(root);
+
+        userinit();
+        let id = os().initproc.lock().unwrap().0;
+        let p = &os().procs[id];
+        os().mycpu().proc.set(Some(id));
+        run_to_ecall(p);
+        assert_eq!(unsafe { (*p.tf.get()).a7 }, SYS_EXEC as u64);
+        unsafe { (*p.tf.get()).epc += 4 };
+        syscall();
+        // exec returns argc, 1 for { "/init", 0 }.
+        assert_eq!(unsafe { (*p.tf.get()).a0 }, 1);
+        assert_eq!(p.procname(), "init");
+        os().mycpu().proc.set(None);
+
+        os().iput(p.cwd.take().unwrap());
+        *os().initproc.lock() = None;
+        reap(id);
+    }
+
+    // boot as far as the host can: initcode execs the real /init,
+    // which starts out waiting for orphans.
+    #[test]
+    fn boots_into_init() {
+        use super::super::fs::tests::rootdisk;
+        use super::super::ramdisk;
+        use super::super::syscall::{syscall, SYS_EXEC, SYS_WAIT};
+        let _kmem = super::super::kalloc::tests::kinit();
+        let _root = rootdisk();
+        ramdisk::mkroot();
+
+        userinit();
+        let id = os().initproc.lock().unwrap().0;
+        let p = &os().procs[id];
+        os().mycpu().proc.set(Some(id));
+        run_to_ecall(p);
+        assert_eq!(unsafe { (*p.tf.get()).a7 }, SYS_EXEC as u64);
+        unsafe { (*p.tf.get()).epc += 4 };
+        syscall();
+        assert_eq!(unsafe { (*p.tf.get()).a0 }, 1);
+        assert_eq!(p.procname(), "init");
+
+        run_to_ecall(p);
+        assert_eq!(unsafe { (*p.tf.get()).a7 }, SYS_WAIT as u64);
+        unsafe { (*p.tf.get()).epc += 4 };
+        syscall();
+        // no children yet.
+        assert_eq!(unsafe { (*p.tf.get()).a0 }, -1i64 as u64);
+        os().mycpu().proc.set(None);
+
+        os().iput(p.cwd.take().unwrap());
+        *os().initproc.lock() = None;
+        reap(id);
+    }
+
+    #[test]
+    fn myproc_follows_the_cpu() {
+        assert_eq!(os().myproc(), None);
+        os().mycpu().proc.set(Some(ProcId(5)));
+        assert_eq!(os().myproc(), Some(ProcId(5)));
+        // another hart is not running it.
+        assert_eq!(thread::spawn(|| os().myproc()).join().unwrap(), None);
+        os().mycpu().proc.set(None);
+    }
+
+    #[test]
+    fn wakeup_only_wakes_sleepers_on_chan() {
+        let chan = 0xdead_usize as *const ();
+        let (a, b) = (ProcId(params::NPROC - 1), ProcId(params::NPROC - 2));
+        {
+            let mut p = os().procs[a].lock.lock();
+            p.state = ProcState::Sleeping;
+            p.chan = chan as usize;
+        }
+        {
+            let mut p = os().procs[b].lock.lock();
+            p.state = ProcState::Sleeping;
+            p.chan = chan as usize + 1;
+        }
+        os().wakeup(chan);
+        assert!(os().procs[a].lock.lock().state == ProcState::Runnable);
+        assert!(os().procs[b].lock.lock().state == ProcState::Sleeping);
+        for id in [a, b].iter() {
+            *os().procs[*id].lock.lock() = ProcInner::new();
+        }
+    }
+
+    #[test]
+    fn sched_checks_invariants() {
+        let id = ProcId(params::NPROC - 3);
+        os().mycpu().proc.set(Some(id));
+
+        // p.lock must be the only lock held.
+        let other = SpinLock::new((), "other");
+        let r = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _o = other.lock();
+            let p = os().procs[id].lock.lock();
+            sched(&p);
+        }));
+        assert!(r.is_err());
+
+        // the process must have left the running state.
+        let r = panic::catch_unwind(|| {
+            let mut p = os().procs[id].lock.lock();
+            p.state = ProcState::Running;
+            sched(&p);
+        });
+        assert!(r.is_err());
+
+        assert_eq!(os().mycpu().noff.get(), 0);
+        *os().procs[id].lock.lock() = ProcInner::new();
+        os().mycpu().proc.set(None);
+    }
+}
@@ -0,0 +1,149 @@
+// ram disk: a block device kept in memory.
+//
+// the kernel uses one as the root disk until there is a disk
+// driver; tests use them as scratch disks.
+
+use super::disk::BlockDevice;
+use super::fs::{self, BSIZE};
+use super::spinlock::SpinLock;
+use super::stat::T_FILE;
+use super::state::os;
+#[cfg(target_os = "none")]
+use super::{disk, params};
+use core::ptr;
+
+pub struct RamDisk {
+    lock: SpinLock<()>, // serializes access to the blocks at base
+    base: *mut u8,
+    nblocks: u32,
+}
+
+// base is only touched while holding lock.
+unsafe impl Sync for RamDisk {}
+
+impl RamDisk {
+    // a disk of nblocks blocks stored at base, which must stay
+    // valid and unused by anything else for as long as the disk.
+    pub const unsafe fn new(base: *mut u8, nblocks: u32) -> RamDisk {
+        RamDisk {
+            lock: SpinLock::new((), "ramdisk"),
+            base,
+            nblocks,
+        }
+    }
+
+    fn block(&self, blockno: u32) -> *mut u8 {
+        if blockno >= self.nblocks {
+            panic!("ramdisk: blockno {}", blockno);
+        }
+        self.base.wrapping_add(blockno as usize * BSIZE)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn read_block(&self, blockno: u32, data: &mut [u8; BSIZE]) {
+        let _g = self.lock.lock();
+        unsafe { ptr::copy_nonoverlapping(self.block(blockno), data.as_mut_ptr(), BSIZE) };
+    }
+
+    fn write_block(&self, blockno: u32, data: &[u8; BSIZE]) {
+        let _g = self.lock.lock();
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.block(blockno), BSIZE) };
+    }
+
+    // memory is as durable as it gets.
+    fn flush(&self) {}
+
+    fn nblocks(&self) -> u32 {
+        self.nblocks
+    }
+}
+
+#[cfg(target_os = "none")]
+static mut ROOTMEM: [u8; params::FSSIZE * BSIZE] = [0; params::FSSIZE * BSIZE];
+
+#[cfg(target_os = "none")]
+static ROOTDISK: RamDisk =
+    unsafe { RamDisk::new(ptr::addr_of_mut!(ROOTMEM) as *mut u8, params::FSSIZE as u32) };
+
+// inodes in the root file system.
+#[cfg(target_os = "none")]
+const NINODES: u32 = 200;
+
+// /init, the program initcode execs.
+// assembled from asm/init.S.
+// od -t xC init
+#[rustfmt::skip]
+pub static INIT: [u8; 152] = [
+    0x7f, 0x45, 0x4c, 0x46, 0x02, 0x01, 0x01, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x02, 0x00, 0xf3, 0x00, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x38, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,
+    0x78, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x13, 0x05, 0x00, 0x00, 0x93, 0x08, 0x30, 0x00,
+    0x73, 0x00, 0x00, 0x00, 0xe3, 0x5a, 0x05, 0xfe,
+    0x13, 0x05, 0x40, 0x06, 0x93, 0x08, 0xd0, 0x00,
+    0x73, 0x00, 0x00, 0x00, 0x6f, 0xf0, 0x5f, 0xfe,
+];
+
+// make the ram disk the root device, with a file system
+// holding /init.
+#[cfg(target_os = "none")]
+pub fn init() {
+    disk::register(params::ROOTDEV as u32, &ROOTDISK);
+    fs::mkfs(params::ROOTDEV as u32, NINODES);
+    mkroot();
+}
+
+// put /init on the empty file system on the root device.
+pub fn mkroot() {
+    let ip = fs::create(b"/init", T_FILE, 0).expect("mkroot: /init");
+    let mut d = os().ilock(ip);
+    let src = INIT.as_ptr() as u64;
+    if os().writei(ip, &mut d, false, src, 0, INIT.len() as u32) != Ok(INIT.len() as u32) {
+        panic!("mkroot: write /init");
+    }
+    os().iunlock(ip, d);
+    os().iput(ip);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::panic;
+
+    // a ram disk of nblocks blocks on the host heap.
+    pub fn ramdisk(nblocks: u32) -> &'static RamDisk {
+        let mem = vec![0u8; nblocks as usize * BSIZE].leak();
+        Box::leak(Box::new(unsafe { RamDisk::new(mem.as_mut_ptr(), nblocks) }))
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let d = ramdisk(4);
+        d.write_block(3, &[7; BSIZE]);
+        let mut data = [0; BSIZE];
+        d.read_block(3, &mut data);
+        assert_eq!(data, [7; BSIZE]);
+        d.read_block(2, &mut data);
+        assert_eq!(data, [0; BSIZE]);
+        assert_eq!(d.nblocks(), 4);
+    }
+
+    #[test]
+    fn out_of_range_panics() {
+        let d = ramdisk(4);
+        let r = panic::catch_unwind(panic::AssertUnwindSafe(|| d.write_block(4, &[0; BSIZE])));
+        assert!(r.is_err());
+    }
+}
@@ -17,20 +17,114 @@
 
 // more references are at the bottom ...
 
-// control and status registers
+#![allow(non_snake_case)]
+
+// every instruction the kernel needs goes through the macros below.
+// on riscv64 they expand to inline asm. anywhere else they talk to the
+// simulated hart in sim.rs, so the kernel core builds and runs its
+// tests on the host.
+#[cfg(not(target_arch = "riscv64"))]
+pub mod sim;
+
+// read a control and status register.
+#[cfg(target_arch = "riscv64")]
+macro_rules! csrr {
+    ($csr:literal) => {{
+        let x: u64;
+        unsafe {
+            core::arch::asm!(concat!("csrr {}, ", $csr), out(reg) x);
+        }
+        x
+    }};
+}
+
+// write a control and status register.
+#[cfg(target_arch = "riscv64")]
+macro_rules! csrw {
+    ($csr:literal, $x:expr) => {
+        unsafe {
+            core::arch::asm!(concat!("csrw ", $csr, ", {}"), in(reg) $x);
+        }
+    };
+}
+
+// read a general purpose register.
+#[cfg(target_arch = "riscv64")]
+macro_rules! regr {
+    ($reg:literal) => {{
+        let x: u64;
+        unsafe {
+            core::arch::asm!(concat!("mv {}, ", $reg), out(reg) x);
+        }
+        x
+    }};
+}
+
+// write a general purpose register.
+#[cfg(target_arch = "riscv64")]
+macro_rules! regw {
+    ($reg:literal, $x:expr) => {
+        unsafe {
+            core::arch::asm!(concat!("mv ", $reg, ", {}"), in(reg) $x);
+        }
+    };
+}
+
+// a single instruction without operands.
+#[cfg(target_arch = "riscv64")]
+macro_rules! insn {
+    ($insn:literal) => {
+        unsafe {
+            core::arch::asm!($insn);
+        }
+    };
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+macro_rules! csrr {
+    ($csr:literal) => {
+        $crate::riscv::sim::csr_read($csr)
+    };
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+macro_rules! csrw {
+    ($csr:literal, $x:expr) => {
+        $crate::riscv::sim::csr_write($csr, $x)
+    };
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+macro_rules! regr {
+    ($reg:literal) => {
+        $crate::riscv::sim::reg_read($reg)
+    };
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+macro_rules! regw {
+    ($reg:literal, $x:expr) => {
+        $crate::riscv::sim::reg_write($reg, $x)
+    };
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+macro_rules! insn {
+    ($insn:literal) => {
+        $crate::riscv::sim::insn($insn)
+    };
+}
+
+// control and status registers.
+// the full set xv6's riscv.h knows about, not only the ones in use.
+#[allow(dead_code)]
 pub mod CSR {
 
     // read hart id
     pub mod MHARTID {
         #[inline]
         pub fn read() -> u64 {
-            let mut x: u64;
-            unsafe {
-                asm!("csrr {}, mhartid",
-                 out(reg) x,
-                 options(readonly));
-            }
-            x
+            csrr!("mhartid")
         }
     }
 
@@ -39,24 +133,17 @@ pub mod CSR {
         pub const MPP_MASK: u64 = 3 << 11; // pp: previous mode.
         pub const MPP_M: u64 = 3 << 11; // set pp to machine-mode.
         pub const MPP_S: u64 = 1 << 11; // set pp to supervisor-mode.
+        #[allow(clippy::identity_op)]
         pub const MPP_U: u64 = 0 << 11; // set pp to user-mode.
         pub const MIE: u64 = 1 << 3; // machine mode iterrupt enable.
         #[inline]
         pub fn read() -> u64 {
-            let mut x: u64;
-            unsafe {
-                asm!("cssr {}, mstatus",
-                 out(reg) x,
-                 options(readonly));
-            }
-            x
+            csrr!("mstatus")
         }
 
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw mstatus, {}", in(reg) x);
-            }
+            csrw!("mstatus", x);
         }
     }
 
@@ -66,9 +153,7 @@ pub mod CSR {
     pub mod MEPC {
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw mepc, {}", in(reg) x);
-            }
+            csrw!("mepc", x);
         }
     }
 
@@ -81,18 +166,12 @@ pub mod CSR {
         pub const UIE: u64 = 1 << 0; // user interrupt enable
         #[inline]
         pub fn read() -> u64 {
-            let mut x: u64 = 0;
-            unsafe {
-                asm!("csrr {}, sstatus", out(reg) x);
-            }
-            x
+            csrr!("sstatus")
         }
 
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw sstatus, {}", in(reg) x);
-            }
+            csrw!("sstatus", x);
         }
     }
 
@@ -100,18 +179,12 @@ pub mod CSR {
     pub mod SIP {
         #[inline]
         pub fn read() -> u64 {
-            let mut x: u64;
-            unsafe {
-                asm!("csrr {}, sip", out(reg) x);
-            }
-            x
+            csrr!("sip")
         }
 
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw sip, {}", in(reg) x);
-            }
+            csrw!("sip", x);
         }
     }
 
@@ -123,18 +196,12 @@ pub mod CSR {
 
         #[inline]
         pub fn read() -> u64 {
-            let mut x: u64;
-            unsafe {
-                asm!("csrr {}, sie", out(reg) x);
-            }
-            x
+            csrr!("sie")
         }
 
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw sie, {}", in(reg) x);
-            }
+            csrw!("sie", x);
         }
     }
 
@@ -146,18 +213,12 @@ pub mod CSR {
 
         #[inline]
         pub fn read() -> u64 {
-            let mut x: u64;
-            unsafe {
-                asm!("csrr {}, mie", out(reg) x);
-            }
-            x
+            csrr!("mie")
         }
 
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw mie, {}", in(reg) x);
-            }
+            csrw!("mie", x);
         }
     }
 
@@ -167,18 +228,12 @@ pub mod CSR {
     pub mod SEPC {
         #[inline]
         pub fn read() -> u64 {
-            let mut x: u64;
-            unsafe {
-                asm!("csrr {}, sepc", out(reg) x);
-            }
-            x
+            csrr!("sepc")
         }
 
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw sepc, {}", in(reg) x);
-            }
+            csrw!("sepc", x);
         }
     }
 
@@ -186,36 +241,24 @@ pub mod CSR {
     pub mod MEDELEG {
         #[inline]
         pub fn read() -> u64 {
-            let mut x: u64;
-            unsafe {
-                asm!("csrr {}, medeleg", out(reg) x);
-            }
-            x
+            csrr!("medeleg")
         }
 
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw medeleg, {}", in(reg) x);
-            }
+            csrw!("medeleg", x);
         }
     }
 
     // machine interrupt delegation
     pub mod MIDELEG {
         pub fn read() -> u64 {
-            let mut x: u64;
-            unsafe {
-                asm!("csrr {}, mideleg", out(reg) x);
-            }
-            x
+            csrr!("mideleg")
         }
 
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw mideleg, {}", in(reg) x);
-            }
+            csrw!("mideleg", x);
         }
     }
 
@@ -223,18 +266,12 @@ pub mod CSR {
     // low two bits ar mode.
     pub mod STVEC {
         pub fn read() -> u64 {
-            let mut x: u64;
-            unsafe {
-                asm!("csrr {}, stvec", out(reg) x);
-            }
-            x
+            csrr!("stvec")
         }
 
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw stvec, {}", in(reg) x);
-            }
+            csrw!("stvec", x);
         }
     }
 
@@ -242,9 +279,7 @@ pub mod CSR {
     pub mod MTVEC {
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw mtvec, {}", in(reg) x);
-            }
+            csrw!("mtvec", x);
         }
     }
 
@@ -260,18 +295,12 @@ pub mod CSR {
         // supervisor address translation and protection.
         // holds the address of the page table.
         pub fn read() -> u64 {
-            let mut x: u64;
-            unsafe {
-                asm!("csrr {}, satp", out(reg) x);
-            }
-            x
+            csrr!("satp")
         }
 
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw satp, {}", in(reg) x);
-            }
+            csrw!("satp", x);
         }
     }
 
@@ -279,16 +308,12 @@ pub mod CSR {
     pub mod SSCRATCH {
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw sscratch, {}", in(reg) x);
-            }
+            csrw!("sscratch", x);
         }
 
         #[inline]
-        pub fn read(x: u64) {
-            unsafe {
-                asm!("csrw mscratch, {}", in(reg) x);
-            }
+        pub fn read() -> u64 {
+            csrr!("sscratch")
         }
     }
 
@@ -296,11 +321,7 @@ pub mod CSR {
     pub mod SCAUSE {
         #[inline]
         pub fn read() -> u64 {
-            let mut x: u64 = 0;
-            unsafe {
-                asm!("csrr {}, scause", out(reg) x);
-            }
-            x
+            csrr!("scause")
         }
     }
 
@@ -308,11 +329,37 @@ pub mod CSR {
     pub mod STVAL {
         #[inline]
         pub fn read() -> u64 {
-            let mut x: u64 = 0;
-            unsafe {
-                asm!("csrr {}, stval", out(reg) x);
-            }
-            x
+            csrr!("stval")
+        }
+    }
+
+    // machine-mode scratch register, for the timer interrupt handler.
+    pub mod MSCRATCH {
+        #[inline]
+        pub fn write(x: u64) {
+            csrw!("mscratch", x);
+        }
+    }
+
+    // physical memory protection.
+    // address register 0 holds the top of the region (shifted right 2),
+    // config register 0 its permissions and address matching mode.
+    pub mod PMPADDR0 {
+        #[inline]
+        pub fn write(x: u64) {
+            csrw!("pmpaddr0", x);
+        }
+    }
+
+    pub mod PMPCFG0 {
+        pub const R: u64 = 1 << 0; // readable
+        pub const W: u64 = 1 << 1; // writable
+        pub const X: u64 = 1 << 2; // executable
+        pub const TOR: u64 = 1 << 3; // top of range: 0 <= addr < pmpaddr0
+
+        #[inline]
+        pub fn write(x: u64) {
+            csrw!("pmpcfg0", x);
         }
     }
 
@@ -320,29 +367,19 @@ pub mod CSR {
     pub mod MCOUNTEREN {
         #[inline]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("csrw mcounteren, {}", in(reg) x);
-            }
+            csrw!("mcounteren", x);
         }
 
         #[inline]
         pub fn read() -> u64 {
-            let mut x: u64 = 0;
-            unsafe {
-                asm!("csrr {}, mcounteren", out(reg) x);
-            }
-            x
+            csrr!("mcounteren")
         }
     }
 
     // machine mode cycle counter
     pub mod TIME {
         pub fn read() -> u64 {
-            let mut x: u64 = 0;
-            unsafe {
-                asm!("csrr {}, time", out(reg) x);
-            }
-            x
+            csrr!("time")
         }
     }
 }
@@ -369,13 +406,10 @@ pub mod DEV_INTR {
 }
 
 pub mod REGS {
+    #[allow(dead_code)]
     pub mod SP {
         pub fn read() -> u64 {
-            let mut x: u64 = 0;
-            unsafe {
-                asm!("mv {}, sp", out(reg) x);
-            }
-            x
+            regr!("sp")
         }
     }
 
@@ -383,27 +417,19 @@ pub mod REGS {
         // read and write tp, the thread pointer
         // tp holds this core's hartid, the index into cpus[].
         pub fn read() -> u64 {
-            let mut x: u64 = 0;
-            unsafe {
-                asm!("mv {}, tp", out(reg) x);
-            }
-            x
+            regr!("tp")
         }
 
+        #[cfg_attr(not(target_os = "none"), allow(dead_code))]
         pub fn write(x: u64) {
-            unsafe {
-                asm!("mv tp, {}", in(reg) x);
-            }
+            regw!("tp", x);
         }
     }
 
+    #[allow(dead_code)]
     pub mod RA {
         pub fn read() -> u64 {
-            let mut x: u64 = 0;
-            unsafe {
-                asm!("mv {}, ra", out(reg) x);
-            }
-            x
+            regr!("ra")
         }
     }
 }
@@ -411,12 +437,24 @@ pub mod REGS {
 pub mod FENCE {
     // flush the TLB.
     pub fn sfence_vma() {
-        unsafe {
-            asm!("sfence.vma zero, zero");
-        }
+        insn!("sfence.vma zero, zero");
     }
 }
 
+// wait for interrupt. idle the hart until one is pending.
+#[inline]
+pub fn wfi() {
+    insn!("wfi");
+}
+
+// return from machine mode to the mode in mstatus.MPP, at mepc.
+#[inline]
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn mret() -> ! {
+    insn!("mret");
+    unreachable!("mret");
+}
+
 pub mod PG {
     pub const SIZE: u64 = 4096;
     pub const SHIFT: u64 = 12;
@@ -434,10 +472,10 @@ pub mod PG {
 
 pub mod PTE {
     pub const V: u64 = 1 << 0; // valid
-    pub const R: u64 = 1 << 1; // valid
-    pub const W: u64 = 1 << 2; // valid
-    pub const X: u64 = 1 << 3; // valid
-    pub const U: u64 = 1 << 4; // valid
+    pub const R: u64 = 1 << 1; // readable
+    pub const W: u64 = 1 << 2; // writable
+    pub const X: u64 = 1 << 3; // executable
+    pub const U: u64 = 1 << 4; // user can access
 
     // shift a physical address to the right place for a PTE.
     #[inline]
@@ -488,55 +526,90 @@ pub const MAXVA: u64 = 1 << (9 + 9 + 9 + 12 - 1);
 //  where op is an binary operation based on amo instruction used.
 
 pub mod SYNC {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
     // atomic swap wrapper mimic gcc extension.
     // write value into p, and returns the previous value of p;
     // .aq bit set to ensure no other thread will observe the
     // AMO operation after AMO memory access.
-    pub fn lock_test_and_set(p: &mut bool, value: bool) -> bool {
-        let prevp: bool = *p;
-        unsafe {
-            asm!(
-                "
-                li t0, {value}
-                lw s1, {p}
-                amoswap.w.aq t0, t0, (s1)
-                 ",
-                 p = inout(reg) p,  // address
-                 value = const value as u64
-            );
-        }
-        prevp
+    // an Acquire swap compiles to amoswap.w.aq on riscv.
+    #[inline]
+    pub fn lock_test_and_set(p: &AtomicBool, value: bool) -> bool {
+        p.swap(value, Ordering::Acquire)
     }
 
     // sync all operations.
-    // fence rwio, rwio is a conservative fence, it
+    // fence iorw, iorw is a conservative fence, it
     // will sync both memory access and device io operations.
     // more to read: https://github.com/riscv/riscv-gcc/pull/55
     pub fn synchronize() {
-        unsafe {
-            asm!("fence rwio, rwio");
-        }
+        insn!("fence iorw, iorw");
     }
 
     // Release the lock.
     // same as spinlock.locked = 0;
     // .rl bit set to ensure threads will not
     // observe the AMO operation before the AMO memory access.
-    pub fn lock_release(p: &mut bool) {
-        unsafe {
-            asm!("la s1 {0}
-                  amoswap.w.rl zero, zero, (s1)",
-                  in(reg) *p as u64);
-        }
+    #[inline]
+    pub fn lock_release(p: &AtomicBool) {
+        p.store(false, Ordering::Release);
+    }
+
+    // peek at the lock word without taking it.
+    #[inline]
+    pub fn lock_held(p: &AtomicBool) -> bool {
+        p.load(Ordering::Relaxed)
     }
 }
 
-pub type Pte = u64;
+// a page table entry: physical page number in bits 10..54,
+// PTE flags in the low 10 bits.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+#[repr(transparent)]
+pub struct Pte(pub u64);
+
+impl Pte {
+    #[inline]
+    pub fn new(pa: u64, flags: u64) -> Pte {
+        Pte(PTE::pa2pte(pa) | flags)
+    }
+
+    #[inline]
+    pub fn is_valid(self) -> bool {
+        self.0 & PTE::V != 0
+    }
+
+    // a valid pte with none of R, W, X set points to
+    // the next level page table.
+    #[inline]
+    pub fn is_leaf(self) -> bool {
+        self.0 & (PTE::R | PTE::W | PTE::X) != 0
+    }
+
+    #[inline]
+    pub fn pa(self) -> u64 {
+        PTE::pte2pa(self.0)
+    }
+
+    #[inline]
+    pub fn flags(self) -> u64 {
+        PTE::pte_flags(self.0)
+    }
+}
+
+// a page table page, which must itself be page aligned.
+#[repr(C, align(4096))]
+pub struct Pagetable(pub [Pte; 512]); // 512 PTEs
+
+impl Pagetable {
+    pub const fn new() -> Pagetable {
+        Pagetable([Pte(0); 512])
+    }
+}
 
-pub struct Pagetable ([u64; 512]); // 512 PTEs
 impl Default for Pagetable {
     fn default() -> Self {
-        Pagetable([0; 512])
+        Pagetable::new()
     }
 }
 
@@ -0,0 +1,103 @@
+// simulated hart for hosted builds.
+//
+// every host thread that runs kernel code behaves like a hart of its
+// own: it claims a hartid the first time it touches the hart and gives
+// it back when the thread exits, so at most params::NCPU threads are
+// inside the kernel at once and each one owns its cpus[] slot. a thread
+// that finds every hart busy waits for one to be released.
+//
+// csrs and registers are private to the hart. nothing is connected to
+// them, so writing mtvec or satp only remembers the value; it's enough
+// for the interrupt-enable bits push_off/pop_off rely on, and for tp.
+
+use crate::params::NCPU;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+static HARTS: Mutex<[bool; NCPU]> = Mutex::new([false; NCPU]);
+static HART_FREED: Condvar = Condvar::new();
+
+struct Hart {
+    id: u64,
+    tp: Cell<u64>,
+    csrs: RefCell<HashMap<&'static str, u64>>,
+    boot: Instant,
+}
+
+impl Hart {
+    fn claim() -> Hart {
+        let mut busy = HARTS.lock().unwrap();
+        loop {
+            if let Some(id) = busy.iter().position(|b| !b) {
+                busy[id] = true;
+                return Hart {
+                    id: id as u64,
+                    tp: Cell::new(id as u64),
+                    csrs: RefCell::new(HashMap::new()),
+                    boot: Instant::now(),
+                };
+            }
+            busy = HART_FREED.wait(busy).unwrap();
+        }
+    }
+}
+
+impl Drop for Hart {
+    fn drop(&mut self) {
+        // a panicking test may still hold HARTS' guard poisoned.
+        let mut busy = HARTS.lock().unwrap_or_else(|e| e.into_inner());
+        busy[self.id as usize] = false;
+        HART_FREED.notify_one();
+    }
+}
+
+thread_local! {
+    static HART: Hart = Hart::claim();
+}
+
+pub fn csr_read(csr: &'static str) -> u64 {
+    HART.with(|h| match csr {
+        "mhartid" => h.id,
+        // qemu's virt machine ticks time at 10MHz.
+        "time" => h.boot.elapsed().as_nanos() as u64 / 100,
+        _ => *h.csrs.borrow().get(csr).unwrap_or(&0),
+    })
+}
+
+pub fn csr_write(csr: &'static str, x: u64) {
+    HART.with(|h| {
+        h.csrs.borrow_mut().insert(csr, x);
+    })
+}
+
+pub fn reg_read(reg: &'static str) -> u64 {
+    match reg {
+        "tp" => HART.with(|h| h.tp.get()),
+        "sp" => {
+            let here = 0u8;
+            &here as *const u8 as u64
+        }
+        "ra" => 0,
+        _ => panic!("sim: can't read register {}", reg),
+    }
+}
+
+pub fn reg_write(reg: &'static str, x: u64) {
+    match reg {
+        "tp" => HART.with(|h| h.tp.set(x)),
+        _ => panic!("sim: can't write register {}", reg),
+    }
+}
+
+pub fn insn(insn: &'static str) {
+    match insn {
+        "fence iorw, iorw" => fence(Ordering::SeqCst),
+        // no simulated page tables, nothing to flush.
+        "sfence.vma zero, zero" => {}
+        "wfi" => std::thread::yield_now(),
+        _ => panic!("sim: can't execute {}", insn),
+    }
+}
@@ -1,58 +1,121 @@
 // long  term lock for processes
-use super::proc::{OSFetch, State};
+//
+// a SleepLock<T> owns the data it protects, like SpinLock<T>, but a
+// process that finds it held goes to sleep instead of spinning, so it
+// can be held across long operations such as disk io. the lock may
+// only be taken from process context (there must be a myproc()).
 use super::spinlock::SpinLock;
+use super::state::os;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
 
+// who holds a sleep lock, protected by SleepLock::lk.
 #[derive(Default)]
-pub struct SleepLock<'a> {
-    os: Option<*mut State<'a>>,
-    pub locked: bool,     // is the lock held?
-    pub lk: SpinLock<'a>, // spinlock protecting this sleep lock.
+struct Holder {
+    locked: bool, // is the lock held?
+    pid: i32,     // process holding lock
+}
+
+pub struct SleepLock<T: ?Sized> {
+    lk: SpinLock<Holder>, // spinlock protecting this sleep lock.
 
     // debug
-    pub name: &'a str,
-    pub pid: i32,
+    #[allow(dead_code)]
+    pub name: &'static str,
+
+    data: UnsafeCell<T>,
+}
+
+// data is only touched by the holder of the lock.
+unsafe impl<T: ?Sized + Send> Sync for SleepLock<T> {}
+
+// holding the guard means holding the lock.
+pub struct SleepLockGuard<'a, T: ?Sized> {
+    lock: &'a SleepLock<T>,
 }
 
-impl<'a> OSFetch<'a> for SleepLock<'a> {
-    fn get_os(&mut self) -> Option<*mut State<'a>> {
-        self.os
+impl<T: Default> Default for SleepLock<T> {
+    fn default() -> Self {
+        SleepLock::new(Default::default(), "")
     }
 }
 
-impl<'a> SleepLock<'a> {
-    pub fn new(name: &'a str, os: Option<*mut State<'a>>) -> SleepLock<'a> {
+impl<T> SleepLock<T> {
+    pub const fn new(data: T, name: &'static str) -> SleepLock<T> {
         SleepLock {
             name,
-            lk: SpinLock::new("sleep lock", os),
-            locked: false,
-            pid: 0,
-            os,
+            lk: SpinLock::new(
+                Holder {
+                    locked: false,
+                    pid: 0,
+                },
+                "sleep lock",
+            ),
+            data: UnsafeCell::new(data),
         }
     }
+}
 
-    pub fn acquire(&mut self) {
-        self.lk.acquire();
-        while self.lk.locked {
-            self.proc_ref_mut().lock.acquire();
-            self.lk.release();
+impl<T: ?Sized> SleepLock<T> {
+    // acquire the lock, sleeping until it is free.
+    pub fn lock(&self) -> SleepLockGuard<'_, T> {
+        let mut holder = self.lk.lock();
+        while holder.locked {
+            holder = os().sleep(self.chan(), holder);
         }
-        self.lk.locked = true;
-        self.pid = self.proc_ref().pid;
-        self.lk.release();
+        holder.locked = true;
+        holder.pid = mypid();
+        SleepLockGuard { lock: self }
+    }
+
+    // is the current process holding the lock?
+    pub fn holding(&self) -> bool {
+        let holder = self.lk.lock();
+        holder.locked && holder.pid == mypid()
     }
 
-    pub fn release(&mut self) {
-        self.lk.acquire();
-        self.locked = false;
-        self.pid = 0;
-        self.os_ref_mut().wakeup(&self);
-        self.lk.release();
+    // access the data without the lock, when the caller can prove
+    // nobody else is using it.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_mut_unchecked(&self) -> &mut T {
+        &mut *self.data.get()
     }
 
-    pub fn holding(&mut self) -> bool {
-        self.lk.acquire();
-        let r: bool = self.locked && (self.pid == self.proc_ref_mut().pid);
-        self.lk.release();
-        r
+    fn release(&self) {
+        let mut holder = self.lk.lock();
+        holder.locked = false;
+        holder.pid = 0;
+        os().wakeup(self.chan());
+    }
+
+    // processes waiting for the lock sleep on its address.
+    fn chan(&self) -> *const () {
+        self as *const Self as *const ()
+    }
+}
+
+impl<T: ?Sized> Deref for SleepLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SleepLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SleepLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+    }
+}
+
+fn mypid() -> i32 {
+    match os().myproc() {
+        Some(p) => os().procs[p].lock.lock().pid,
+        None => 0,
     }
 }
@@ -1,89 +1,185 @@
-use super::proc::{Cpu, OSFetch, State};
+// mutual exclusion spin locks.
+//
+// a SpinLock<T> owns the data it protects. the only way to reach the
+// data is through the guard returned by lock(), and dropping the guard
+// releases the lock, so the data can't be touched without holding it.
+use super::proc::{CpuId, State};
 use super::riscv;
+use super::state::os;
+use core::cell::{Cell, UnsafeCell};
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-#[derive(Default)]
-pub struct SpinLock<'a> {
-    os: Option<*mut State<'a>>,
-    pub locked: bool,
-    pub name: &'a str,
-    pub cpu: Option<&'a mut Cpu<'a>>,
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    pub name: &'static str,
+    cpu: Cell<Option<CpuId>>, // the cpu holding the lock.
+
+    // contention counters, for measuring lock hot spots.
+    nacquire: AtomicU64, // times the lock was acquired
+    nspin: AtomicU64,    // failed test-and-sets while acquiring
+    data: UnsafeCell<T>,
+}
+
+// snapshot of a lock's contention counters.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct LockStats {
+    pub nacquire: u64,
+    pub nspin: u64,
+}
+
+impl core::ops::Add for LockStats {
+    type Output = LockStats;
+    fn add(self, o: LockStats) -> LockStats {
+        LockStats {
+            nacquire: self.nacquire + o.nacquire,
+            nspin: self.nspin + o.nspin,
+        }
+    }
+}
+
+// cpu and data are only touched by the holder of the lock.
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+// holding the guard means holding the lock.
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        SpinLock::new(Default::default(), "")
+    }
 }
 
 // identity check. Compare pointer since rust doesn't have
 // direct idenity
 // you only care if two spinlock are the same lock.
-impl<'a> PartialEq for SpinLock<'a> {
+impl<T: ?Sized> PartialEq for SpinLock<T> {
     fn eq(&self, other: &Self) -> bool {
-        self as *const _ == other as *const _
+        ptr::eq(self, other)
     }
 }
 
-impl<'a> OSFetch<'a> for SpinLock<'a> {
-    fn get_os(&mut self) -> Option<*mut State<'a>> {
-        self.os
-    }
-}
-
-impl<'a> SpinLock<'a> {
-    pub fn new(name: &'a str, os: Option<*mut State<'a>>) -> SpinLock<'a> {
+impl<T> SpinLock<T> {
+    pub const fn new(data: T, name: &'static str) -> SpinLock<T> {
         SpinLock {
             name,
-            locked: false,
-            cpu: None,
-            os,
+            locked: AtomicBool::new(false),
+            cpu: Cell::new(None),
+            nacquire: AtomicU64::new(0),
+            nspin: AtomicU64::new(0),
+            data: UnsafeCell::new(data),
         }
     }
+}
 
+impl<T: ?Sized> SpinLock<T> {
     // acquire the lock.
     // Loops (spins) until the lock is acquired.
-    pub fn acquire(&mut self) {
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        self.acquire();
+        SpinLockGuard { lock: self }
+    }
+
+    // check whether this cpu is holding the lock.
+    pub fn holding(&self) -> bool {
+        let mut r = false;
+        os().push_off()
+            .sdo(|state| {
+                let is_same_cpu = self.cpu.get() == Some(state.cpuid());
+                r = riscv::SYNC::lock_held(&self.locked) && is_same_cpu;
+            })
+            .pop_off();
+        r
+    }
+
+    // read the contention counters.
+    pub fn stats(&self) -> LockStats {
+        LockStats {
+            nacquire: self.nacquire.load(Ordering::Relaxed),
+            nspin: self.nspin.load(Ordering::Relaxed),
+        }
+    }
+
+    // access the data without the lock, when the caller can prove
+    // nobody else is using it (e.g. during boot on a single hart).
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_mut_unchecked(&self) -> &mut T {
+        &mut *self.data.get()
+    }
+
+    // release a lock that was acquired on the other side of a context
+    // switch and whose guard is therefore not on this stack.
+    pub unsafe fn force_unlock(&self) {
+        self.release();
+    }
+
+    fn acquire(&self) {
         // disable interrupts to avoid deadlock.
-        self.os_ref_mut().push_off();
+        os().push_off();
         if self.holding() {
-            panic!("acquire");
+            panic!("acquire {}", self.name);
         }
 
         // on risc-v sync_lock_test_and_set turns into an atomic swap.
         // this is why it is called spinlock.
-        while riscv::SYNC::lock_test_and_set(&mut self.locked, true) {}
+        while riscv::SYNC::lock_test_and_set(&self.locked, true) {
+            self.nspin.fetch_add(1, Ordering::Relaxed);
+        }
+        self.nacquire.fetch_add(1, Ordering::Relaxed);
 
         riscv::SYNC::synchronize();
-        self.cpu = Some(self.cpu_ref_mut());
+        self.cpu.set(Some(os().cpuid()));
     }
 
     // release the lock
-    pub fn release(&mut self) {
+    fn release(&self) {
         if !self.holding() {
-            panic!("release");
+            panic!("release {}", self.name);
         }
-        self.cpu = None;
+        self.cpu.set(None);
 
         // this turns into a fence instruction in riscv.
         riscv::SYNC::synchronize();
 
-        riscv::SYNC::lock_release(&mut self.locked);
-        self.os_ref_mut().pop_off();
+        riscv::SYNC::lock_release(&self.locked);
+        os().pop_off();
     }
+}
 
-    // check whether this cpu is holding the lock.
-    pub fn holding(&mut self) -> bool {
-        let mut r = false;
-        self.os_ref_mut()
-            .push_off()
-            .sdo(|state| {
-                let isSameCpu = self
-                    .cpu
-                    .as_mut()
-                    .map(|val| {
-                        let this = state.cpu_ref() as *const Cpu as *const ();
-                        let other = val as &mut Cpu as *const Cpu as *const ();
-                        this == other
-                    })
-                    .unwrap_or(false);
-                r = self.locked && isSameCpu;
-            })
-            .pop_off();
-        r
+impl<'a, T: ?Sized> SpinLockGuard<'a, T> {
+    // release the lock, handing back the lock itself so it can be
+    // reacquired later (see State::sleep).
+    pub fn unlock(self) -> &'a SpinLock<T> {
+        let lock = self.lock;
+        drop(self);
+        lock
+    }
+
+    // the lock this guard holds.
+    pub fn spinlock(&self) -> &'a SpinLock<T> {
+        self.lock
+    }
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release();
     }
 }
 
@@ -91,38 +187,128 @@ impl<'a> SpinLock<'a> {
 // matched:
 // it takes two pop_off() to undo two push_off()s. Also, if interrrupts
 // are initially off, then push_off, pop_off leaves them off.
-impl<'a> State<'a> {
-    pub fn push_off(&mut self) -> &mut Self {
+impl State {
+    pub fn push_off(&self) -> &Self {
         let old = riscv::DEV_INTR::get();
         riscv::DEV_INTR::off();
-        if self.cpu_ref().noff == 0 {
-            self.cpu_ref_mut().intena = old;
+        let c = self.mycpu();
+        if c.noff.get() == 0 {
+            c.intena.set(old);
         }
-        self.cpu_ref_mut().noff += 1;
+        c.noff.set(c.noff.get() + 1);
         self
     }
 
-    pub fn pop_off(&mut self) -> &mut Self {
-        let c = self.cpu_ref_mut();
+    pub fn pop_off(&self) -> &Self {
         if riscv::DEV_INTR::get() {
             panic!("pop off - interruptible");
         }
-        c.noff -= 1;
-        if c.noff < 0 {
+        let c = self.mycpu();
+        c.noff.set(c.noff.get() - 1);
+        if c.noff.get() < 0 {
             panic!("pop off");
         }
-        if c.noff == 0 && c.intena {
+        if c.noff.get() == 0 && c.intena.get() {
             riscv::DEV_INTR::on();
         }
         self
     }
 
     // perform side effects here.
-    pub fn sdo<F>(&mut self, f: F) -> &mut Self
+    pub fn sdo<F>(&self, f: F) -> &Self
     where
-        F: FnOnce(&mut Self) -> (),
+        F: FnOnce(&Self),
     {
         f(self);
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn lock_disables_interrupts_until_released() {
+        let lock = SpinLock::new(0, "test");
+        riscv::DEV_INTR::on();
+        {
+            let mut g = lock.lock();
+            *g += 1;
+            assert!(!riscv::DEV_INTR::get());
+            assert!(lock.holding());
+        }
+        assert!(riscv::DEV_INTR::get());
+        assert!(!lock.holding());
+        assert_eq!(*lock.lock(), 1);
+    }
+
+    #[test]
+    fn nested_locks_restore_interrupts_on_last_release() {
+        let a = SpinLock::new((), "a");
+        let b = SpinLock::new((), "b");
+        riscv::DEV_INTR::on();
+        let ga = a.lock();
+        let gb = b.lock();
+        assert_eq!(os().mycpu().noff.get(), 2);
+        drop(gb);
+        assert!(!riscv::DEV_INTR::get());
+        drop(ga);
+        assert_eq!(os().mycpu().noff.get(), 0);
+        assert!(riscv::DEV_INTR::get());
+    }
+
+    #[test]
+    fn interrupts_stay_off_if_they_were_off() {
+        let lock = SpinLock::new((), "test");
+        riscv::DEV_INTR::off();
+        drop(lock.lock());
+        assert!(!riscv::DEV_INTR::get());
+    }
+
+    #[test]
+    fn holding_is_per_cpu() {
+        let lock = Arc::new(SpinLock::new((), "test"));
+        let g = lock.lock();
+        let other = Arc::clone(&lock);
+        assert!(!thread::spawn(move || other.holding()).join().unwrap());
+        assert!(lock.holding());
+        drop(g);
+    }
+
+    #[test]
+    fn reacquire_panics() {
+        let lock = SpinLock::new((), "test");
+        let r = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _g = lock.lock();
+            let _h = lock.lock();
+        }));
+        assert!(r.is_err());
+        // the failed acquire pushed off before it noticed.
+        os().pop_off();
+        assert_eq!(os().mycpu().noff.get(), 0);
+    }
+
+    #[test]
+    fn harts_exclude_each_other() {
+        let counter = Arc::new(SpinLock::new(0u64, "counter"));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        *counter.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+        for w in workers {
+            w.join().unwrap();
+        }
+        assert_eq!(*counter.lock(), 4000);
+        assert_eq!(counter.stats().nacquire, 4001);
+    }
+}
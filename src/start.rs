@@ -1,2 +1,98 @@
+// first rust code run on each hart, still in machine mode.
+// entry.S jumps here on stack0.
+//
+// machine mode is only used to boot and to field timer interrupts,
+// everything else runs in supervisor mode. start() sets that up and
+// mrets into main().
 
+use super::memlayout::CLINT;
+use super::params::NCPU;
+use super::riscv;
+use super::riscv::CSR::*;
+use core::ptr;
 
+// entry.S needs one stack per cpu.
+#[repr(C, align(16))]
+pub struct Stack([u8; 4096 * NCPU]);
+
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static mut stack0: Stack = Stack([0; 4096 * NCPU]);
+
+// a scratch area per CPU for machine-mode timer interrupts.
+// layout is described at the top of kernelvec.S.
+static mut TIMER_SCRATCH: [[u64; 5]; NCPU] = [[0; 5]; NCPU];
+
+// cycles between timer interrupts; about 1/10th second in qemu.
+const INTERVAL: u64 = 1_000_000;
+
+extern "C" {
+    // in kernelvec.S
+    fn timervec();
+}
+
+#[no_mangle]
+pub extern "C" fn start() -> ! {
+    // set M Previous Privilege mode to Supervisor, for mret.
+    let x = MSTATUS::read() & !MSTATUS::MPP_MASK;
+    MSTATUS::write(x | MSTATUS::MPP_S);
+
+    // set M Exception Program Counter to main, for mret.
+    MEPC::write(super::main as *const () as u64);
+
+    // disable paging for now.
+    SATP::write(0);
+
+    // delegate all interrupts and exceptions to supervisor mode.
+    MEDELEG::write(0xffff);
+    MIDELEG::write(0xffff);
+    SIE::write(SIE::read() | SIE::SEIE | SIE::STIE | SIE::SSIE);
+
+    // configure Physical Memory Protection to give supervisor mode
+    // access to all of physical memory.
+    PMPADDR0::write(0x3fffffffffffff);
+    PMPCFG0::write(PMPCFG0::R | PMPCFG0::W | PMPCFG0::X | PMPCFG0::TOR);
+
+    // ask for clock interrupts.
+    timerinit();
+
+    // keep each CPU's hartid in its tp register, for cpuid().
+    riscv::REGS::TP::write(MHARTID::read());
+
+    // switch to supervisor mode and jump to main().
+    riscv::mret()
+}
+
+// arrange to receive timer interrupts.
+// they will arrive in machine mode at
+// at timervec in kernelvec.S,
+// which turns them into software interrupts for
+// devintr() in trap.rs.
+fn timerinit() {
+    // each CPU has a separate source of timer interrupts.
+    let id = MHARTID::read();
+
+    // ask the CLINT for a timer interrupt.
+    unsafe {
+        let mtime = ptr::read_volatile(CLINT::CLINT_MTIME as *const u64);
+        ptr::write_volatile(CLINT::clint_mtimecmp(id) as *mut u64, mtime + INTERVAL);
+    }
+
+    // prepare information in scratch[] for timervec.
+    // scratch[0..2] : space for timervec to save registers.
+    // scratch[3] : address of CLINT MTIMECMP register.
+    // scratch[4] : desired interval (in cycles) between timer interrupts.
+    let scratch = unsafe { &mut (*ptr::addr_of_mut!(TIMER_SCRATCH))[id as usize] };
+    scratch[3] = CLINT::clint_mtimecmp(id);
+    scratch[4] = INTERVAL;
+    MSCRATCH::write(scratch.as_mut_ptr() as u64);
+
+    // set the machine-mode trap handler.
+    MTVEC::write(timervec as *const () as u64);
+
+    // enable machine-mode interrupts.
+    MSTATUS::write(MSTATUS::read() | MSTATUS::MIE);
+
+    // enable machine-mode timer interrupts.
+    MIE::write(MIE::read() | MIE::MTIE);
+}
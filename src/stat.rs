@@ -0,0 +1,3 @@
+pub const T_DIR: u16 = 1; // Directory
+pub const T_FILE: u16 = 2; // File
+pub const T_DEVICE: u16 = 3; // Device
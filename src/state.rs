@@ -1,22 +1,18 @@
 use super::proc::State;
-use std::mem::replace;
 
 // toplevel singleton state manager.
 // hold all the state and can only have one instance.
-pub struct UnsafeRotonOS {
-    state: Option<State<'static>>,
-}
-
-impl UnsafeRotonOS {
-    pub unsafe fn take_state(&mut self) -> State<'static> {
-        let p = replace(&mut self.state, None);
-        p.unwrap()
-    }
-}
-
+#[allow(non_snake_case)]
 pub mod RotonOS {
     // singleton state of the entire os.
-    pub static mut ROTON_OS: super::UnsafeRotonOS = super::UnsafeRotonOS {
-        state: Some(super::State::new()),
-    };
+    pub static ROTON_OS: super::State = super::State::new();
+}
+
+// fetch the state of the os.
+// every hart shares the same State, so it is only ever handed out
+// by shared reference: anything mutable inside it sits behind a lock,
+// an atomic, or a per-cpu/per-process cell whose owner is documented
+// on the type.
+pub fn os() -> &'static State {
+    &RotonOS::ROTON_OS
 }
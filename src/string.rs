@@ -1,110 +1,183 @@
 // <string.h> style string library. can be called from c code directly.
-use std::ffi::c_void;
-use std::slice;
+// the symbols are only exported in the kernel image; on the host they
+// would shadow the libc routines the test harness itself relies on.
+use core::ffi::c_void;
 
 // c char is signed 8 bit integer.
 #[allow(non_camel_case_types)]
 pub type c_char = i8;
 
-#[no_mangle]
-pub extern "C" fn memset(dst: *mut c_void, c: c_char, n: usize) -> *mut c_void {
+// the loops below are written byte by byte on purpose: these are the
+// routines the compiler lowers copies into, so they can't use slice
+// copies themselves.
+
+#[cfg_attr(target_os = "none", no_mangle)]
+pub unsafe extern "C" fn memset(dst: *mut c_void, c: c_char, n: usize) -> *mut c_void {
     let cdst = dst as *mut c_char;
-    let n = n as isize;
-    (0..n).map(|i| unsafe {
-        *cdst.offset(i) = c;
-    });
+    for i in 0..n {
+        *cdst.add(i) = c;
+    }
     dst
 }
 
-#[no_mangle]
-pub extern "C" fn memcmp(v1: *const c_void, v2: *const c_void, n: usize) -> i32 {
-    let s1 = unsafe { slice::from_raw_parts(v1 as *const c_char, n) };
-    let s2 = unsafe { slice::from_raw_parts(v2 as *const c_char, n) };
-    for (a, b) in s1.iter().zip(s2.iter()) {
-        let diff = *a - *b;
-        if diff != 0 {
-            return diff as i32;
+// compare as unsigned char, like the c library does.
+#[cfg_attr(target_os = "none", no_mangle)]
+pub unsafe extern "C" fn memcmp(v1: *const c_void, v2: *const c_void, n: usize) -> i32 {
+    let s1 = v1 as *const u8;
+    let s2 = v2 as *const u8;
+    for i in 0..n {
+        let (a, b) = (*s1.add(i), *s2.add(i));
+        if a != b {
+            return a as i32 - b as i32;
         }
     }
     0
 }
 
-#[no_mangle]
-pub extern "C" fn memcpy(dst: *mut c_void, src: *const c_void, n: usize) -> *mut c_void {
-    let s1 = unsafe { slice::from_raw_parts_mut(dst as *mut c_char, n) };
-    let s2 = unsafe { slice::from_raw_parts_mut(src as *mut c_char, n) };
-    for (a, b) in s1.iter_mut().zip(s2.iter()) {
-        *a = *b;
-    }
-    dst
+#[cfg_attr(target_os = "none", no_mangle)]
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub unsafe extern "C" fn memcpy(dst: *mut c_void, src: *const c_void, n: usize) -> *mut c_void {
+    memmove(dst, src, n)
 }
 
-#[no_mangle]
-pub extern "C" fn memmove(dst: *mut c_void, src: *const c_void, n: usize) -> *mut c_void {
-    memcpy(dst, src, n)
-}
-
-#[no_mangle]
-pub extern "C" fn strlen(s: *const c_char) -> isize {
-    unsafe {
-        let mut n = 0;
-        while *s.offset(n) != 0 {
-            n += 1;
+// copy n bytes, the two regions may overlap.
+#[cfg_attr(target_os = "none", no_mangle)]
+pub unsafe extern "C" fn memmove(dst: *mut c_void, src: *const c_void, n: usize) -> *mut c_void {
+    let d = dst as *mut u8;
+    let s = src as *const u8;
+    if (s as usize) < (d as usize) && (s as usize) + n > (d as usize) {
+        // dst overlaps the tail of src, copy backwards.
+        let mut i = n;
+        while i > 0 {
+            i -= 1;
+            *d.add(i) = *s.add(i);
+        }
+    } else {
+        for i in 0..n {
+            *d.add(i) = *s.add(i);
         }
-        n
     }
+    dst
 }
 
-#[no_mangle]
-pub extern "C" fn strncpy(s: *mut c_char, t: *const c_char, n: i32) -> *const c_char {
-    if n <= 0 {
-        return s;
+#[cfg_attr(target_os = "none", no_mangle)]
+pub unsafe extern "C" fn strlen(s: *const c_char) -> isize {
+    let mut n = 0;
+    while *s.offset(n) != 0 {
+        n += 1;
     }
+    n
+}
 
-    let (ss, ts) = unsafe {
-        let ss = slice::from_raw_parts_mut(s, n as usize);
-        let ts = slice::from_raw_parts(t, n as usize);
-        (ss, ts)
-    };
-
-    ss.iter_mut().zip(ts).map(|(e, ch)| {
-        *e = *ch;
-    });
+// copy at most n chars of t, padding s with NUL if t is shorter.
+// s is not NUL terminated if t has n or more chars.
+#[cfg_attr(target_os = "none", no_mangle)]
+pub unsafe extern "C" fn strncpy(s: *mut c_char, t: *const c_char, n: i32) -> *const c_char {
+    let n = if n <= 0 { return s } else { n as usize };
+    let mut i = 0;
+    while i < n && *t.add(i) != 0 {
+        *s.add(i) = *t.add(i);
+        i += 1;
+    }
+    while i < n {
+        *s.add(i) = 0;
+        i += 1;
+    }
     s
 }
 
 // guaranteed to NUL terminated.
-#[no_mangle]
-pub extern "C" fn safestrncpy(s: *mut c_char, t: *const c_char, n: i32) -> *const c_char {
-    if n < 0 {
-        return s;
-    }
-
-    let (ss, ts) = unsafe {
-        let ss = slice::from_raw_parts_mut(s, n as usize);
-        let ts = slice::from_raw_parts(t, n as usize);
-        (ss, ts)
-    };
-    ss.iter_mut()
-        .zip(ts.iter().chain([0].iter()))
-        .map(|(e, ch)| {
-            *e = *ch;
-        });
+#[cfg_attr(target_os = "none", no_mangle)]
+pub unsafe extern "C" fn safestrncpy(s: *mut c_char, t: *const c_char, n: i32) -> *const c_char {
+    let n = if n <= 0 { return s } else { n as usize };
+    let mut i = 0;
+    while i + 1 < n && *t.add(i) != 0 {
+        *s.add(i) = *t.add(i);
+        i += 1;
+    }
+    *s.add(i) = 0;
     s
 }
 
-#[no_mangle]
-pub extern "C" fn strncmp(p: *const c_char, q: *const c_char, n: u32) -> isize {
-    let (ps, qs) = unsafe {
-        let ps = slice::from_raw_parts(p, n as usize);
-        let qs = slice::from_raw_parts(q, n as usize);
-        (ps, qs)
-    };
-
-    for (ps, qs) in ps.iter().take_while(|&&x| x != 0).chain([0].iter()).zip(qs) {
-        if *ps != *qs {
-            return (*ps - *qs) as isize;
+#[cfg_attr(target_os = "none", no_mangle)]
+pub unsafe extern "C" fn strncmp(p: *const c_char, q: *const c_char, n: u32) -> isize {
+    for i in 0..n as usize {
+        let (a, b) = (*p.add(i) as u8, *q.add(i) as u8);
+        if a != b || a == 0 {
+            return a as isize - b as isize;
         }
     }
     0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cstr(s: &[u8]) -> *const c_char {
+        s.as_ptr() as *const c_char
+    }
+
+    #[test]
+    fn memset_fills_n_bytes() {
+        let mut buf = [1u8; 8];
+        unsafe { memset(buf.as_mut_ptr() as *mut c_void, 7, 5) };
+        assert_eq!(buf, [7, 7, 7, 7, 7, 1, 1, 1]);
+    }
+
+    #[test]
+    fn memcmp_orders_as_unsigned() {
+        let a = [1u8, 2, 0x80];
+        let b = [1u8, 2, 0x01];
+        unsafe {
+            let (pa, pb) = (a.as_ptr() as *const c_void, b.as_ptr() as *const c_void);
+            assert_eq!(memcmp(pa, pa, 3), 0);
+            assert_eq!(memcmp(pa, pb, 2), 0);
+            assert!(memcmp(pa, pb, 3) > 0);
+            assert!(memcmp(pb, pa, 3) < 0);
+        }
+    }
+
+    #[test]
+    fn memmove_handles_overlap() {
+        let mut buf = *b"abcdef";
+        let p = buf.as_mut_ptr();
+        unsafe { memmove(p.add(2) as *mut c_void, p as *const c_void, 4) };
+        assert_eq!(&buf, b"ababcd");
+
+        let mut buf = *b"abcdef";
+        let p = buf.as_mut_ptr();
+        unsafe { memmove(p as *mut c_void, p.add(2) as *const c_void, 4) };
+        assert_eq!(&buf, b"cdefef");
+    }
+
+    #[test]
+    fn strlen_stops_at_nul() {
+        assert_eq!(unsafe { strlen(cstr(b"hello\0world\0")) }, 5);
+        assert_eq!(unsafe { strlen(cstr(b"\0")) }, 0);
+    }
+
+    #[test]
+    fn strncpy_pads_with_nul() {
+        let mut buf = [0x55 as c_char; 6];
+        unsafe { strncpy(buf.as_mut_ptr(), cstr(b"ab\0"), 5) };
+        assert_eq!(buf, [b'a' as c_char, b'b' as c_char, 0, 0, 0, 0x55]);
+    }
+
+    #[test]
+    fn safestrncpy_always_terminates() {
+        let mut buf = [0x55 as c_char; 4];
+        unsafe { safestrncpy(buf.as_mut_ptr(), cstr(b"abcdef\0"), 4) };
+        assert_eq!(buf, [b'a' as c_char, b'b' as c_char, b'c' as c_char, 0]);
+    }
+
+    #[test]
+    fn strncmp_compares_up_to_n() {
+        unsafe {
+            assert_eq!(strncmp(cstr(b"abc\0"), cstr(b"abd\0"), 2), 0);
+            assert!(strncmp(cstr(b"abc\0"), cstr(b"abd\0"), 3) < 0);
+            assert!(strncmp(cstr(b"ab\0"), cstr(b"a\0"), 5) > 0);
+            assert_eq!(strncmp(cstr(b"ab\0x"), cstr(b"ab\0y"), 4), 0);
+        }
+    }
+}
@@ -1 +1,20 @@
+// context switch between kernel threads.
+// only callee-saved registers are switched, the caller-saved ones are
+// already on the stack of whoever called swtch().
 
+use super::proc::Context;
+
+#[cfg(target_arch = "riscv64")]
+core::arch::global_asm!(include_str!("asm/switch.S"));
+
+#[cfg(target_arch = "riscv64")]
+extern "C" {
+    // save current registers in old, load from new.
+    pub fn swtch(old: *mut Context, new: *const Context);
+}
+
+// a simulated hart runs exactly one host thread and can't give it up.
+#[cfg(not(target_arch = "riscv64"))]
+pub unsafe extern "C" fn swtch(_old: *mut Context, _new: *const Context) {
+    panic!("swtch: no context switch on a simulated hart");
+}
@@ -0,0 +1,365 @@
+// system calls.
+//
+// usertrap() lands here for every ecall from user space. the system
+// call number is in a7, the arguments in a0..a5, and the result goes
+// back to user space in a0. a failed call returns -1.
+
+use super::exec::ExecErr;
+use super::file::FileId;
+use super::params::NOFILE;
+use super::proc::{Proc, StateErr};
+use super::state::os;
+use super::sysfile::*;
+use super::sysproc::*;
+use super::vm::VmErr;
+
+// System call numbers, shared with user space.
+// not all of them have a handler yet.
+pub use self::nr::*;
+#[allow(dead_code)]
+mod nr {
+    pub const SYS_FORK: usize = 1;
+    pub const SYS_EXIT: usize = 2;
+    pub const SYS_WAIT: usize = 3;
+    pub const SYS_PIPE: usize = 4;
+    pub const SYS_READ: usize = 5;
+    pub const SYS_KILL: usize = 6;
+    pub const SYS_EXEC: usize = 7;
+    pub const SYS_FSTAT: usize = 8;
+    pub const SYS_CHDIR: usize = 9;
+    pub const SYS_DUP: usize = 10;
+    pub const SYS_GETPID: usize = 11;
+    pub const SYS_SBRK: usize = 12;
+    pub const SYS_SLEEP: usize = 13;
+    pub const SYS_UPTIME: usize = 14;
+    pub const SYS_OPEN: usize = 15;
+    pub const SYS_WRITE: usize = 16;
+    pub const SYS_MKNOD: usize = 17;
+    pub const SYS_UNLINK: usize = 18;
+    pub const SYS_LINK: usize = 19;
+    pub const SYS_MKDIR: usize = 20;
+    pub const SYS_CLOSE: usize = 21;
+}
+
+// why a system call failed. user space only ever sees -1.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq)]
+pub enum SysErr {
+    BadAddrErr(VmErr), // an argument pointed at bad user memory
+    BadFdErr,          // not an open file descriptor
+    NoFdErr,           // all of the process's descriptors are in use
+    KilledErr,         // the process was killed while waiting
+    ProcErr(StateErr), // fork, wait or kill failed
+    ExecErr(ExecErr),  // exec could not load the program
+}
+
+impl From<VmErr> for SysErr {
+    fn from(e: VmErr) -> SysErr {
+        SysErr::BadAddrErr(e)
+    }
+}
+
+impl From<StateErr> for SysErr {
+    fn from(e: StateErr) -> SysErr {
+        SysErr::ProcErr(e)
+    }
+}
+
+impl From<ExecErr> for SysErr {
+    fn from(e: ExecErr) -> SysErr {
+        SysErr::ExecErr(e)
+    }
+}
+
+pub type SysResult = Result<u64, SysErr>;
+
+// the numbers without a handler are not implemented yet, and
+// take the unknown system call path.
+static SYSCALLS: [Option<fn() -> SysResult>; SYS_CLOSE + 1] = {
+    let mut t: [Option<fn() -> SysResult>; SYS_CLOSE + 1] = [None; SYS_CLOSE + 1];
+    t[SYS_FORK] = Some(sys_fork);
+    t[SYS_EXIT] = Some(sys_exit);
+    t[SYS_WAIT] = Some(sys_wait);
+    t[SYS_KILL] = Some(sys_kill);
+    t[SYS_EXEC] = Some(sys_exec);
+    t[SYS_DUP] = Some(sys_dup);
+    t[SYS_GETPID] = Some(sys_getpid);
+    t[SYS_SBRK] = Some(sys_sbrk);
+    t[SYS_SLEEP] = Some(sys_sleep);
+    t[SYS_UPTIME] = Some(sys_uptime);
+    t[SYS_CLOSE] = Some(sys_close);
+    t
+};
+
+pub fn syscall() {
+    let id = os().myproc().expect("syscall");
+    let p = &os().procs[id];
+    let tf = unsafe { &mut *p.tf.get() };
+
+    let num = tf.a7 as usize;
+    tf.a0 = match SYSCALLS.get(num) {
+        Some(Some(f)) => match f() {
+            Ok(ret) => ret,
+            Err(_) => -1i64 as u64,
+        },
+        _ => {
+            println!("{} {}: unknown sys call {}", p.lock.lock().pid, p.procname(), num);
+            -1i64 as u64
+        }
+    }
+}
+
+fn myproc() -> &'static Proc {
+    let id = os().myproc().expect("syscall: no process");
+    &os().procs[id]
+}
+
+// Fetch the u64 at addr from the current process.
+pub fn fetchaddr(addr: u64) -> Result<u64, SysErr> {
+    let p = myproc();
+    // both tests needed, in case of overflow
+    if addr >= p.sz.get() || addr + 8 > p.sz.get() {
+        return Err(SysErr::BadAddrErr(VmErr::BadAddressErr(addr)));
+    }
+    let mut buf = [0u8; 8];
+    unsafe { (*p.pagetable.get()).copyin(&mut buf, addr)? };
+    Ok(u64::from_le_bytes(buf))
+}
+
+// Fetch the nul-terminated string at addr from the current process.
+// Returns length of string, not including nul.
+pub fn fetchstr(addr: u64, buf: &mut [u8]) -> Result<usize, SysErr> {
+    let p = myproc();
+    Ok(unsafe { (*p.pagetable.get()).copyinstr(buf, addr)? })
+}
+
+fn argraw(n: usize) -> u64 {
+    let tf = unsafe { &*myproc().tf.get() };
+    match n {
+        0 => tf.a0,
+        1 => tf.a1,
+        2 => tf.a2,
+        3 => tf.a3,
+        4 => tf.a4,
+        5 => tf.a5,
+        _ => panic!("argraw"),
+    }
+}
+
+// Fetch the nth 32-bit system call argument.
+pub fn argint(n: usize) -> i32 {
+    argraw(n) as i32
+}
+
+// Retrieve an argument as a pointer.
+// Doesn't check for legality, since
+// copyin/copyout will do that.
+pub fn argaddr(n: usize) -> u64 {
+    argraw(n)
+}
+
+// Fetch the nth word-sized system call argument as a null-terminated string.
+// Copies into buf, at most buf.len() (and MAXPATH) bytes.
+// Returns string length, not including nul.
+pub fn argstr(n: usize, buf: &mut [u8]) -> Result<usize, SysErr> {
+    fetchstr(argaddr(n), buf)
+}
+
+// Fetch the nth word-sized system call argument as a file descriptor
+// and return both the descriptor and the corresponding open file.
+pub fn argfd(n: usize) -> Result<(usize, FileId), SysErr> {
+    let fd = argint(n);
+    if fd < 0 || fd as usize >= NOFILE {
+        return Err(SysErr::BadFdErr);
+    }
+    match myproc().ofile[fd as usize].get() {
+        Some(f) => Ok((fd as usize, f)),
+        None => Err(SysErr::BadFdErr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::exec::tests::image;
+    use super::super::fs::tests::{putfile, rootdisk};
+    use super::super::fs::ROOTINO;
+    use super::super::kalloc::{self, kalloc};
+    use super::super::memlayout::TRAPFRAME;
+    use super::super::params::{MAXARG, NPROC, ROOTDEV};
+    use super::super::proc::{ProcId, Trapframe};
+    use super::super::riscv::{PG, PTE};
+    use super::*;
+
+    // run f as a process in slot NPROC-4 with a one page user image,
+    // then put the slot back.
+    fn as_proc<F: FnOnce(&Proc)>(f: F) {
+        let _kmem = kalloc::tests::kinit();
+        let id = ProcId(NPROC - 4);
+        let p = &os().procs[id];
+        p.tf.set(kalloc().unwrap() as *mut Trapframe);
+        unsafe { *p.tf.get() = Trapframe::default() };
+        p.pagetable.set(p.proc_pagetable().unwrap());
+        p.sz.set(unsafe { (*p.pagetable.get()).uvmalloc(0, PG::SIZE, PTE::W).unwrap() });
+        p.lock.lock().pid = 4242;
+        p.setname(b"hello");
+        os().mycpu().proc.set(Some(id));
+
+        f(p);
+
+        os().mycpu().proc.set(None);
+        super::super::proc::freeproc(id, p.lock.lock());
+    }
+
+    fn call(p: &Proc, num: usize) -> u64 {
+        unsafe { (*p.tf.get()).a7 = num as u64 };
+        syscall();
+        unsafe { (*p.tf.get()).a0 }
+    }
+
+    #[test]
+    fn dispatches_by_number() {
+        as_proc(|p| {
+            assert_eq!(call(p, SYS_GETPID), 4242);
+            unsafe { (*p.tf.get()).a0 = 0 };
+            assert_eq!(call(p, SYS_SBRK), PG::SIZE);
+            assert_eq!(p.sz.get(), PG::SIZE);
+            unsafe { (*p.tf.get()).a0 = PG::SIZE };
+            assert_eq!(call(p, SYS_SBRK), PG::SIZE);
+            assert_eq!(p.sz.get(), 2 * PG::SIZE);
+            assert!(unsafe { (*p.pagetable.get()).walkaddr(PG::SIZE) }.is_some());
+        });
+    }
+
+    #[test]
+    fn unknown_syscall_returns_minus_one() {
+        as_proc(|p| {
+            assert_eq!(call(p, 0), -1i64 as u64);
+            assert_eq!(call(p, 99), -1i64 as u64);
+            assert_eq!(call(p, SYS_OPEN), -1i64 as u64);
+        });
+    }
+
+    #[test]
+    fn fetches_arguments() {
+        as_proc(|p| {
+            let pt = unsafe { &mut *p.pagetable.get() };
+            pt.copyout(16, b"/init\0").unwrap();
+            pt.copyout(8, &16u64.to_le_bytes()).unwrap();
+            let tf = unsafe { &mut *p.tf.get() };
+            tf.a0 = 16;
+            tf.a1 = -3i64 as u64;
+            tf.a2 = TRAPFRAME;
+
+            let mut buf = [0u8; 32];
+            assert_eq!(argstr(0, &mut buf), Ok(5));
+            assert_eq!(&buf[..6], b"/init\0");
+            assert_eq!(argint(1), -3);
+            assert_eq!(argaddr(0), 16);
+            assert_eq!(fetchaddr(8), Ok(16));
+            assert!(fetchaddr(PG::SIZE - 4).is_err());
+            // the trapframe is mapped, but not for the user.
+            assert_eq!(
+                argstr(2, &mut buf),
+                Err(SysErr::BadAddrErr(VmErr::BadAddressErr(TRAPFRAME)))
+            );
+        });
+    }
+
+    #[test]
+    fn exec_fetches_path_and_argv() {
+        as_proc(|p| {
+            let _root = rootdisk();
+            let root = os().iget(ROOTDEV as u32, ROOTINO);
+            putfile(root, b"echo", &image(&[0x13; 8], b"d", 0));
+            os().iput(root);
+
+            // the strings, then argv = { "echo", "hi", 0 } at 64,
+            // and MAXARG + 1 copies of "hi" at 256.
+            let pt = unsafe { &mut *p.pagetable.get() };
+            pt.copyout(16, b"/echo\0").unwrap();
+            pt.copyout(32, b"hi\0").unwrap();
+            for (i, a) in [17u64, 32, 0].iter().enumerate() {
+                pt.copyout(64 + 8 * i as u64, &a.to_le_bytes()).unwrap();
+            }
+            for i in 0..=MAXARG as u64 {
+                pt.copyout(256 + 8 * i, &32u64.to_le_bytes()).unwrap();
+            }
+            let exec = |p: &Proc, path: u64, argv: u64| {
+                unsafe { ((*p.tf.get()).a0, (*p.tf.get()).a1) = (path, argv) };
+                call(p, SYS_EXEC)
+            };
+
+            assert_eq!(exec(p, TRAPFRAME, 64), -1i64 as u64);
+            assert_eq!(exec(p, 16, 256), -1i64 as u64);
+            // argv runs off the end of user memory.
+            assert_eq!(exec(p, 16, PG::SIZE - 4), -1i64 as u64);
+            assert_eq!(p.procname(), "hello");
+
+            assert_eq!(exec(p, 16, 64), 2);
+            assert_eq!(p.procname(), "echo");
+            let pt = unsafe { &mut *p.pagetable.get() };
+            let mut argv0 = [0u8; 8];
+            pt.copyin(&mut argv0, unsafe { (*p.tf.get()).a1 }).unwrap();
+            let mut arg = [0u8; 5];
+            pt.copyin(&mut arg, u64::from_le_bytes(argv0)).unwrap();
+            assert_eq!(&arg, b"echo\0");
+        });
+    }
+
+    #[test]
+    fn argfd_checks_the_table() {
+        as_proc(|p| {
+            p.ofile[3].set(Some(FileId(7)));
+            let tf = unsafe { &mut *p.tf.get() };
+            tf.a0 = 3;
+            tf.a1 = 4;
+            tf.a2 = -1i64 as u64;
+            tf.a3 = NOFILE as u64;
+            assert_eq!(argfd(0).map(|(fd, f)| (fd, f.0)), Ok((3, 7)));
+            assert_eq!(argfd(1).err(), Some(SysErr::BadFdErr));
+            assert_eq!(argfd(2).err(), Some(SysErr::BadFdErr));
+            assert_eq!(argfd(3).err(), Some(SysErr::BadFdErr));
+        });
+    }
+
+    #[test]
+    fn dup_and_close_share_the_file() {
+        as_proc(|p| {
+            {
+                let mut file = os().ftable.file.lock();
+                file[8].refc = 1;
+            }
+            p.ofile[0].set(Some(FileId(8)));
+            let a0 = |fd: u64| unsafe { (*p.tf.get()).a0 = fd };
+
+            a0(0);
+            assert_eq!(call(p, SYS_DUP), 1);
+            assert_eq!(p.ofile[1].get(), Some(FileId(8)));
+            assert_eq!(os().ftable.file.lock()[8].refc, 2);
+
+            a0(0);
+            assert_eq!(call(p, SYS_CLOSE), 0);
+            assert_eq!(p.ofile[0].get(), None);
+            a0(0);
+            assert_eq!(call(p, SYS_CLOSE), -1i64 as u64);
+            // dup hands out the lowest free descriptor.
+            a0(1);
+            assert_eq!(call(p, SYS_DUP), 0);
+
+            for fd in 2..NOFILE {
+                p.ofile[fd].set(Some(FileId(8)));
+            }
+            a0(0);
+            assert_eq!(call(p, SYS_DUP), -1i64 as u64);
+            for fd in 2..NOFILE {
+                p.ofile[fd].set(None);
+            }
+
+            for fd in 0..2 {
+                a0(fd);
+                assert_eq!(call(p, SYS_CLOSE), 0);
+            }
+            assert_eq!(os().ftable.file.lock()[8].refc, 0);
+        });
+    }
+}
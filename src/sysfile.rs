@@ -0,0 +1,82 @@
+// File-system system calls.
+// Mostly argument checking, since we don't trust
+// user code, and calls into file.rs and fs.rs.
+
+use super::exec::{exec, ExecErr};
+use super::file::FileId;
+use super::kalloc::{kalloc, kfree};
+use super::params::{MAXARG, MAXPATH};
+use super::riscv::PG;
+use super::state::os;
+use super::syscall::{argaddr, argfd, argstr, fetchaddr, fetchstr, SysErr, SysResult};
+use super::vm::VmErr;
+use core::slice;
+
+// the argument strings of exec share one page.
+const _: () = assert!(MAXARG * MAXPATH <= PG::SIZE as usize);
+
+// Allocate a file descriptor for the given file.
+// Takes over file reference from caller on success.
+fn fdalloc(f: FileId) -> Result<usize, SysErr> {
+    let p = &os().procs[os().myproc().expect("fdalloc")];
+    match p.ofile.iter().position(|of| of.get().is_none()) {
+        Some(fd) => {
+            p.ofile[fd].set(Some(f));
+            Ok(fd)
+        }
+        None => Err(SysErr::NoFdErr),
+    }
+}
+
+pub fn sys_dup() -> SysResult {
+    let (_, f) = argfd(0)?;
+    let fd = fdalloc(f)?;
+    os().filedup(f);
+    Ok(fd as u64)
+}
+
+pub fn sys_close() -> SysResult {
+    let (fd, f) = argfd(0)?;
+    let p = &os().procs[os().myproc().expect("sys_close")];
+    p.ofile[fd].set(None);
+    os().fileclose(f);
+    Ok(0)
+}
+
+pub fn sys_exec() -> SysResult {
+    let mut path = [0u8; MAXPATH];
+    let uargv = argaddr(1);
+    let n = argstr(0, &mut path)?;
+
+    let page = kalloc().ok_or(VmErr::OutOfMemErr)?;
+    let strs = unsafe { slice::from_raw_parts_mut(page, PG::SIZE as usize) };
+    let mut argv: [&[u8]; MAXARG] = [&[]; MAXARG];
+    let ret = match fetchargs(uargv, strs, &mut argv) {
+        Ok(argc) => exec(&path[..n], &argv[..argc]).map_err(SysErr::from),
+        Err(e) => Err(e),
+    };
+    kfree(page);
+    ret
+}
+
+// fetch the null-terminated array of string pointers at uargv,
+// copying each string into its own MAXPATH bytes of strs.
+// returns argc.
+fn fetchargs<'a>(
+    uargv: u64,
+    strs: &'a mut [u8],
+    argv: &mut [&'a [u8]; MAXARG],
+) -> Result<usize, SysErr> {
+    for (i, dst) in strs.chunks_mut(MAXPATH).take(MAXARG).enumerate() {
+        let addr = uargv
+            .checked_add(8 * i as u64)
+            .ok_or(VmErr::BadAddressErr(uargv))?;
+        let uarg = fetchaddr(addr)?;
+        if uarg == 0 {
+            return Ok(i);
+        }
+        let n = fetchstr(uarg, dst)?;
+        argv[i] = &dst[..n];
+    }
+    Err(ExecErr::TooManyArgsErr.into())
+}
@@ -0,0 +1,62 @@
+// process related system calls.
+
+use super::proc;
+use super::state::os;
+use super::syscall::{argaddr, argint, SysErr, SysResult};
+use super::trap::TICKS;
+
+pub fn sys_exit() -> SysResult {
+    let n = argint(0);
+    proc::exit(n)
+}
+
+pub fn sys_fork() -> SysResult {
+    Ok(proc::fork()? as u64)
+}
+
+pub fn sys_wait() -> SysResult {
+    let p = argaddr(0);
+    Ok(proc::wait(p)? as u64)
+}
+
+pub fn sys_kill() -> SysResult {
+    let pid = argint(0);
+    proc::kill(pid)?;
+    Ok(0)
+}
+
+pub fn sys_getpid() -> SysResult {
+    let id = os().myproc().expect("getpid");
+    Ok(os().procs[id].lock.lock().pid as u64)
+}
+
+// grow (or shrink) user memory by n bytes.
+// returns the old size, which is where the new memory starts.
+pub fn sys_sbrk() -> SysResult {
+    let n = argint(0);
+    let id = os().myproc().expect("sbrk");
+    let addr = os().procs[id].sz.get();
+    proc::growproc(n)?;
+    Ok(addr)
+}
+
+pub fn sys_sleep() -> SysResult {
+    let n = argint(0).max(0) as u32;
+    let id = os().myproc().expect("sleep");
+    let mut ticks = TICKS.lock();
+    let ticks0 = *ticks;
+    while ticks.wrapping_sub(ticks0) < n {
+        if os().procs[id].killed() {
+            return Err(SysErr::KilledErr);
+        }
+        let chan = &*ticks as *const u32;
+        ticks = os().sleep(chan, ticks);
+    }
+    Ok(0)
+}
+
+// return how many clock tick interrupts have occurred
+// since start.
+pub fn sys_uptime() -> SysResult {
+    Ok(*TICKS.lock() as u64)
+}
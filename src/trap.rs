@@ -0,0 +1,320 @@
+// traps: system calls, exceptions and device interrupts.
+//
+// traps from user space enter through uservec in trampoline.S, which
+// saves the user registers in p.tf and jumps to usertrap() on the
+// process's kernel stack. usertrapret() goes the other way.
+// traps taken in the kernel enter through kernelvec in kernelvec.S,
+// which saves registers on the current stack and calls kerneltrap().
+
+use super::memlayout::{TRAMPOLINE, TRAPFRAME, UART, UVIRTIO};
+use super::plic;
+use super::proc::{self, ProcState};
+use super::riscv::{self, CSR::*};
+use super::spinlock::SpinLock;
+use super::state::os;
+use super::syscall::syscall;
+use super::uart;
+use core::mem;
+
+// ticks since boot, bumped by hart 0 on each timer interrupt.
+pub static TICKS: SpinLock<u32> = SpinLock::new(0, "time");
+
+#[cfg(target_os = "none")]
+extern "C" {
+    // in kernelvec.S, calls kerneltrap().
+    fn kernelvec();
+
+    // in trampoline.S
+    pub fn trampoline();
+    fn uservec();
+    fn userret();
+}
+
+// the vectors only exist in the kernel image. a simulated hart never
+// traps, so these stand-ins only keep trap.rs building on the host.
+#[cfg(not(target_os = "none"))]
+pub use self::hosted::*;
+
+#[cfg(not(target_os = "none"))]
+mod hosted {
+    pub extern "C" fn kernelvec() {
+        unreachable!("kernelvec")
+    }
+    pub extern "C" fn trampoline() {
+        unreachable!("trampoline")
+    }
+    pub extern "C" fn uservec() {
+        unreachable!("uservec")
+    }
+    pub extern "C" fn userret() {
+        unreachable!("userret")
+    }
+}
+
+// scause: the top bit says interrupt, the rest is the cause code.
+const SCAUSE_INTR: u64 = 1 << 63;
+const SCAUSE_ECALL_U: u64 = 8;
+const SCAUSE_INST_PAGE_FAULT: u64 = 12;
+const SCAUSE_LOAD_PAGE_FAULT: u64 = 13;
+const SCAUSE_STORE_PAGE_FAULT: u64 = 15;
+const SCAUSE_SOFT_INTR: u64 = SCAUSE_INTR | 1;
+const SCAUSE_EXT_INTR: u64 = SCAUSE_INTR | 9;
+
+// what devintr() found.
+#[derive(PartialEq)]
+enum Intr {
+    Timer,
+    Device,
+    Unknown,
+}
+
+// set up to take exceptions and traps while in the kernel.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn trapinithart() {
+    STVEC::write(kernelvec as *const () as u64);
+}
+
+// handle an interrupt, exception, or system call from user space.
+// called from trampoline.S
+#[no_mangle]
+pub extern "C" fn usertrap() -> ! {
+    if SSTATUS::read() & SSTATUS::SPP != 0 {
+        panic!("usertrap: not from user mode");
+    }
+
+    // send interrupts and exceptions to kerneltrap(),
+    // since we're now in the kernel.
+    STVEC::write(kernelvec as *const () as u64);
+
+    let id = os().myproc().expect("usertrap");
+    let p = &os().procs[id];
+    let tf = unsafe { &mut *p.tf.get() };
+
+    // save user program counter.
+    tf.epc = SEPC::read();
+
+    let scause = SCAUSE::read();
+    let mut which = Intr::Unknown;
+    match scause {
+        SCAUSE_ECALL_U => {
+            // system call
+            if p.killed() {
+                proc::exit(-1);
+            }
+
+            // sepc points to the ecall instruction,
+            // but we want to return to the next instruction.
+            tf.epc += 4;
+
+            // an interrupt will change sstatus &c registers,
+            // so don't enable until done with those registers.
+            riscv::DEV_INTR::on();
+
+            syscall();
+        }
+        SCAUSE_INST_PAGE_FAULT | SCAUSE_LOAD_PAGE_FAULT | SCAUSE_STORE_PAGE_FAULT => {
+            // nothing is mapped lazily, so the address is just bad.
+            println!(
+                "usertrap(): {} pid={}\n            sepc={:#x} stval={:#x}",
+                cause_name(scause),
+                p.lock.lock().pid,
+                tf.epc,
+                STVAL::read()
+            );
+            p.setkilled();
+        }
+        _ => {
+            which = devintr();
+            if which == Intr::Unknown {
+                println!(
+                    "usertrap(): unexpected scause {:#x} ({}) pid={}\n            sepc={:#x} stval={:#x}",
+                    scause,
+                    cause_name(scause),
+                    p.lock.lock().pid,
+                    tf.epc,
+                    STVAL::read()
+                );
+                p.setkilled();
+            }
+        }
+    }
+
+    if p.killed() {
+        proc::exit(-1);
+    }
+
+    // give up the CPU if this is a timer interrupt.
+    if which == Intr::Timer {
+        proc::yield_();
+    }
+
+    usertrapret()
+}
+
+// return to user space
+pub fn usertrapret() -> ! {
+    let id = os().myproc().expect("usertrapret");
+    let p = &os().procs[id];
+    let tf = unsafe { &mut *p.tf.get() };
+
+    // we're about to switch the destination of traps from
+    // kerneltrap() to usertrap(), so turn off interrupts until
+    // we're back in user space, where usertrap() is correct.
+    riscv::DEV_INTR::off();
+
+    // send syscalls, interrupts, and exceptions to trampoline.S
+    let trampoline = trampoline as *const () as u64;
+    STVEC::write(TRAMPOLINE + (uservec as *const () as u64 - trampoline));
+
+    // set up trapframe values that uservec will need when
+    // the process next re-enters the kernel.
+    tf.kernel_satp = SATP::read(); // kernel page table
+    tf.kernel_sp = p.kstack.get() + riscv::PG::SIZE; // process's kernel stack
+    tf.kernel_trap = usertrap as *const () as u64;
+    tf.kernel_hartid = riscv::REGS::TP::read(); // hartid for cpuid()
+
+    // set up the registers that trampoline.S's sret will use
+    // to get to user space.
+
+    // set S Previous Privilege mode to User.
+    let mut x = SSTATUS::read();
+    x &= !SSTATUS::SPP; // clear SPP to 0 for user mode
+    x |= SSTATUS::SPIE; // enable interrupts in user mode
+    SSTATUS::write(x);
+
+    // set S Exception Program Counter to the saved user pc.
+    SEPC::write(tf.epc);
+
+    // tell trampoline.S the user page table to switch to.
+    let satp = SATP::make(p.pagetable.get() as u64);
+
+    // jump to trampoline.S at the top of memory, which
+    // switches to the user page table, restores user registers,
+    // and switches to user mode with sret.
+    let userret = TRAMPOLINE + (userret as *const () as u64 - trampoline);
+    unsafe {
+        let userret: extern "C" fn(u64, u64) -> ! = mem::transmute(userret as usize);
+        userret(TRAPFRAME, satp)
+    }
+}
+
+// interrupts and exceptions from kernel code go here via kernelvec,
+// on whatever the current kernel stack is.
+#[no_mangle]
+pub extern "C" fn kerneltrap() {
+    let sepc = SEPC::read();
+    let sstatus = SSTATUS::read();
+    let scause = SCAUSE::read();
+
+    if sstatus & SSTATUS::SPP == 0 {
+        panic!("kerneltrap: not from supervisor mode");
+    }
+    if riscv::DEV_INTR::get() {
+        panic!("kerneltrap: interrupts enabled");
+    }
+
+    let which = devintr();
+    if which == Intr::Unknown {
+        panic!(
+            "kerneltrap: scause {:#x} ({}) sepc={:#x} stval={:#x}",
+            scause,
+            cause_name(scause),
+            sepc,
+            STVAL::read()
+        );
+    }
+
+    // give up the CPU if this is a timer interrupt.
+    if which == Intr::Timer {
+        if let Some(id) = os().myproc() {
+            let running = os().procs[id].lock.lock().state == ProcState::Running;
+            if running {
+                proc::yield_();
+            }
+        }
+    }
+
+    // the yield_() may have caused some traps to occur,
+    // so restore trap registers for use by kernelvec.S's sepc instruction.
+    SEPC::write(sepc);
+    SSTATUS::write(sstatus);
+}
+
+fn clockintr() {
+    let mut ticks = TICKS.lock();
+    *ticks += 1;
+    os().wakeup(&*ticks as *const u32);
+}
+
+// check if it's an external interrupt or software interrupt,
+// and handle it.
+fn devintr() -> Intr {
+    match SCAUSE::read() {
+        SCAUSE_EXT_INTR => {
+            // this is a supervisor external interrupt, via PLIC.
+
+            // irq indicates which device interrupted.
+            let irq = plic::claim();
+
+            if irq == UART::UART0_1RQ {
+                uart::uartintr();
+            } else if irq == UVIRTIO::UVIRTIO0_IRQ {
+                // the virtio disk driver doesn't use interrupts yet.
+            } else if irq != 0 {
+                println!("unexpected interrupt irq={}", irq);
+            }
+
+            // the PLIC allows each device to raise at most one
+            // interrupt at a time; tell the PLIC the device is
+            // now allowed to interrupt again.
+            if irq != 0 {
+                plic::complete(irq);
+            }
+
+            Intr::Device
+        }
+        SCAUSE_SOFT_INTR => {
+            // software interrupt from a machine-mode timer interrupt,
+            // forwarded by timervec in kernelvec.S.
+
+            if os().cpuid().0 == 0 {
+                clockintr();
+            }
+
+            // acknowledge the software interrupt by clearing
+            // the SSIP bit in sip.
+            SIP::write(SIP::read() & !2);
+
+            Intr::Timer
+        }
+        _ => Intr::Unknown,
+    }
+}
+
+// human readable scause, for the diagnostics above.
+fn cause_name(scause: u64) -> &'static str {
+    if scause & SCAUSE_INTR != 0 {
+        return match scause & !SCAUSE_INTR {
+            1 => "supervisor software interrupt",
+            5 => "supervisor timer interrupt",
+            9 => "supervisor external interrupt",
+            _ => "unknown interrupt",
+        };
+    }
+    match scause {
+        0 => "instruction address misaligned",
+        1 => "instruction access fault",
+        2 => "illegal instruction",
+        3 => "breakpoint",
+        4 => "load address misaligned",
+        5 => "load access fault",
+        6 => "store/amo address misaligned",
+        7 => "store/amo access fault",
+        8 => "environment call from u-mode",
+        9 => "environment call from s-mode",
+        12 => "instruction page fault",
+        13 => "load page fault",
+        15 => "store/amo page fault",
+        _ => "unknown exception",
+    }
+}
@@ -0,0 +1,103 @@
+// low-level driver routines for 16550a UART.
+// polling output for the kernel's printf, and interrupt-driven input
+// that is echoed back until there is a console device to read it.
+
+use super::kalloc;
+use super::memlayout::UART::UART0;
+use super::state::os;
+use core::ptr;
+
+// the UART control registers.
+// some have different meanings for
+// read vs write.
+// see http://byterunner.com/16550.html
+const RHR: u64 = 0; // receive holding register (for input bytes)
+const THR: u64 = 0; // transmit holding register (for output bytes)
+const IER: u64 = 1; // interrupt enable register
+const IER_RX_ENABLE: u8 = 1;
+const FCR: u64 = 2; // FIFO control register
+const FCR_FIFO_ENABLE: u8 = 1;
+const FCR_FIFO_CLEAR: u8 = 3 << 1; // clear the content of the two FIFOs
+const LCR: u64 = 3; // line control register
+const LCR_EIGHT_BITS: u8 = 3;
+const LCR_BAUD_LATCH: u8 = 1 << 7; // special mode to set baud rate
+const LSR: u64 = 5; // line status register
+const LSR_RX_READY: u8 = 1; // input is waiting to be read from RHR
+const LSR_TX_IDLE: u8 = 1 << 5; // THR can accept another character to send
+
+// the UART control registers are memory-mapped
+// at address UART0.
+#[inline]
+fn read_reg(reg: u64) -> u8 {
+    unsafe { ptr::read_volatile((UART0 + reg) as *const u8) }
+}
+
+#[inline]
+fn write_reg(reg: u64, v: u8) {
+    unsafe { ptr::write_volatile((UART0 + reg) as *mut u8, v) }
+}
+
+pub fn init() {
+    // disable interrupts.
+    write_reg(IER, 0x00);
+
+    // special mode to set baud rate.
+    write_reg(LCR, LCR_BAUD_LATCH);
+
+    // LSB for baud rate of 38.4K.
+    write_reg(0, 0x03);
+
+    // MSB for baud rate of 38.4K.
+    write_reg(1, 0x00);
+
+    // leave set-baud mode,
+    // and set word length to 8 bits, no parity.
+    write_reg(LCR, LCR_EIGHT_BITS);
+
+    // reset and enable FIFOs.
+    write_reg(FCR, FCR_FIFO_ENABLE | FCR_FIFO_CLEAR);
+
+    // enable receive interrupts.
+    write_reg(IER, IER_RX_ENABLE);
+}
+
+// write one output character to the UART, spinning
+// until the UART's output register is empty.
+// used by the kernel's printf(), doesn't use interrupts.
+pub fn putc_sync(c: u8) {
+    while read_reg(LSR) & LSR_TX_IDLE == 0 {}
+    write_reg(THR, c);
+}
+
+// read one input character from the UART.
+// return None if none is waiting.
+fn getc() -> Option<u8> {
+    if read_reg(LSR) & LSR_RX_READY != 0 {
+        Some(read_reg(RHR))
+    } else {
+        None
+    }
+}
+
+// Control-P
+const CTRL_P: u8 = 0x10;
+
+// handle a uart interrupt, raised because input has arrived.
+// called from devintr(). there is no console device to hand
+// the input to yet, so just echo it. ^P prints the kernel's
+// memory and buffer cache counters.
+pub fn uartintr() {
+    while let Some(c) = getc() {
+        match c {
+            CTRL_P => {
+                let mem = kalloc::stats();
+                println!("\n{} pages free, {} in use", mem.nfree, mem.nused);
+                let bc = os().bcache.stats();
+                println!("bcache: {} lock acquires, {} spins", bc.nacquire, bc.nspin);
+            }
+            b'\r' => print!("\n"),
+            0x7f => print!("\x08 \x08"), // backspace
+            c => print!("{}", c as char),
+        }
+    }
+}
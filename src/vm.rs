@@ -20,11 +20,665 @@
 //                  | user text and |
 //                  | data          |  instruction come first, then global variales
 //             0 -> +===============+
-// use 39 - 1 bits for virtual address, maxium address = 2^38 - 1 = 0x3fffffff = MAXVA
+// use 39 - 1 bits for virtual address, maxium address = 2^38 - 1 = 0x3fffffffff = MAXVA - 1
 
-use super::riscv;
+use super::kalloc::{kalloc, kfree};
+#[cfg(target_os = "none")]
+use super::memlayout::{CLINT, KERNBASE, PHYSTOP, PLIC, TRAMPOLINE, UART, UVIRTIO};
+use super::params::MAXPATH;
+use super::riscv::{Pagetable, Pte, CSR::SATP, FENCE, MAXVA, PG, PTE, PX};
+use super::state::os;
+#[cfg(target_os = "none")]
+use super::proc;
+#[cfg(target_os = "none")]
+use super::trap::trampoline;
+use core::ptr;
+use core::sync::atomic::Ordering;
 
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq)]
+pub enum VmErr {
+    OutOfMemErr,        // kalloc() could not supply a page
+    BadAddressErr(u64), // user va is unmapped or not accessible to the user
+    NameTooLongErr,     // no NUL within MAXPATH bytes
+}
+
+#[cfg(target_os = "none")]
+extern "C" {
+    // kernel.ld sets this to end of kernel code.
+    static etext: u8;
+}
 
+// allocate a zeroed page to hold a page table.
+pub fn pagetable_alloc() -> Option<*mut Pagetable> {
+    let pa = kalloc()? as *mut Pagetable;
+    unsafe { ptr::write(pa, Pagetable::new()) };
+    Some(pa)
+}
+
+// create a direct-map page table for the kernel.
+#[cfg(target_os = "none")]
 pub fn kvminit() {
+    let kpgtbl = pagetable_alloc().expect("kvminit");
+    let kpgtbl = unsafe { &mut *kpgtbl };
+    let text_end = unsafe { &etext as *const u8 as u64 };
+
+    // uart registers
+    kvmmap(kpgtbl, UART::UART0, UART::UART0, PG::SIZE, PTE::R | PTE::W);
+
+    // virtio mmio disk interface
+    kvmmap(kpgtbl, UVIRTIO::UVIRTIO0, UVIRTIO::UVIRTIO0, PG::SIZE, PTE::R | PTE::W);
+
+    // CLINT
+    kvmmap(kpgtbl, CLINT::CLINT, CLINT::CLINT, 0x10000, PTE::R | PTE::W);
+
+    // PLIC
+    kvmmap(kpgtbl, PLIC::PLIC, PLIC::PLIC, 0x400000, PTE::R | PTE::W);
+
+    // map kernel text executable and read-only.
+    kvmmap(kpgtbl, KERNBASE, KERNBASE, text_end - KERNBASE, PTE::R | PTE::X);
+
+    // map kernel data and the physical RAM we'll make use of.
+    kvmmap(kpgtbl, text_end, text_end, PHYSTOP - text_end, PTE::R | PTE::W);
+
+    // map the trampoline for trap entry/exit to
+    // the highest virtual address in the kernel.
+    let trampoline = trampoline as *const () as u64;
+    kvmmap(kpgtbl, TRAMPOLINE, trampoline, PG::SIZE, PTE::R | PTE::X);
+
+    // allocate and map a kernel stack for each process.
+    proc::proc_mapstacks(kpgtbl);
+
+    os().kpagetable.store(kpgtbl, Ordering::Relaxed);
+}
+
+// Switch h/w page table register to the kernel's page table,
+// and enable paging.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn kvminithart() {
+    // wait for any previous writes to the page table memory to finish.
+    FENCE::sfence_vma();
+
+    SATP::write(SATP::make(os().kpagetable.load(Ordering::Relaxed) as u64));
+
+    // flush stale entries from the TLB.
+    FENCE::sfence_vma();
+}
+
+// add a mapping to the kernel page table.
+// only used when booting.
+// does not flush TLB or enable paging.
+pub fn kvmmap(kpgtbl: &mut Pagetable, va: u64, pa: u64, sz: u64, perm: u64) {
+    if kpgtbl.mappages(va, sz, pa, perm).is_err() {
+        panic!("kvmmap");
+    }
+}
+
+// create an empty user page table.
+pub fn uvmcreate() -> Result<*mut Pagetable, VmErr> {
+    pagetable_alloc().ok_or(VmErr::OutOfMemErr)
+}
+
+// Recursively free page-table pages.
+// All leaf mappings must already have been removed.
+pub fn freewalk(pagetable: *mut Pagetable) {
+    // there are 2^9 = 512 PTEs in a page table.
+    let pt = unsafe { &mut *pagetable };
+    for pte in pt.0.iter_mut() {
+        if pte.is_valid() && !pte.is_leaf() {
+            // this PTE points to a lower-level page table.
+            freewalk(pte.pa() as *mut Pagetable);
+            *pte = Pte(0);
+        } else if pte.is_valid() {
+            panic!("freewalk: leaf");
+        }
+    }
+    kfree(pagetable as *mut u8);
+}
+
+// Free user memory pages,
+// then free page-table pages.
+pub fn uvmfree(pagetable: *mut Pagetable, sz: u64) {
+    if sz > 0 {
+        unsafe { (*pagetable).uvmunmap(0, PG::roundup(sz) / PG::SIZE, true) };
+    }
+    freewalk(pagetable);
+}
+
+impl Pagetable {
+    // Return the address of the PTE in page table pagetable
+    // that corresponds to virtual address va.  If alloc is true,
+    // create any required page-table pages.
+    //
+    // The risc-v Sv39 scheme has three levels of page-table
+    // pages. A page-table page contains 512 64-bit PTEs.
+    // A 64-bit virtual address is split into five fields:
+    //   39..63 -- must be zero.
+    //   30..38 -- 9 bits of level-2 index.
+    //   21..29 -- 9 bits of level-1 index.
+    //   12..20 -- 9 bits of level-0 index.
+    //    0..11 -- 12 bits of byte offset within the page.
+    pub fn walk(&mut self, va: u64, alloc: bool) -> Option<&mut Pte> {
+        if va >= MAXVA {
+            panic!("walk {:#x}", va);
+        }
+
+        let mut pagetable = self as *mut Pagetable;
+        for level in (1..3).rev() {
+            let pte = unsafe { &mut (*pagetable).0[PX::px(level, va) as usize] };
+            if pte.is_valid() {
+                pagetable = pte.pa() as *mut Pagetable;
+            } else {
+                if !alloc {
+                    return None;
+                }
+                pagetable = pagetable_alloc()?;
+                *pte = Pte::new(pagetable as u64, PTE::V);
+            }
+        }
+        Some(unsafe { &mut (*pagetable).0[PX::px(0, va) as usize] })
+    }
+
+    // Look up a virtual address, return the physical address,
+    // or None if not mapped.
+    // Can only be used to look up user pages.
+    pub fn walkaddr(&mut self, va: u64) -> Option<u64> {
+        if va >= MAXVA {
+            return None;
+        }
+        let pte = *self.walk(va, false)?;
+        if !pte.is_valid() || pte.flags() & PTE::U == 0 {
+            return None;
+        }
+        Some(pte.pa())
+    }
+
+    // Create PTEs for virtual addresses starting at va that refer to
+    // physical addresses starting at pa. va and size might not
+    // be page-aligned. Returns Err if walk() couldn't
+    // allocate a needed page-table page.
+    pub fn mappages(&mut self, va: u64, size: u64, pa: u64, perm: u64) -> Result<(), VmErr> {
+        if size == 0 {
+            panic!("mappages: size");
+        }
+
+        let mut a = PG::rounddown(va);
+        let last = PG::rounddown(va + size - 1);
+        let mut pa = pa;
+        loop {
+            let pte = self.walk(a, true).ok_or(VmErr::OutOfMemErr)?;
+            if pte.is_valid() {
+                panic!("mappages: remap {:#x}", a);
+            }
+            *pte = Pte::new(pa, perm | PTE::V);
+            if a == last {
+                break;
+            }
+            a += PG::SIZE;
+            pa += PG::SIZE;
+        }
+        Ok(())
+    }
+
+    // Remove npages of mappings starting from va. va must be
+    // page-aligned. The mappings must exist.
+    // Optionally free the physical memory.
+    pub fn uvmunmap(&mut self, va: u64, npages: u64, do_free: bool) {
+        if va & (PG::SIZE - 1) != 0 {
+            panic!("uvmunmap: not aligned");
+        }
+
+        for a in (va..va + npages * PG::SIZE).step_by(PG::SIZE as usize) {
+            let pte = match self.walk(a, false) {
+                Some(pte) => pte,
+                None => panic!("uvmunmap: walk"),
+            };
+            if !pte.is_valid() {
+                panic!("uvmunmap: not mapped");
+            }
+            if !pte.is_leaf() {
+                panic!("uvmunmap: not a leaf");
+            }
+            if do_free {
+                kfree(pte.pa() as *mut u8);
+            }
+            *pte = Pte(0);
+        }
+    }
+}
+
+impl Pagetable {
+    // Load the user initcode into address 0 of this page table,
+    // for the very first process.
+    // src.len() must be less than a page.
+    pub fn uvmfirst(&mut self, src: &[u8]) {
+        if src.len() as u64 >= PG::SIZE {
+            panic!("uvmfirst: more than a page");
+        }
+        let mem = kalloc().expect("uvmfirst");
+        unsafe {
+            ptr::write_bytes(mem, 0, PG::SIZE as usize);
+            ptr::copy_nonoverlapping(src.as_ptr(), mem, src.len());
+        }
+        if self
+            .mappages(0, PG::SIZE, mem as u64, PTE::W | PTE::R | PTE::X | PTE::U)
+            .is_err()
+        {
+            panic!("uvmfirst: mappages");
+        }
+    }
+
+    // Allocate PTEs and physical memory to grow process from oldsz to
+    // newsz, which need not be page aligned.  Returns new size.
+    pub fn uvmalloc(&mut self, oldsz: u64, newsz: u64, xperm: u64) -> Result<u64, VmErr> {
+        if newsz < oldsz {
+            return Ok(oldsz);
+        }
+
+        let oldsz = PG::roundup(oldsz);
+        for a in (oldsz..newsz).step_by(PG::SIZE as usize) {
+            let mem = match kalloc() {
+                Some(mem) => mem,
+                None => {
+                    self.uvmdealloc(a, oldsz);
+                    return Err(VmErr::OutOfMemErr);
+                }
+            };
+            unsafe { ptr::write_bytes(mem, 0, PG::SIZE as usize) };
+            if let Err(e) = self.mappages(a, PG::SIZE, mem as u64, PTE::R | PTE::U | xperm) {
+                kfree(mem);
+                self.uvmdealloc(a, oldsz);
+                return Err(e);
+            }
+        }
+        Ok(newsz)
+    }
+
+    // Deallocate user pages to bring the process size from oldsz to
+    // newsz.  oldsz and newsz need not be page-aligned, nor does newsz
+    // need to be less than oldsz.  oldsz can be larger than the actual
+    // process size.  Returns the new process size.
+    pub fn uvmdealloc(&mut self, oldsz: u64, newsz: u64) -> u64 {
+        if newsz >= oldsz {
+            return oldsz;
+        }
+
+        if PG::roundup(newsz) < PG::roundup(oldsz) {
+            let npages = (PG::roundup(oldsz) - PG::roundup(newsz)) / PG::SIZE;
+            self.uvmunmap(PG::roundup(newsz), npages, true);
+        }
+        newsz
+    }
+
+    // mark a PTE invalid for user access.
+    // used by exec for the user stack guard page.
+    pub fn uvmclear(&mut self, va: u64) {
+        match self.walk(va, false) {
+            Some(pte) => pte.0 &= !PTE::U,
+            None => panic!("uvmclear"),
+        }
+    }
+
+    // Given a parent process's page table, copy
+    // its memory into a child's page table.
+    // Copies both the page table and the
+    // physical memory.
+    // frees any allocated pages on failure.
+    pub fn uvmcopy(&mut self, new: &mut Pagetable, sz: u64) -> Result<(), VmErr> {
+        for i in (0..sz).step_by(PG::SIZE as usize) {
+            let pte = match self.walk(i, false) {
+                Some(pte) if pte.is_valid() => *pte,
+                _ => panic!("uvmcopy: page not present"),
+            };
+            let mem = match kalloc() {
+                Some(mem) => mem,
+                None => {
+                    new.uvmunmap(0, i / PG::SIZE, true);
+                    return Err(VmErr::OutOfMemErr);
+                }
+            };
+            unsafe { ptr::copy_nonoverlapping(pte.pa() as *const u8, mem, PG::SIZE as usize) };
+            if let Err(e) = new.mappages(i, PG::SIZE, mem as u64, pte.flags()) {
+                kfree(mem);
+                new.uvmunmap(0, i / PG::SIZE, true);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+// moving data across the user boundary. user addresses are
+// translated through the page table by hand, one page at a time,
+// and only pages with PTE::U (plus PTE::W for writes) are touched.
+impl Pagetable {
+    // physical address of the user page at va0, if perm allows it.
+    fn userpage(&mut self, va0: u64, perm: u64) -> Result<u64, VmErr> {
+        if va0 >= MAXVA {
+            return Err(VmErr::BadAddressErr(va0));
+        }
+        match self.walk(va0, false) {
+            Some(pte) if pte.is_valid() && pte.flags() & (PTE::U | perm) == PTE::U | perm => {
+                Ok(pte.pa())
+            }
+            _ => Err(VmErr::BadAddressErr(va0)),
+        }
+    }
+
+    // Copy from kernel to user.
+    // Copy src to virtual address dstva in this page table.
+    pub fn copyout(&mut self, dstva: u64, src: &[u8]) -> Result<(), VmErr> {
+        let mut dstva = dstva;
+        let mut src = src;
+        while !src.is_empty() {
+            let va0 = PG::rounddown(dstva);
+            let pa0 = self.userpage(va0, PTE::W)?;
+            let n = ((PG::SIZE - (dstva - va0)) as usize).min(src.len());
+            unsafe { ptr::copy(src.as_ptr(), (pa0 + (dstva - va0)) as *mut u8, n) };
+
+            src = &src[n..];
+            dstva = va0 + PG::SIZE;
+        }
+        Ok(())
+    }
+
+    // Copy from user to kernel.
+    // Fill dst from virtual address srcva in this page table.
+    pub fn copyin(&mut self, dst: &mut [u8], srcva: u64) -> Result<(), VmErr> {
+        let mut srcva = srcva;
+        let mut done = 0;
+        while done < dst.len() {
+            let va0 = PG::rounddown(srcva);
+            let pa0 = self.userpage(va0, 0)?;
+            let n = ((PG::SIZE - (srcva - va0)) as usize).min(dst.len() - done);
+            unsafe { ptr::copy((pa0 + (srcva - va0)) as *const u8, dst[done..].as_mut_ptr(), n) };
+
+            done += n;
+            srcva = va0 + PG::SIZE;
+        }
+        Ok(())
+    }
+
+    // Copy a null-terminated string from user to kernel.
+    // Copy bytes to dst from virtual address srcva in this page table,
+    // until a '\0', or dst is full, or MAXPATH bytes were read.
+    // dst is always NUL terminated; returns the length of the string.
+    pub fn copyinstr(&mut self, dst: &mut [u8], srcva: u64) -> Result<usize, VmErr> {
+        let max = dst.len().min(MAXPATH);
+        let mut srcva = srcva;
+        let mut got = 0;
+        while got < max {
+            let va0 = PG::rounddown(srcva);
+            let pa0 = self.userpage(va0, 0)?;
+            let n = ((PG::SIZE - (srcva - va0)) as usize).min(max - got);
+            let p = (pa0 + (srcva - va0)) as *const u8;
+            for i in 0..n {
+                let c = unsafe { *p.add(i) };
+                dst[got] = c;
+                if c == 0 {
+                    return Ok(got);
+                }
+                got += 1;
+            }
+            srcva = va0 + PG::SIZE;
+        }
+        if max > 0 {
+            dst[max - 1] = 0;
+        }
+        Err(VmErr::NameTooLongErr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::kalloc;
+    use super::*;
+    use std::panic;
+
+    fn page() -> u64 {
+        kalloc().unwrap() as u64
+    }
+
+    #[test]
+    fn mappages_then_walkaddr() {
+        let _kmem = kalloc::tests::kinit();
+        let nfree = kalloc::stats().nfree;
+        let pt = unsafe { &mut *pagetable_alloc().unwrap() };
+        let (pa0, pa1) = (page(), page());
+
+        // two pages on either side of a level-1 boundary.
+        let va = (1 << 21) - PG::SIZE;
+        pt.mappages(va, 2 * PG::SIZE, pa0, PTE::R | PTE::U).unwrap();
+        pt.mappages(va + 2 * PG::SIZE, PG::SIZE, pa1, PTE::R | PTE::W | PTE::U)
+            .unwrap();
+
+        assert_eq!(pt.walkaddr(va), Some(pa0));
+        assert_eq!(pt.walkaddr(va + 123), Some(pa0));
+        assert_eq!(pt.walkaddr(va + PG::SIZE), Some(pa0 + PG::SIZE));
+        assert_eq!(pt.walkaddr(va + 2 * PG::SIZE), Some(pa1));
+        assert_eq!(pt.walkaddr(va + 3 * PG::SIZE), None);
+        assert_eq!(pt.walkaddr(MAXVA), None);
+
+        let pte = *pt.walk(va + 2 * PG::SIZE, false).unwrap();
+        assert!(pte.is_valid() && pte.is_leaf());
+        assert_eq!(pte.flags(), PTE::V | PTE::R | PTE::W | PTE::U);
+
+        // only the first page of the pa0 run belongs to this test.
+        pt.uvmunmap(va, 2, false);
+        kfree(pa0 as *mut u8);
+        pt.uvmunmap(va + 2 * PG::SIZE, 1, true);
+        freewalk(pt);
+        assert_eq!(kalloc::stats().nfree, nfree);
+    }
+
+    #[test]
+    fn walkaddr_skips_kernel_pages() {
+        let _kmem = kalloc::tests::kinit();
+        let nfree = kalloc::stats().nfree;
+        let pt = unsafe { &mut *pagetable_alloc().unwrap() };
+        pt.mappages(0, PG::SIZE, page(), PTE::R | PTE::W).unwrap();
+        assert!(pt.walk(0, false).unwrap().is_valid());
+        assert_eq!(pt.walkaddr(0), None);
+
+        pt.uvmunmap(0, 1, true);
+        freewalk(pt);
+        assert_eq!(kalloc::stats().nfree, nfree);
+    }
+
+    #[test]
+    fn uvmunmap_clears_and_frees() {
+        let _kmem = kalloc::tests::kinit();
+        let pt = unsafe { &mut *pagetable_alloc().unwrap() };
+        let pa = page();
+        pt.mappages(PG::SIZE, PG::SIZE, pa, PTE::R | PTE::U).unwrap();
+        let nfree = kalloc::stats().nfree;
+        pt.uvmunmap(PG::SIZE, 1, true);
+        assert_eq!(pt.walkaddr(PG::SIZE), None);
+        assert_eq!(kalloc::stats().nfree, nfree + 1);
+        freewalk(pt);
+    }
+
+    // fill each page of [0, sz) with its page number.
+    fn scribble(pt: &mut Pagetable, sz: u64) {
+        for va in (0..sz).step_by(PG::SIZE as usize) {
+            let pa = pt.walkaddr(va).unwrap();
+            unsafe { ptr::write_bytes(pa as *mut u8, (va / PG::SIZE) as u8 + 1, PG::SIZE as usize) };
+        }
+    }
+
+    #[test]
+    fn uvmalloc_grows_and_uvmdealloc_shrinks() {
+        let _kmem = kalloc::tests::kinit();
+        let pt = unsafe { &mut *uvmcreate().unwrap() };
+
+        assert_eq!(pt.uvmalloc(0, 3 * PG::SIZE + 1, PTE::W), Ok(3 * PG::SIZE + 1));
+        for va in (0..4 * PG::SIZE).step_by(PG::SIZE as usize) {
+            let pa = pt.walkaddr(va).unwrap();
+            let page = unsafe { std::slice::from_raw_parts(pa as *const u8, PG::SIZE as usize) };
+            assert!(page.iter().all(|b| *b == 0));
+        }
+        assert_eq!(pt.walkaddr(4 * PG::SIZE), None);
+        let pte = *pt.walk(0, false).unwrap();
+        assert_eq!(pte.flags(), PTE::V | PTE::R | PTE::W | PTE::U);
+
+        // growing from a partial page maps only the pages beyond it.
+        assert_eq!(pt.uvmalloc(3 * PG::SIZE + 1, 5 * PG::SIZE, PTE::W), Ok(5 * PG::SIZE));
+        assert!(pt.walkaddr(4 * PG::SIZE).is_some());
+
+        assert_eq!(pt.uvmdealloc(5 * PG::SIZE, PG::SIZE + 10), PG::SIZE + 10);
+        assert!(pt.walkaddr(PG::SIZE).is_some());
+        assert_eq!(pt.walkaddr(2 * PG::SIZE), None);
+        assert_eq!(pt.walkaddr(4 * PG::SIZE), None);
+
+        uvmfree(pt, PG::SIZE + 10);
+    }
+
+    #[test]
+    fn uvmcopy_duplicates_memory() {
+        let _kmem = kalloc::tests::kinit();
+        let sz = 3 * PG::SIZE;
+        let old = unsafe { &mut *uvmcreate().unwrap() };
+        old.uvmalloc(0, sz, PTE::W).unwrap();
+        scribble(old, sz);
+
+        let new = unsafe { &mut *uvmcreate().unwrap() };
+        old.uvmcopy(new, sz).unwrap();
+        for va in (0..sz).step_by(PG::SIZE as usize) {
+            let (pa_old, pa_new) = (old.walkaddr(va).unwrap(), new.walkaddr(va).unwrap());
+            assert_ne!(pa_old, pa_new);
+            let page = unsafe { std::slice::from_raw_parts(pa_new as *const u8, PG::SIZE as usize) };
+            assert!(page.iter().all(|b| *b == (va / PG::SIZE) as u8 + 1));
+            assert_eq!(old.walk(va, false).unwrap().flags(), new.walk(va, false).unwrap().flags());
+        }
+
+        uvmfree(old, sz);
+        uvmfree(new, sz);
+    }
+
+    #[test]
+    fn uvmfree_returns_every_page() {
+        let _kmem = kalloc::tests::kinit();
+        let nfree = kalloc::stats().nfree;
+        let pt = uvmcreate().unwrap();
+        let sz = unsafe { (*pt).uvmalloc(0, 2 * PG::SIZE, PTE::W).unwrap() };
+        assert_eq!(sz, 2 * PG::SIZE);
+        // two data pages, and a page-table page on each of three levels.
+        assert_eq!(kalloc::stats().nfree, nfree - 5);
+        uvmfree(pt, sz);
+        assert_eq!(kalloc::stats().nfree, nfree);
+    }
+
+    // a user image of two writable pages, then a read-only page,
+    // then an unmapped page, then a kernel-only page.
+    fn image() -> &'static mut Pagetable {
+        let pt = unsafe { &mut *uvmcreate().unwrap() };
+        pt.uvmalloc(0, 2 * PG::SIZE, PTE::W).unwrap();
+        pt.uvmalloc(2 * PG::SIZE, 3 * PG::SIZE, 0).unwrap();
+        pt.mappages(4 * PG::SIZE, PG::SIZE, page(), PTE::R | PTE::W).unwrap();
+        pt
+    }
+
+    fn free_image(pt: &mut Pagetable) {
+        pt.uvmunmap(4 * PG::SIZE, 1, true);
+        uvmfree(pt, 3 * PG::SIZE);
+    }
+
+    #[test]
+    fn copyout_copyin_cross_pages() {
+        let _kmem = kalloc::tests::kinit();
+        let nfree = kalloc::stats().nfree;
+        let pt = image();
+        let src: Vec<u8> = (0..100).collect();
+        let va = PG::SIZE - 40;
+        pt.copyout(va, &src).unwrap();
+
+        let mut dst = [0u8; 100];
+        pt.copyin(&mut dst, va).unwrap();
+        assert_eq!(&dst[..], &src[..]);
+
+        // the bytes landed in two different physical pages.
+        let pa1 = pt.walkaddr(PG::SIZE).unwrap();
+        assert_eq!(unsafe { *(pa1 as *const u8) }, 40);
+
+        free_image(pt);
+        assert_eq!(kalloc::stats().nfree, nfree);
+    }
+
+    #[test]
+    fn copy_rejects_inaccessible_pages() {
+        let _kmem = kalloc::tests::kinit();
+        let nfree = kalloc::stats().nfree;
+        let pt = image();
+        let mut buf = [7u8; 16];
+
+        // read-only for the user.
+        assert_eq!(pt.copyin(&mut buf, 2 * PG::SIZE), Ok(()));
+        assert_eq!(pt.copyout(2 * PG::SIZE, &buf), Err(VmErr::BadAddressErr(2 * PG::SIZE)));
+        // running off the end into an unmapped page.
+        assert_eq!(pt.copyin(&mut buf, 3 * PG::SIZE - 8), Err(VmErr::BadAddressErr(3 * PG::SIZE)));
+        // kernel-only page.
+        assert_eq!(pt.copyin(&mut buf, 4 * PG::SIZE), Err(VmErr::BadAddressErr(4 * PG::SIZE)));
+        assert_eq!(pt.copyout(4 * PG::SIZE, &buf), Err(VmErr::BadAddressErr(4 * PG::SIZE)));
+        // beyond the address space.
+        assert_eq!(pt.copyin(&mut buf, MAXVA), Err(VmErr::BadAddressErr(MAXVA)));
+
+        free_image(pt);
+        assert_eq!(kalloc::stats().nfree, nfree);
+    }
+
+    #[test]
+    fn copyinstr_stops_at_nul() {
+        let _kmem = kalloc::tests::kinit();
+        let nfree = kalloc::stats().nfree;
+        let pt = image();
+        let va = PG::SIZE - 3;
+        pt.copyout(va, b"hello\0world").unwrap();
+
+        let mut dst = [0xffu8; MAXPATH];
+        assert_eq!(pt.copyinstr(&mut dst, va), Ok(5));
+        assert_eq!(&dst[..6], b"hello\0");
+
+        free_image(pt);
+        assert_eq!(kalloc::stats().nfree, nfree);
+    }
+
+    #[test]
+    fn copyinstr_bounds_length() {
+        let _kmem = kalloc::tests::kinit();
+        let nfree = kalloc::stats().nfree;
+        let pt = image();
+        pt.copyout(0, &[b'a'; MAXPATH + 1]).unwrap();
+
+        let mut dst = [0u8; MAXPATH + 10];
+        assert_eq!(pt.copyinstr(&mut dst, 0), Err(VmErr::NameTooLongErr));
+        assert_eq!(dst[MAXPATH - 1], 0);
+
+        let mut small = [0u8; 4];
+        assert_eq!(pt.copyinstr(&mut small, 0), Err(VmErr::NameTooLongErr));
+        assert_eq!(&small, b"aaa\0");
+
+        // the read-only page is fine for reads, the page after it is not.
+        let pa = pt.walkaddr(2 * PG::SIZE).unwrap();
+        unsafe { ptr::write_bytes(pa as *mut u8, b'b', PG::SIZE as usize) };
+        let mut dst = [0u8; MAXPATH];
+        assert_eq!(pt.copyinstr(&mut dst, 3 * PG::SIZE - 4), Err(VmErr::BadAddressErr(3 * PG::SIZE)));
+
+        free_image(pt);
+        assert_eq!(kalloc::stats().nfree, nfree);
+    }
+
+    #[test]
+    fn remap_panics() {
+        let _kmem = kalloc::tests::kinit();
+        let nfree = kalloc::stats().nfree;
+        let pt = unsafe { &mut *pagetable_alloc().unwrap() };
+        let pa = page();
+        pt.mappages(0, PG::SIZE, pa, PTE::R).unwrap();
+        let r = panic::catch_unwind(panic::AssertUnwindSafe(|| pt.mappages(0, PG::SIZE, pa, PTE::R)));
+        assert!(r.is_err());
 
+        pt.uvmunmap(0, 1, true);
+        freewalk(pt);
+        assert_eq!(kalloc::stats().nfree, nfree);
+    }
 }